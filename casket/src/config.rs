@@ -3,6 +3,8 @@ use std::collections::HashMap;
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Catalog {
@@ -10,16 +12,823 @@ pub struct Catalog {
     pub data_path: PathBuf,
     /// サムネイル保存先パス (データベースファイルもここに配置)
     pub thumbnail_path: PathBuf,
+    /// サムネイルを thumbnail_path 直下ではなくこのサブディレクトリ以下に保存する
+    /// (例: "thumbnails"。データベースファイルは thumbnail_path 直下のまま)
+    #[serde(default)]
+    pub thumbnail_subdir: Option<PathBuf>,
+    /// サムネイルのJPEGクオリティ (1-100、省略時は `[defaults]` か `DEFAULT_THUMBNAIL_QUALITY`)
+    #[serde(default)]
+    pub thumbnail_quality: Option<u8>,
+    /// サムネイルの保存形式 ("jpeg" / "avif"、省略時は `[defaults]` か JPEG)
+    #[serde(default)]
+    pub thumbnail_format: Option<ThumbnailFormat>,
+    /// サムネイル縮小のフィルター ("fast" / "good" / "best"、省略時は `[defaults]` か "fast")
+    #[serde(default)]
+    pub thumbnail_filter: Option<ThumbnailFilter>,
+    /// サムネイルを作らない拡張子 (例: ["mp4", "mov"]。ファイルのコピーとDB登録は行う)
+    #[serde(default)]
+    pub skip_thumbnails: Vec<String>,
+    /// データベースファイルのパス (省略時は thumbnail_path/casket.db)
+    #[serde(default)]
+    pub database_path: Option<PathBuf>,
+    /// データベースが他の接続にロックされているときに待つ時間 (ミリ秒、省略時は `[defaults]` か
+    /// `DEFAULT_BUSY_TIMEOUT_MS`)
+    #[serde(default)]
+    pub busy_timeout_ms: Option<u64>,
+    /// 保存先ディレクトリの階層 (例: "year/month"、省略時は "year/month/day")
+    #[serde(default)]
+    pub path_granularity: PathGranularity,
+    /// 日付フォルダの前にカメラモデルのフォルダを挟む (例: data_path/NIKON Z 6/2024/05/05)
+    #[serde(default)]
+    pub group_by_camera_model: bool,
+    /// RAW デコードの最終手段に使う外部変換コマンド (省略時は `[defaults]` かプラットフォーム既定)
+    #[serde(default)]
+    pub raw_converter: Option<RawConverter>,
+    /// libraw で現像した RAW サムネイルの仕上げ (省略時は `[defaults]` か補正なし)
+    #[serde(default)]
+    pub raw_rendering: Option<RawRendering>,
+    /// 組み込みの対応に追加する拡張子 (`[defaults.extensions]` の分も合わせて使う)
+    #[serde(default)]
+    pub extensions: MediaExtensions,
+    /// GPS 座標から地名を引く設定 (省略時は `[defaults]` か、地名を引かない)
+    #[serde(default)]
+    pub geocoding: Option<Geocoding>,
 }
 
+/// 拡張子ごとに使うデコーダーの追加設定 (組み込みの対応より優先)
+///
+/// ```toml
+/// [defaults.extensions]
+/// raw = ["rw2", "raf"]
+/// video = ["mkv"]
+/// ```
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MediaExtensions {
+    /// libraw で現像する拡張子
+    #[serde(default)]
+    pub raw: Vec<String>,
+    /// HEIC/HEIF としてデコードする拡張子
+    #[serde(default)]
+    pub heic: Vec<String>,
+    /// 動画として扱う拡張子
+    #[serde(default)]
+    pub video: Vec<String>,
+}
+
+impl MediaExtensions {
+    /// `other` の拡張子を追加する (重複は除く)
+    fn extend(&mut self, other: &MediaExtensions) {
+        for (list, additions) in [
+            (&mut self.raw, &other.raw),
+            (&mut self.heic, &other.heic),
+            (&mut self.video, &other.video),
+        ] {
+            for ext in additions {
+                if !list.contains(ext) {
+                    list.push(ext.clone());
+                }
+            }
+        }
+    }
+}
+
+/// GPS 座標から地名を引く逆ジオコーディングの設定
+///
+/// 引いた地名は座標を丸めた値をキーにしてキャッシュし、同じ場所の問い合わせを繰り返さない。
+///
+/// ```toml
+/// [defaults.geocoding]
+/// endpoint = "https://nominatim.openstreetmap.org/reverse?format=jsonv2&lat={lat}&lon={lon}"
+/// name_field = "/display_name"
+/// ```
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Geocoding {
+    /// 問い合わせる URL (`{lat}` と `{lon}` を丸めた座標に置き換える)
+    pub endpoint: String,
+    /// 応答の JSON から地名を取り出す JSON Pointer (省略時は "/display_name")
+    #[serde(default = "Geocoding::default_name_field")]
+    pub name_field: String,
+    /// キャッシュのデータベースファイル (省略時はユーザーのキャッシュディレクトリの casket/geocoding.db)
+    #[serde(default)]
+    pub cache_path: Option<PathBuf>,
+    /// 座標を丸める小数点以下の桁数 (0-6、省略時は 3 = 約 100m)
+    #[serde(default = "Geocoding::default_precision")]
+    pub precision: u8,
+    /// 問い合わせの最小間隔 (秒、省略時は 1)
+    #[serde(default = "Geocoding::default_min_interval_secs")]
+    pub min_interval_secs: f64,
+    /// 1回の問い合わせのタイムアウト (秒、省略時は 10)
+    #[serde(default = "Geocoding::default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl Geocoding {
+    fn default_name_field() -> String {
+        "/display_name".to_string()
+    }
+
+    fn default_precision() -> u8 {
+        3
+    }
+
+    fn default_min_interval_secs() -> f64 {
+        1.0
+    }
+
+    fn default_timeout_secs() -> u64 {
+        10
+    }
+
+    /// 値の範囲を検証する
+    pub fn validate(&self) -> Result<(), String> {
+        for token in ["{lat}", "{lon}"] {
+            if !self.endpoint.contains(token) {
+                return Err(format!("geocoding.endpoint '{}' must contain {}", self.endpoint, token));
+            }
+        }
+        if !self.name_field.is_empty() && !self.name_field.starts_with('/') {
+            return Err(format!(
+                "geocoding.name_field '{}' must be a JSON Pointer starting with '/'",
+                self.name_field
+            ));
+        }
+        if self.precision > 6 {
+            return Err(format!("geocoding.precision must be at most 6, got {}", self.precision));
+        }
+        if !(self.min_interval_secs >= 0.0 && self.min_interval_secs.is_finite()) {
+            return Err(format!(
+                "geocoding.min_interval_secs must be 0 or more, got {}",
+                self.min_interval_secs
+            ));
+        }
+        if self.timeout_secs == 0 {
+            return Err("geocoding.timeout_secs must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    /// キャッシュのデータベースファイルのパス (キャッシュディレクトリが分からなければ None)
+    pub fn cache_path(&self) -> Option<PathBuf> {
+        self.cache_path
+            .clone()
+            .or_else(|| dirs::cache_dir().map(|dir| dir.join("casket").join("geocoding.db")))
+    }
+
+    /// 相対パスの cache_path を設定ファイルのあるディレクトリ基準にする
+    fn resolve_relative_paths(&mut self, base_dir: &Path) {
+        if let Some(path) = self.cache_path.as_mut().filter(|path| path.is_relative()) {
+            *path = base_dir.join(&*path);
+        }
+    }
+}
+
+/// サムネイルの保存形式
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(try_from = "String")]
+pub enum ThumbnailFormat {
+    /// JPEG (.jpg)
+    #[default]
+    Jpeg,
+    /// AVIF (.avif、`avif` フィーチャーが必要)
+    #[cfg(feature = "avif")]
+    Avif,
+}
+
+impl ThumbnailFormat {
+    /// サムネイルファイルの拡張子
+    pub fn extension(self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "jpg",
+            #[cfg(feature = "avif")]
+            ThumbnailFormat::Avif => "avif",
+        }
+    }
+}
+
+impl TryFrom<String> for ThumbnailFormat {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.to_ascii_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(ThumbnailFormat::Jpeg),
+            #[cfg(feature = "avif")]
+            "avif" => Ok(ThumbnailFormat::Avif),
+            #[cfg(not(feature = "avif"))]
+            "avif" => Err("thumbnail_format 'avif' requires casket built with the avif feature".to_string()),
+            _ => Err(format!("unknown thumbnail_format '{}' (use jpeg or avif)", value)),
+        }
+    }
+}
+
+/// サムネイル縮小の画質 (速度とのトレードオフ)
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(try_from = "String")]
+pub enum ThumbnailFilter {
+    /// image クレートの `thumbnail()` (高速、細部が粗くなる)
+    #[default]
+    Fast,
+    /// Catmull-Rom
+    Good,
+    /// Lanczos3 (最も遅い)
+    Best,
+}
+
+impl ThumbnailFilter {
+    /// `resize()` に渡すフィルター (Fast は `thumbnail()` を使うので None)
+    pub fn filter_type(self) -> Option<image::imageops::FilterType> {
+        match self {
+            ThumbnailFilter::Fast => None,
+            ThumbnailFilter::Good => Some(image::imageops::FilterType::CatmullRom),
+            ThumbnailFilter::Best => Some(image::imageops::FilterType::Lanczos3),
+        }
+    }
+}
+
+impl TryFrom<String> for ThumbnailFilter {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.to_ascii_lowercase().as_str() {
+            "fast" => Ok(ThumbnailFilter::Fast),
+            "good" => Ok(ThumbnailFilter::Good),
+            "best" => Ok(ThumbnailFilter::Best),
+            _ => Err(format!("unknown thumbnail_filter '{}' (use fast, good or best)", value)),
+        }
+    }
+}
+
+/// 保存先ディレクトリを日時のどの単位まで分けるか
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(try_from = "String")]
+pub enum PathGranularity {
+    /// year
+    Year,
+    /// year/month
+    Month,
+    /// year/month/day
+    #[default]
+    Day,
+    /// year/month/day/hour
+    Hour,
+}
+
+impl PathGranularity {
+    /// 階層の順序 (この順に先頭から指定する)
+    const TOKENS: [&'static str; 4] = ["year", "month", "day", "hour"];
+    const FORMATS: [&'static str; 4] = ["%Y", "%m", "%d", "%H"];
+
+    fn depth(self) -> usize {
+        match self {
+            PathGranularity::Year => 1,
+            PathGranularity::Month => 2,
+            PathGranularity::Day => 3,
+            PathGranularity::Hour => 4,
+        }
+    }
+
+    /// 日時から保存先のサブディレクトリ (例: 2024/05/05) を組み立てる
+    pub fn subdirectory<Tz: chrono::TimeZone>(self, datetime: &chrono::DateTime<Tz>) -> PathBuf
+    where
+        Tz::Offset: std::fmt::Display,
+    {
+        Self::FORMATS[..self.depth()]
+            .iter()
+            .map(|format| datetime.format(format).to_string())
+            .collect()
+    }
+}
+
+impl TryFrom<String> for PathGranularity {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let tokens: Vec<&str> = value.split('/').collect();
+        for (index, token) in tokens.iter().enumerate() {
+            match Self::TOKENS.iter().position(|t| t == token) {
+                Some(position) if position == index => {}
+                Some(_) => {
+                    return Err(format!(
+                        "path_granularity '{}' must list tokens in the order year/month/day/hour",
+                        value
+                    ))
+                }
+                None => {
+                    return Err(format!(
+                        "unknown token '{}' in path_granularity '{}' (use year, month, day, hour)",
+                        token, value
+                    ))
+                }
+            }
+        }
+        match tokens.len() {
+            1 => Ok(PathGranularity::Year),
+            2 => Ok(PathGranularity::Month),
+            3 => Ok(PathGranularity::Day),
+            _ => Ok(PathGranularity::Hour),
+        }
+    }
+}
+
+/// libraw で現像した RAW サムネイルの仕上げ
+///
+/// libraw-rs は libraw の現像パラメーター (出力色空間、ガンマ、ホワイトバランスなど) を
+/// 設定できないため、現像後の 8bit 画像に適用する。色空間やホワイトバランスまで
+/// 調整したい場合は `raw_converter` で外部の現像ソフトを使う。
+///
+/// ```toml
+/// [catalogs.work.raw_rendering]
+/// gamma = 1.1
+/// auto_brightness = true
+/// contrast = 15.0
+/// ```
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct RawRendering {
+    /// 追加のガンマ補正 (1.0 で補正なし、1.0 より大きいと中間調が明るくなる)
+    pub gamma: f32,
+    /// 上位 1% の画素が白になるようにレベルを引き伸ばす (暗く眠い現像結果の補正)
+    pub auto_brightness: bool,
+    /// コントラストの調整量 (-100 から 100、0 で変更なし)
+    pub contrast: f32,
+}
+
+impl Default for RawRendering {
+    fn default() -> Self {
+        RawRendering {
+            gamma: 1.0,
+            auto_brightness: false,
+            contrast: 0.0,
+        }
+    }
+}
+
+impl RawRendering {
+    /// 値の範囲を検証する
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.gamma > 0.0 && self.gamma <= 5.0) {
+            return Err(format!("raw_rendering.gamma must be greater than 0 and at most 5, got {}", self.gamma));
+        }
+        if !(-100.0..=100.0).contains(&self.contrast) {
+            return Err(format!(
+                "raw_rendering.contrast must be between -100 and 100, got {}",
+                self.contrast
+            ));
+        }
+        Ok(())
+    }
+
+    /// 何も変更しない設定かどうか
+    pub fn is_neutral(&self) -> bool {
+        *self == RawRendering::default()
+    }
+}
+
+/// RAW を JPEG に変換する外部コマンド (例: "darktable-cli {input} {output}")
+///
+/// 空白で区切った最初の要素がプログラム、残りが引数。`{input}` は元ファイル、
+/// `{output}` は書き出す JPEG のパスに置き換えられる (どちらも必須)。
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct RawConverter {
+    program: String,
+    args: Vec<String>,
+}
+
+impl RawConverter {
+    const TOKENS: [&'static str; 2] = ["{input}", "{output}"];
+
+    /// コマンドテンプレートを解析する (未知のトークンや `{input}` / `{output}` の欠落はエラー)
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut parts = template.split_whitespace().map(str::to_string);
+        let program = parts
+            .next()
+            .ok_or_else(|| "raw_converter must not be empty".to_string())?;
+        let args: Vec<String> = parts.collect();
+
+        for arg in &args {
+            let mut rest = arg.as_str();
+            while let Some(start) = rest.find('{') {
+                let end = rest[start..]
+                    .find('}')
+                    .map(|end| start + end + 1)
+                    .ok_or_else(|| format!("unclosed token in raw_converter '{}'", template))?;
+                let token = &rest[start..end];
+                if !Self::TOKENS.contains(&token) {
+                    return Err(format!(
+                        "unknown token '{}' in raw_converter '{}' (use {{input}} and {{output}})",
+                        token, template
+                    ));
+                }
+                rest = &rest[end..];
+            }
+        }
+        for token in Self::TOKENS {
+            if !args.iter().any(|arg| arg.contains(token)) {
+                return Err(format!("raw_converter '{}' must contain {}", template, token));
+            }
+        }
+
+        Ok(RawConverter { program, args })
+    }
+
+    /// 設定がない場合に使うコマンド (macOS は sips、それ以外はなし)
+    pub fn platform_default() -> Option<Self> {
+        if cfg!(target_os = "macos") {
+            Some(Self::parse("sips -s format jpeg {input} --out {output}").expect("valid template"))
+        } else {
+            None
+        }
+    }
+
+    /// 実行するプログラム名
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    /// トークンを置き換えた実行用のコマンドを組み立てる
+    pub fn command(&self, input: &Path, output: &Path) -> Command {
+        let input = input.to_string_lossy();
+        let output = output.to_string_lossy();
+        let mut command = Command::new(&self.program);
+        command.args(
+            self.args
+                .iter()
+                .map(|arg| arg.replace("{input}", &input).replace("{output}", &output)),
+        );
+        command
+    }
+}
+
+impl TryFrom<String> for RawConverter {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::parse(&value)
+    }
+}
+
+/// `thumbnail_subdir` が thumbnail_path の内側を指す相対パスか検証する
+pub fn validate_thumbnail_subdir(subdir: &Path) -> Result<(), String> {
+    let mut components = subdir.components().peekable();
+    if components.peek().is_none() {
+        return Err("thumbnail_subdir must not be empty".to_string());
+    }
+    if components.all(|component| matches!(component, std::path::Component::Normal(_))) {
+        Ok(())
+    } else {
+        Err(format!(
+            "thumbnail_subdir must be a relative path inside thumbnail_path, got {:?}",
+            subdir
+        ))
+    }
+}
+
+/// サムネイルのJPEGクオリティのデフォルト値 (1-100、旧 1-10 スケールの 6 相当)
+pub const DEFAULT_THUMBNAIL_QUALITY: u8 = 60;
+
+/// データベースのロックを待つ時間のデフォルト (ミリ秒)
+pub const DEFAULT_BUSY_TIMEOUT_MS: u64 = 5000;
+
+/// JPEGクオリティが 1-100 の範囲にあるか検証する
+pub fn validate_thumbnail_quality(quality: u8) -> Result<u8, String> {
+    if (1..=100).contains(&quality) {
+        Ok(quality)
+    } else {
+        Err(format!(
+            "thumbnail quality must be between 1 and 100, got {}",
+            quality
+        ))
+    }
+}
+
+impl Catalog {
+    /// 保存先だけを指定したカタログを作る (設定ファイルを使わない一時的なインポート用、他の項目は既定値)
+    pub fn new(data_path: PathBuf, thumbnail_path: PathBuf) -> Self {
+        Catalog {
+            data_path,
+            thumbnail_path,
+            thumbnail_subdir: None,
+            thumbnail_quality: None,
+            thumbnail_format: None,
+            thumbnail_filter: None,
+            skip_thumbnails: Vec::new(),
+            database_path: None,
+            busy_timeout_ms: None,
+            path_granularity: PathGranularity::default(),
+            group_by_camera_model: false,
+            raw_converter: None,
+            raw_rendering: None,
+            extensions: MediaExtensions::default(),
+            geocoding: None,
+        }
+    }
+
+    /// 日付ディレクトリを作ってサムネイルを保存するディレクトリ
+    /// (`thumbnail_subdir` があれば thumbnail_path/thumbnail_subdir)
+    pub fn thumbnail_dir(&self) -> PathBuf {
+        match &self.thumbnail_subdir {
+            Some(subdir) => self.thumbnail_path.join(subdir),
+            None => self.thumbnail_path.clone(),
+        }
+    }
+
+    /// `skip_thumbnails` に拡張子が含まれていて、サムネイルを作らないファイルかどうか
+    pub fn skips_thumbnail(&self, path: &Path) -> bool {
+        let Some(ext) = path.extension().and_then(|s| s.to_str()) else {
+            return false;
+        };
+        self.skip_thumbnails
+            .iter()
+            .any(|skipped| skipped.trim_start_matches('.').eq_ignore_ascii_case(ext))
+    }
+
+    /// サムネイル生成に使うJPEGクオリティ
+    pub fn thumbnail_quality(&self) -> u8 {
+        self.thumbnail_quality.unwrap_or(DEFAULT_THUMBNAIL_QUALITY)
+    }
+
+    /// サムネイルの保存形式
+    pub fn thumbnail_format(&self) -> ThumbnailFormat {
+        self.thumbnail_format.unwrap_or_default()
+    }
+
+    /// サムネイル縮小のフィルター
+    pub fn thumbnail_filter(&self) -> ThumbnailFilter {
+        self.thumbnail_filter.unwrap_or_default()
+    }
+
+    /// libraw で現像した RAW サムネイルの仕上げ
+    pub fn raw_rendering(&self) -> RawRendering {
+        self.raw_rendering.unwrap_or_default()
+    }
+
+    /// RAW デコードの最終手段に使う外部変換コマンド (未設定ならプラットフォーム既定)
+    pub fn raw_converter(&self) -> Option<RawConverter> {
+        self.raw_converter.clone().or_else(RawConverter::platform_default)
+    }
+
+    /// カタログのデータベースファイルのパス
+    pub fn database_path(&self) -> PathBuf {
+        self.database_path
+            .clone()
+            .unwrap_or_else(|| self.thumbnail_path.join("casket.db"))
+    }
+
+    /// データベースのロックを待つ時間
+    pub fn busy_timeout(&self) -> Duration {
+        Duration::from_millis(self.busy_timeout_ms.unwrap_or(DEFAULT_BUSY_TIMEOUT_MS))
+    }
+
+    /// 相対パスを設定ファイルのあるディレクトリ基準の絶対パスにする
+    fn resolve_relative_paths(&mut self, base_dir: &Path) {
+        let paths = [&mut self.data_path, &mut self.thumbnail_path]
+            .into_iter()
+            .chain(self.database_path.as_mut());
+        for path in paths {
+            if path.is_relative() {
+                *path = base_dir.join(&*path);
+            }
+        }
+        if let Some(geocoding) = &mut self.geocoding {
+            geocoding.resolve_relative_paths(base_dir);
+        }
+    }
+}
+
+/// カレントディレクトリから親方向に探索するローカル設定ファイル名
+pub const LOCAL_CONFIG_FILE_NAME: &str = ".casket.toml";
+
+/// 設定ファイル全体
+///
+/// ```toml
+/// [defaults]
+/// thumbnail_quality = 85
+///
+/// [catalogs.work]
+/// data_path = "/path/to/data"
+/// thumbnail_path = "/path/to/thumbnails"
+/// ```
+///
+/// カタログをトップレベルのテーブルとして並べる旧形式も読み込める (`parse_toml_config` 参照)
 #[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
-    #[serde(flatten)]
+    #[serde(default)]
     pub catalogs: HashMap<String, Catalog>,
+    #[serde(default)]
+    pub defaults: Defaults,
+}
+
+/// 全カタログ共通のデフォルト設定 (`[defaults]`)
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct Defaults {
+    /// カタログで thumbnail_quality を省略した場合のJPEGクオリティ (1-100)
+    pub thumbnail_quality: Option<u8>,
+    /// カタログで thumbnail_format を省略した場合の保存形式
+    pub thumbnail_format: Option<ThumbnailFormat>,
+    /// カタログで thumbnail_filter を省略した場合の縮小フィルター
+    pub thumbnail_filter: Option<ThumbnailFilter>,
+    /// カタログで busy_timeout_ms を省略した場合のデータベースのロックを待つ時間 (ミリ秒)
+    pub busy_timeout_ms: Option<u64>,
+    /// カタログで raw_converter を省略した場合の外部変換コマンド
+    pub raw_converter: Option<RawConverter>,
+    /// カタログで raw_rendering を省略した場合の RAW サムネイルの仕上げ
+    pub raw_rendering: Option<RawRendering>,
+    /// 全カタログに追加する拡張子とデコーダーの対応
+    #[serde(default)]
+    pub extensions: MediaExtensions,
+    /// カタログで geocoding を省略した場合の逆ジオコーディングの設定
+    pub geocoding: Option<Geocoding>,
+}
+
+impl Defaults {
+    /// `other` で指定されている項目を上書きする
+    fn merge(&mut self, other: Defaults) {
+        if other.thumbnail_quality.is_some() {
+            self.thumbnail_quality = other.thumbnail_quality;
+        }
+        if other.thumbnail_format.is_some() {
+            self.thumbnail_format = other.thumbnail_format;
+        }
+        if other.thumbnail_filter.is_some() {
+            self.thumbnail_filter = other.thumbnail_filter;
+        }
+        if other.busy_timeout_ms.is_some() {
+            self.busy_timeout_ms = other.busy_timeout_ms;
+        }
+        if other.raw_converter.is_some() {
+            self.raw_converter = other.raw_converter;
+        }
+        if other.raw_rendering.is_some() {
+            self.raw_rendering = other.raw_rendering;
+        }
+        if other.geocoding.is_some() {
+            self.geocoding = other.geocoding;
+        }
+        self.extensions.extend(&other.extensions);
+    }
+}
+
+impl Config {
+    /// カタログで省略された項目に `[defaults]` の値を当てはめる
+    fn apply_defaults(&mut self) {
+        for catalog in self.catalogs.values_mut() {
+            if catalog.thumbnail_quality.is_none() {
+                catalog.thumbnail_quality = self.defaults.thumbnail_quality;
+            }
+            if catalog.thumbnail_format.is_none() {
+                catalog.thumbnail_format = self.defaults.thumbnail_format;
+            }
+            if catalog.thumbnail_filter.is_none() {
+                catalog.thumbnail_filter = self.defaults.thumbnail_filter;
+            }
+            if catalog.busy_timeout_ms.is_none() {
+                catalog.busy_timeout_ms = self.defaults.busy_timeout_ms;
+            }
+            if catalog.raw_converter.is_none() {
+                catalog.raw_converter = self.defaults.raw_converter.clone();
+            }
+            if catalog.raw_rendering.is_none() {
+                catalog.raw_rendering = self.defaults.raw_rendering;
+            }
+            if catalog.geocoding.is_none() {
+                catalog.geocoding = self.defaults.geocoding.clone();
+            }
+            catalog.extensions.extend(&self.defaults.extensions);
+        }
+    }
+
+    /// 指定された名前のカタログの、設定上の名前を返す
+    ///
+    /// 完全に一致する名前を優先し、なければ大文字小文字を区別せずに探す
+    /// (大文字小文字だけが違うカタログが複数あって決められない場合は None)。
+    pub fn find_catalog_name(&self, name: &str) -> Option<&str> {
+        if let Some((key, _)) = self.catalogs.get_key_value(name) {
+            return Some(key);
+        }
+        let mut matches = self.catalogs.keys().filter(|key| key.eq_ignore_ascii_case(name));
+        match (matches.next(), matches.next()) {
+            (Some(key), None) => Some(key),
+            _ => None,
+        }
+    }
+
+    /// 指定された名前に最も近いカタログ名を返す (編集距離が名前の長さの 1/3 以下、最低 2 のもの)
+    pub fn closest_catalog_name(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        let max_distance = (name.chars().count() / 3).max(2);
+        self.catalogs
+            .keys()
+            .map(|key| (edit_distance(&name, &key.to_lowercase()), key))
+            .filter(|&(distance, _)| distance <= max_distance)
+            .min()
+            .map(|(_, key)| key.as_str())
+    }
+}
+
+/// 2つの文字列の編集距離 (レーベンシュタイン距離、文字単位)
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, &b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// 旧形式の設定 (カタログがトップレベルのテーブル)
+#[derive(Deserialize)]
+struct LegacyConfig {
+    #[serde(flatten)]
+    catalogs: HashMap<String, Catalog>,
+}
+
+/// 設定ファイルの形式 (拡張子で判別する)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// パスの拡張子から形式を判別する (拡張子がなければ TOML とみなす)
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        let Some(extension) = path.extension() else {
+            return Ok(ConfigFormat::Toml);
+        };
+        match extension.to_string_lossy().to_ascii_lowercase().as_str() {
+            "toml" => Ok(ConfigFormat::Toml),
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            "json" => Ok(ConfigFormat::Json),
+            other => Err(format!(
+                "Unsupported config file extension '.{}' for {:?} (expected .toml, .yaml, .yml or .json)",
+                other, path
+            )),
+        }
+    }
+}
+
+/// 設定ファイルの内容を形式に応じて解析する
+fn parse_config(content: &str, format: ConfigFormat) -> Result<Config, Box<dyn std::error::Error>> {
+    match format {
+        ConfigFormat::Toml => Ok(parse_toml_config(content)?),
+        ConfigFormat::Json => Ok(serde_json::from_str(content)?),
+        #[cfg(feature = "yaml")]
+        ConfigFormat::Yaml => Ok(serde_yaml::from_str(content)?),
+        #[cfg(not(feature = "yaml"))]
+        ConfigFormat::Yaml => {
+            Err("YAML config files are not supported by this build (rebuild with --features yaml)".into())
+        }
+    }
+}
+
+/// TOML の設定ファイルの内容を解析する
+///
+/// まず `[catalogs.*]` / `[defaults]` の形式として解析し、失敗した場合は
+/// トップレベルに `catalogs` も `defaults` もなければ旧形式として解析し直す。
+/// (旧形式は TOML にしかないため、YAML / JSON では `catalogs` / `defaults` の形式だけを読む)
+fn parse_toml_config(content: &str) -> Result<Config, toml::de::Error> {
+    let layout_error = match toml::from_str::<Config>(content) {
+        Ok(config) => return Ok(config),
+        Err(e) => e,
+    };
+
+    let table: toml::Table = toml::from_str(content)?;
+    if table.contains_key("catalogs") || table.contains_key("defaults") {
+        return Err(layout_error);
+    }
+
+    let legacy: LegacyConfig = toml::from_str(content)?;
+    verbose!("Parsed legacy config layout (top-level catalog tables)");
+    Ok(Config {
+        catalogs: legacy.catalogs,
+        defaults: Defaults::default(),
+    })
+}
+
+/// 設定ファイルのパスを指定する環境変数 (コンテナなどホームディレクトリがない環境向け)
+pub const CONFIG_ENV_VAR: &str = "CASKET_CONFIG";
+
+/// 環境変数 `CASKET_CONFIG` で指定された設定ファイルのパス (未設定や空なら None)
+pub fn env_config_path() -> Option<PathBuf> {
+    std::env::var_os(CONFIG_ENV_VAR)
+        .filter(|value| !value.is_empty())
+        .map(PathBuf::from)
 }
 
-/// 設定ファイルのデフォルトパスを取得
-fn default_config_path() -> Result<PathBuf, io::Error> {
+/// 設定ファイルのデフォルトパスを取得 (`CASKET_CONFIG` が設定されていればそのパス)
+pub fn default_config_path() -> Result<PathBuf, io::Error> {
+    if let Some(path) = env_config_path() {
+        return Ok(path);
+    }
     // macOSの標準的な設定ディレクトリ (~/Library/Application Support) を使うことも検討
     // ここでは ~/.config/casket/catalogs.toml を仮のデフォルトとする
     dirs::config_dir()
@@ -27,26 +836,128 @@ fn default_config_path() -> Result<PathBuf, io::Error> {
         .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Config directory not found"))
 }
 
+/// `start` から親ディレクトリ方向にたどり、最初に見つかった `.casket.toml` を返す
+pub fn find_local_config(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join(LOCAL_CONFIG_FILE_NAME))
+        .find(|candidate| candidate.is_file())
+}
+
 /// 設定ファイルを読み込む
-pub fn load_config() -> Result<Config, Box<dyn std::error::Error>> {
-    let config_path = default_config_path()?;
-    load_config_from_path(&config_path)
+///
+/// 優先順位 (後のものが同名のカタログや `[defaults]` の項目を上書きする):
+/// 1. グローバル設定 (`config_path`、省略時は環境変数 `CASKET_CONFIG`、
+///    それもなければ `default_config_path()` の catalogs.toml)
+/// 2. カレントディレクトリから親方向に探索して最初に見つかった `.casket.toml`
+///
+/// `config_path` か `CASKET_CONFIG` で明示した場合、そのファイルが存在しなければエラーにする
+pub fn load_config(config_path: Option<&Path>) -> Result<Config, Box<dyn std::error::Error>> {
+    let config_path = match config_path.map(Path::to_path_buf).or_else(env_config_path) {
+        Some(path) if !path.exists() => {
+            return Err(format!("Config file {:?} not found", path).into());
+        }
+        Some(path) => path,
+        None => default_config_path()?,
+    };
+    let mut config = load_config_from_path(&config_path)?;
+
+    let local_path = std::env::current_dir()
+        .ok()
+        .and_then(|cwd| find_local_config(&cwd));
+    if let Some(local_path) = local_path {
+        info!("Using local config overrides from: {:?}", local_path);
+        let local_config = load_config_from_path(&local_path)?;
+        // ローカル定義が優先 (同名カタログは丸ごと置き換え)
+        config.catalogs.extend(local_config.catalogs);
+        config.defaults.merge(local_config.defaults);
+    }
+
+    config.apply_defaults();
+    Ok(config)
 }
 
-/// 指定されたパスから設定ファイルを読み込む
+/// 指定されたパスから設定ファイルを読み込む (`[defaults]` はまだカタログに適用しない)
+///
+/// 形式は拡張子で決める (`.toml`、`.yaml` / `.yml`、`.json`、拡張子なしは TOML)。
+/// どの形式も同じ [`Config`] の構造 (`catalogs` と `defaults`) として読む。
 pub fn load_config_from_path(path: &Path) -> Result<Config, Box<dyn std::error::Error>> {
-    println!("Loading config from: {:?}", path); // デバッグ用
+    verbose!("Loading config from: {:?}", path); // デバッグ用
+    let format = ConfigFormat::from_path(path)?;
     if !path.exists() {
         // 設定ファイルが存在しない場合は空の設定を返すか、エラーとするか？
         // ここでは空の設定を返す（カタログ未定義状態）
-        println!("Config file not found, returning default empty config.");
+        info!("Config file not found, returning default empty config.");
         return Ok(Config::default());
     }
 
     let content = fs::read_to_string(path)?;
-    let config: Config = toml::from_str(&content)?;
+    let mut config =
+        parse_config(&content, format).map_err(|e| format!("Could not parse config file {:?}: {}", path, e))?;
+
+    if let Some(quality) = config.defaults.thumbnail_quality {
+        validate_thumbnail_quality(quality).map_err(|e| format!("[defaults] in {:?}: {}", path, e))?;
+    }
+    if let Some(rendering) = &config.defaults.raw_rendering {
+        rendering.validate().map_err(|e| format!("[defaults] in {:?}: {}", path, e))?;
+    }
+    if let Some(geocoding) = &config.defaults.geocoding {
+        geocoding.validate().map_err(|e| format!("[defaults] in {:?}: {}", path, e))?;
+    }
+    for (name, catalog) in &config.catalogs {
+        if let Some(quality) = catalog.thumbnail_quality {
+            validate_thumbnail_quality(quality)
+                .map_err(|e| format!("Catalog '{}' in {:?}: {}", name, path, e))?;
+        }
+        if let Some(rendering) = &catalog.raw_rendering {
+            rendering
+                .validate()
+                .map_err(|e| format!("Catalog '{}' in {:?}: {}", name, path, e))?;
+        }
+        if let Some(subdir) = &catalog.thumbnail_subdir {
+            validate_thumbnail_subdir(subdir)
+                .map_err(|e| format!("Catalog '{}' in {:?}: {}", name, path, e))?;
+        }
+        if let Some(geocoding) = &catalog.geocoding {
+            geocoding
+                .validate()
+                .map_err(|e| format!("Catalog '{}' in {:?}: {}", name, path, e))?;
+        }
+    }
+
+    // カタログ内の相対パスは設定ファイルのディレクトリを基準に解決する
+    if let Some(base_dir) = path.parent() {
+        for catalog in config.catalogs.values_mut() {
+            catalog.resolve_relative_paths(base_dir);
+        }
+        if let Some(geocoding) = &mut config.defaults.geocoding {
+            geocoding.resolve_relative_paths(base_dir);
+        }
+    }
     Ok(config)
 }
 
+/// ディレクトリが存在し、書き込み可能であることを確認する
+/// `create` が true の場合は存在しないディレクトリを作成する
+pub fn ensure_writable_dir(path: &Path, create: bool) -> io::Result<()> {
+    if !path.exists() {
+        if !create {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "directory does not exist"));
+        }
+        info!("Creating directory: {:?}", path);
+        fs::create_dir_all(path)?;
+    }
+
+    if !path.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "path is not a directory"));
+    }
+
+    // 実際に一時ファイルを書き込んで書き込み権限を確認する
+    let probe = path.join(format!(".casket_write_test_{}", std::process::id()));
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)?;
+    Ok(())
+}
+
 // 設定ファイルが存在しない場合にデフォルト設定で作成する関数なども検討可能
 // pub fn ensure_config_file_exists() -> Result<PathBuf, io::Error> { ... }
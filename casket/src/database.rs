@@ -1,17 +1,44 @@
 use crate::processor::ProcessedInfo;
-use chrono::SecondsFormat; // For ISO 8601 formatting
+use chrono::{NaiveDate, SecondsFormat, Utc}; // For ISO 8601 formatting
+use rusqlite::types::ValueRef;
 use rusqlite::{params, Connection, Result, Transaction}; // Added params and Transaction
-use std::path::Path;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 /// データベース接続を開く (ファイルが存在しなければ作成される)
-pub fn open_database(db_path: &Path) -> Result<Connection> {
-    println!("Opening database connection to: {:?}", db_path);
-    Connection::open(db_path)
+///
+/// インポート中も他のツールから読めるよう WAL モードにし、他の接続のロックは `busy_timeout` まで待つ。
+/// WAL にできないファイルシステム (ネットワークドライブなど) では警告を出して従来のジャーナルのまま使う。
+/// レコードの削除でタグ付けも消えるよう、外部キー制約を有効にする。
+pub fn open_database(db_path: &Path, busy_timeout: Duration) -> Result<Connection> {
+    info!("Opening database connection to: {:?}", db_path);
+    let conn = Connection::open(db_path)?;
+    conn.busy_timeout(busy_timeout)?;
+    conn.pragma_update(None, "foreign_keys", true)?;
+    let journal_mode: String = conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))?;
+    if !journal_mode.eq_ignore_ascii_case("wal") {
+        warn!(
+            "Warning: could not enable WAL mode for {:?} (journal mode is {}), readers may be blocked during imports",
+            db_path, journal_mode
+        );
+    }
+    Ok(conn)
+}
+
+/// パスを DB 保存用の文字列にする
+/// `to_string_lossy` で置換文字に化けると元ファイルと対応しなくなるため、UTF-8 でないパスはエラーにする
+fn path_to_sql(path: &Path) -> Result<&str> {
+    path.to_str().ok_or_else(|| {
+        rusqlite::Error::ToSqlConversionFailure(
+            format!("path is not valid UTF-8: {:?}", path).into(),
+        )
+    })
 }
 
 /// 必要なテーブルを作成する (存在しない場合のみ)
 pub fn create_tables(conn: &Connection) -> Result<()> {
-    println!("Creating database tables if they don't exist...");
+    info!("Creating database tables if they don't exist...");
     conn.execute(
         "CREATE TABLE IF NOT EXISTS media_items (
             id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -27,7 +54,95 @@ pub fn create_tables(conn: &Connection) -> Result<()> {
         )",
         [], // no parameters
     )?;
-    println!("Table 'media_items' checked/created.");
+    info!("Table 'media_items' checked/created.");
+
+    add_missing_columns(conn)?;
+
+    // 日付範囲・カメラ・種類での絞り込み用インデックス (追加カラムを参照するものは ALTER TABLE の後に作成)
+    conn.execute_batch(
+        "CREATE INDEX IF NOT EXISTS idx_media_items_import_session ON media_items (import_session_id);
+         CREATE INDEX IF NOT EXISTS idx_media_items_datetime_indexed ON media_items (datetime_indexed);
+         CREATE INDEX IF NOT EXISTS idx_media_items_camera_datetime ON media_items (camera_model, datetime_indexed);
+         CREATE INDEX IF NOT EXISTS idx_media_items_media_kind ON media_items (media_kind);",
+    )?;
+
+    // 中断したインポートの再開用ジャーナル (処理を終えたファイルを記録し、DB保存に成功したら消す)
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS import_journal (
+            session_id TEXT NOT NULL,    -- インポートセッションID
+            source_path TEXT NOT NULL,   -- インポート元ディレクトリ
+            original_path TEXT NOT NULL, -- 処理 (コピー、サムネイル生成) を終えた元ファイル
+            PRIMARY KEY (session_id, original_path)
+        )",
+        [],
+    )?;
+
+    // タグとレコードの対応 (レコードかタグを削除すると対応も消える)
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS tags (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL UNIQUE COLLATE NOCASE, -- タグ名 (大文字小文字は区別しない)
+            created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );
+        CREATE TABLE IF NOT EXISTS media_tags (
+            media_item_id INTEGER NOT NULL REFERENCES media_items (id) ON DELETE CASCADE,
+            tag_id INTEGER NOT NULL REFERENCES tags (id) ON DELETE CASCADE,
+            PRIMARY KEY (media_item_id, tag_id)
+        );
+        CREATE INDEX IF NOT EXISTS idx_media_tags_tag ON media_tags (tag_id);",
+    )?;
+    Ok(())
+}
+
+/// 初期スキーマ以降に追加したカラム (名前, 型定義)
+/// 新規DB・既存DBのどちらにも ALTER TABLE で追加される
+const ADDED_COLUMNS: &[(&str, &str)] = &[
+    ("width", "INTEGER"),  // 元画像の幅 (px)
+    ("height", "INTEGER"), // 元画像の高さ (px)
+    ("rating", "INTEGER"), // XMPサイドカーのレーティング (0-5)
+    ("keywords", "TEXT"),  // XMPサイドカーのキーワード (カンマ区切り)
+    ("content_hash", "TEXT"), // コピー検証済みの SHA-256 (重複検出・ビットロット検出用)
+    ("import_session_id", "TEXT"), // 同じ実行でインポートしたレコードに共通のID
+    ("focal_length", "REAL"),     // 焦点距離 (mm)
+    ("focal_length_35mm", "INTEGER"), // 35mm判換算の焦点距離 (mm)
+    ("exposure_program", "TEXT"), // 露出プログラム
+    ("file_size", "INTEGER"),     // 元ファイルのバイト数
+    ("thumbnail_width", "INTEGER"),  // 保存したサムネイルの幅 (px)
+    ("thumbnail_height", "INTEGER"), // 保存したサムネイルの高さ (px)
+    ("gps_latitude", "REAL"),  // 緯度 (度、南緯は負)
+    ("gps_longitude", "REAL"), // 経度 (度、西経は負)
+    ("gps_altitude", "REAL"),  // 高度 (m、海面下は負)
+    ("gps_direction", "REAL"), // 撮影方向 (度)
+    ("artist", "TEXT"),        // 撮影者 (EXIF Artist)
+    ("copyright", "TEXT"),     // 著作権表示 (EXIF Copyright)
+    ("live_photo_group", "TEXT"), // Live Photo の静止画と動画で共通の識別子
+    ("white_balance", "TEXT"),    // ホワイトバランス (例: "auto white balance", "manual white balance")
+    ("flash_fired", "INTEGER"),   // フラッシュ発光 (1 = 発光、0 = 非発光)
+    ("location_name", "TEXT"),    // 撮影地の地名 (逆ジオコーディング)
+    ("date_source", "TEXT"),      // 保存先の日付に使った日時の出どころ (exif_original, video_created, file_modified など)
+    ("color_space", "TEXT"),      // 色空間 ("sRGB" / "Adobe RGB" / "uncalibrated")
+    ("has_icc_profile", "INTEGER"), // ICC プロファイルの埋め込み (1 = あり、0 = なし、NULL = 不明)
+    ("media_kind", "TEXT"),       // ファイルの種類 ("photo" / "raw" / "heic" / "video" / "other")
+    ("body_serial", "TEXT"),      // カメラ本体のシリアル番号 (EXIF BodySerialNumber)
+    ("lens_serial", "TEXT"),      // レンズのシリアル番号 (EXIF LensSerialNumber)
+];
+
+/// media_items に存在しないカラムを追加する (既存DBのマイグレーション)
+fn add_missing_columns(conn: &Connection) -> Result<()> {
+    let existing: HashSet<String> = conn
+        .prepare("PRAGMA table_info(media_items)")?
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<_>>()?;
+
+    for (name, definition) in ADDED_COLUMNS {
+        if !existing.contains(*name) {
+            conn.execute(
+                &format!("ALTER TABLE media_items ADD COLUMN {} {}", name, definition),
+                [],
+            )?;
+            info!("Added column '{}' to 'media_items'.", name);
+        }
+    }
     Ok(())
 }
 
@@ -35,6 +150,7 @@ pub fn create_tables(conn: &Connection) -> Result<()> {
 fn save_processed_info_txn(
     tx: &Transaction,
     processed_info: &ProcessedInfo,
+    session_id: &str,
 ) -> Result<usize> { // Returns number of affected rows (0 if ignored)
     // println!("Saving info for {:?} to database...", processed_info.original_path); // Logged in save_all
 
@@ -42,22 +158,37 @@ fn save_processed_info_txn(
     let datetime_str = processed_info
         .metadata
         .datetime_original
-        .map(|dt| dt.to_rfc3339_opts(SecondsFormat::Secs, true)); // Use RFC3339 (ISO 8601 compatible)
+        .map(|dt| dt.to_rfc3339_opts(SecondsFormat::AutoSi, true)); // RFC3339 (ISO 8601互換)、小数秒は存在する場合のみ
 
-    // Convert PathBufs to Strings (handle potential non-UTF8 paths?)
-    let original_path_str = processed_info.original_path.to_string_lossy().to_string();
-    let data_path_str = processed_info.data_dest_path.to_string_lossy().to_string();
+    // パスは UTF-8 として保存する (変換できないパスは重複判定キーが壊れるためエラーにする)
+    let original_path_str = path_to_sql(&processed_info.original_path)?;
+    let data_path_str = path_to_sql(&processed_info.data_dest_path)?;
     let thumbnail_path_str = processed_info
         .thumbnail_dest_path
-        .as_ref()
-        .map(|p| p.to_string_lossy().to_string());
+        .as_deref()
+        .map(path_to_sql)
+        .transpose()?;
+
+    // キーワードはカンマ区切りで保存 (なければ NULL)
+    let keywords_str = if processed_info.metadata.keywords.is_empty() {
+        None
+    } else {
+        Some(processed_info.metadata.keywords.join(","))
+    };
 
     // INSERT OR IGNORE: 重複する original_path があれば挿入をスキップする
     tx.execute(
         "INSERT OR IGNORE INTO media_items (
             original_path, data_path, thumbnail_path,
-            datetime_original, datetime_indexed, camera_make, camera_model
-        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            datetime_original, datetime_indexed, camera_make, camera_model,
+            width, height, rating, keywords, content_hash, import_session_id,
+            focal_length, focal_length_35mm, exposure_program, file_size,
+            thumbnail_width, thumbnail_height,
+            gps_latitude, gps_longitude, gps_altitude, gps_direction,
+            artist, copyright, live_photo_group, white_balance, flash_fired, location_name,
+            date_source, color_space, has_icc_profile, media_kind, body_serial, lens_serial
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19,
+                  ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28, ?29, ?30, ?31, ?32, ?33, ?34, ?35)",
         params![
             original_path_str,
             data_path_str,
@@ -66,63 +197,541 @@ fn save_processed_info_txn(
             processed_info.datetime_indexed,
             processed_info.metadata.camera_make,
             processed_info.metadata.camera_model,
+            processed_info.width,
+            processed_info.height,
+            processed_info.metadata.rating,
+            keywords_str,
+            processed_info.content_hash,
+            session_id,
+            processed_info.metadata.focal_length,
+            processed_info.metadata.focal_length_35mm,
+            processed_info.metadata.exposure_program,
+            processed_info.file_size,
+            processed_info.thumbnail_width,
+            processed_info.thumbnail_height,
+            processed_info.metadata.gps_latitude,
+            processed_info.metadata.gps_longitude,
+            processed_info.metadata.gps_altitude,
+            processed_info.metadata.gps_direction,
+            processed_info.metadata.artist,
+            processed_info.metadata.copyright,
+            processed_info.live_photo_group,
+            processed_info.metadata.white_balance,
+            processed_info.metadata.flash_fired,
+            processed_info.metadata.location_name,
+            processed_info.date_source.as_str(),
+            processed_info.metadata.color_space,
+            processed_info.metadata.has_icc_profile,
+            processed_info.media_kind,
+            processed_info.metadata.body_serial,
+            processed_info.metadata.lens_serial,
         ],
     )
 }
 
-/// 複数の処理結果をまとめてデータベースに保存する (トランザクション使用)
+/// インポートセッションIDを生成する (1回の実行につき1つ)
+/// UTC のタイムスタンプ始まりなので、文字列順がそのまま実行順になる
+pub fn new_import_session_id() -> String {
+    format!(
+        "{}-{}",
+        Utc::now().format("%Y%m%dT%H%M%S%.6fZ"),
+        std::process::id()
+    )
+}
+
+/// [`save_all_processed_info`] のエラー
+#[derive(Debug, thiserror::Error)]
+pub enum SaveError {
+    /// `strict` 指定時に、1件のレコードの保存に失敗した (そのチャンクはロールバック済み)
+    #[error("could not save {path:?}: {source}")]
+    Record {
+        path: PathBuf,
+        source: rusqlite::Error,
+    },
+    /// トランザクションの開始やコミットの失敗
+    #[error(transparent)]
+    Database(#[from] rusqlite::Error),
+}
+
+/// `save_all_processed_info` の1トランザクションあたりの件数 (CLI の `--db-chunk-size` の既定値)
+pub const DEFAULT_DB_CHUNK_SIZE: usize = 1000;
+
+/// 複数の処理結果をまとめてデータベースに保存する
+/// 全レコードに同じ `session_id` を記録する
+/// `chunk_size` 件ごとに別のトランザクションでコミットする (0 なら全件を1トランザクション)。
+/// 大量のインポートでもトランザクションが肥大化せず、中断しても保存済みのチャンクは残る。
+/// `strict` が true の場合、1件でも挿入に失敗したらそのチャンクをロールバックしてエラーを返す
+/// (それより前にコミットしたチャンクは残る)。
+/// 非strictでは保存に失敗したレコードの元ファイルパスとエラーを返す
 pub fn save_all_processed_info(
     conn: &mut Connection, // Needs mutable connection for transaction
     results: &[ProcessedInfo],
-) -> Result<()> {
-    println!("\nSaving all processed info to database...");
-    let tx = conn.transaction()?; // Start transaction
+    session_id: &str,
+    strict: bool,
+    chunk_size: usize,
+) -> std::result::Result<Vec<(PathBuf, rusqlite::Error)>, SaveError> {
+    info!("\nSaving all processed info to database...");
+    let chunk_size = if chunk_size == 0 { results.len().max(1) } else { chunk_size };
+    let chunk_count = results.len().div_ceil(chunk_size);
 
     let mut saved_count = 0;
     let mut ignored_count = 0;
-    let mut error_count = 0;
-
-    for info in results {
-        match save_processed_info_txn(&tx, info) {
-            Ok(affected_rows) => {
-                if affected_rows > 0 {
-                    saved_count += 1;
-                    println!("  Saved info for {:?}", info.original_path);
-                } else {
-                    ignored_count += 1;
-                     println!("  Ignored duplicate entry for {:?}", info.original_path);
+    let mut failed = Vec::new();
+
+    for (chunk_index, chunk) in results.chunks(chunk_size).enumerate() {
+        let tx = conn.transaction()?; // チャンクごとにトランザクションを開始
+
+        for info in chunk {
+            match save_processed_info_txn(&tx, info, session_id) {
+                Ok(affected_rows) => {
+                    if affected_rows > 0 {
+                        saved_count += 1;
+                        info!("  Saved info for {:?}", info.original_path);
+                    } else {
+                        ignored_count += 1;
+                        info!("  Ignored duplicate entry for {:?}", info.original_path);
+                    }
+                }
+                Err(e) => {
+                    error!("  Error saving info for {:?}: {}", info.original_path, e);
+                    if strict {
+                        // strictモード: このチャンクを取り消してエラーを返す
+                        tx.rollback()?;
+                        if chunk_count > 1 {
+                            error!(
+                                "Database save aborted (--strict-db). Chunk {}/{} was rolled back; earlier chunks remain saved.",
+                                chunk_index + 1,
+                                chunk_count
+                            );
+                        } else {
+                            error!("Database save aborted (--strict-db). All changes in this batch were rolled back.");
+                        }
+                        return Err(SaveError::Record {
+                            path: info.original_path.clone(),
+                            source: e,
+                        });
+                    }
+                    failed.push((info.original_path.clone(), e));
+                    // 非strictモードでは個別の挿入エラーとして扱い、処理を続行する
                 }
             }
-            Err(e) => {
-                eprintln!("  Error saving info for {:?}: {}", info.original_path, e);
-                error_count += 1;
-                // トランザクション内のエラーは通常ロールバックすべきだが、
-                // ここでは個別の挿入エラーとして扱い、処理を続行する方針
-                // (ロールバックする場合は早期リターン `Err(e)?` または `tx.rollback()?` を使う)
-            }
+        }
+
+        // 非strictモードではエラーがあっても成功分はコミットする
+        // (バッチ全体を原子的にしたい場合は --strict-db と --db-chunk-size 0 を使う)
+        tx.commit()?;
+        if chunk_count > 1 {
+            info!(
+                "  Committed chunk {}/{} ({} records saved so far)",
+                chunk_index + 1,
+                chunk_count,
+                saved_count
+            );
         }
     }
 
-    if error_count == 0 {
-        tx.commit()?; // Commit transaction if no errors occurred during iteration
-        println!(
+    if failed.is_empty() {
+        info!(
             "Database save complete. {} new records saved, {} duplicates ignored.",
             saved_count, ignored_count
         );
     } else {
-        // エラーがあった場合、コミットせずにロールバックすることも検討できるが、
-        // ここでは個別のエラーとして扱い、成功分はコミットする方針
-        // (ただし、上記ループ内でエラー時に早期リターンしていないため、
-        //  エラーがあっても tx.commit() が呼ばれる。より厳密なエラー処理が必要なら要修正)
-         tx.commit()?; // ここではエラーがあってもコミットする
-         eprintln!(
+         error!(
              "Database save finished with errors. {} new records saved, {} duplicates ignored, {} errors.",
-             saved_count, ignored_count, error_count
+             saved_count, ignored_count, failed.len()
          );
-         eprintln!("Please check database save errors above.");
-         // エラーがあったことを示すためにエラーを返すことも検討
-         // return Err(rusqlite::Error::ExecuteReturnedMoreThanOneRow); // ダミーのエラー型
+         error!("Please check database save errors above.");
+    }
+
+    Ok(failed)
+}
+
+/// レコードの ID とファイルパス (undo-last-import / verify-catalog 用)
+#[derive(Debug)]
+pub struct MediaItemPaths {
+    pub id: i64,
+    pub original_path: PathBuf,
+    pub data_path: PathBuf,
+    pub thumbnail_path: Option<PathBuf>,
+}
+
+/// 最後に実行したインポートセッションのIDを取得する (セッションIDのないレコードは対象外)
+pub fn latest_import_session(conn: &Connection) -> Result<Option<String>> {
+    conn.query_row(
+        "SELECT MAX(import_session_id) FROM media_items",
+        [],
+        |row| row.get(0),
+    )
+}
+
+/// 指定したインポートセッションのレコードを取得する
+pub fn session_items(conn: &Connection, session_id: &str) -> Result<Vec<MediaItemPaths>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, original_path, data_path, thumbnail_path FROM media_items
+         WHERE import_session_id = ?1 ORDER BY id",
+    )?;
+    let rows = stmt.query_map(params![session_id], media_item_paths_from_row)?;
+    rows.collect()
+}
+
+/// 処理を終えたファイルをインポートジャーナルに記録する
+pub fn journal_completed(
+    conn: &Connection,
+    session_id: &str,
+    source: &Path,
+    original_path: &Path,
+) -> Result<()> {
+    conn.execute(
+        "INSERT OR IGNORE INTO import_journal (session_id, source_path, original_path)
+         VALUES (?1, ?2, ?3)",
+        params![session_id, path_to_sql(source)?, path_to_sql(original_path)?],
+    )?;
+    Ok(())
+}
+
+/// `source` からのインポートで、DB保存まで終わらなかった最後のセッションと処理済みのファイルを取得する
+pub fn unfinished_import(
+    conn: &Connection,
+    source: &Path,
+) -> Result<Option<(String, HashSet<PathBuf>)>> {
+    let source = path_to_sql(source)?;
+    let session_id: Option<String> = conn.query_row(
+        "SELECT MAX(session_id) FROM import_journal WHERE source_path = ?1",
+        params![source],
+        |row| row.get(0),
+    )?;
+    let Some(session_id) = session_id else {
+        return Ok(None);
+    };
+    let mut stmt = conn.prepare("SELECT original_path FROM import_journal WHERE session_id = ?1")?;
+    let paths = stmt
+        .query_map(params![session_id], |row| row.get::<_, String>(0).map(PathBuf::from))?
+        .collect::<Result<_>>()?;
+    Ok(Some((session_id, paths)))
+}
+
+/// `source` からのインポートのジャーナルを削除する (DB保存に成功した後に呼ぶ)
+/// 再開せずにやり直した場合も、中断したセッションの記録はもう要らないので合わせて消す
+pub fn clear_journal(conn: &Connection, source: &Path) -> Result<usize> {
+    conn.execute(
+        "DELETE FROM import_journal WHERE source_path = ?1",
+        params![path_to_sql(source)?],
+    )
+}
+
+/// 逆ジオコーディングのキャッシュDBを開く (ファイルやテーブルがなければ作成する)
+pub fn open_geocoding_cache(path: &Path) -> Result<Connection> {
+    verbose!("Opening geocoding cache: {:?}", path);
+    let conn = Connection::open(path)?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS geocoding_cache (
+            coordinates TEXT PRIMARY KEY, -- 丸めた座標 (例: \"35.681,139.767\")
+            place_name TEXT,              -- 地名 (見つからなかった場合は NULL)
+            looked_up_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+    Ok(conn)
+}
+
+/// キャッシュした地名を取得する (キャッシュにない場合は None、地名が見つからなかった座標は Some(None))
+pub fn cached_place_name(conn: &Connection, coordinates: &str) -> Result<Option<Option<String>>> {
+    let mut stmt = conn.prepare("SELECT place_name FROM geocoding_cache WHERE coordinates = ?1")?;
+    let mut rows = stmt.query(params![coordinates])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(row.get(0)?)),
+        None => Ok(None),
     }
+}
 
+/// 問い合わせた地名をキャッシュする (地名が見つからなかった座標も None で記録する)
+pub fn cache_place_name(conn: &Connection, coordinates: &str, place_name: Option<&str>) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO geocoding_cache (coordinates, place_name, looked_up_at) VALUES (?1, ?2, ?3)",
+        params![coordinates, place_name, Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)],
+    )?;
     Ok(())
 }
+
+/// 全レコードの ID とファイルパスを取得する
+pub fn all_items(conn: &Connection) -> Result<Vec<MediaItemPaths>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, original_path, data_path, thumbnail_path FROM media_items ORDER BY id",
+    )?;
+    let rows = stmt.query_map([], media_item_paths_from_row)?;
+    rows.collect()
+}
+
+/// 登録済みの全レコードの元ファイルパスを取得する (`--skip-existing` 用)
+pub fn imported_original_paths(conn: &Connection) -> Result<HashSet<PathBuf>> {
+    let mut stmt = conn.prepare("SELECT original_path FROM media_items")?;
+    let rows = stmt.query_map([], |row| row.get::<_, String>(0).map(PathBuf::from))?;
+    rows.collect()
+}
+
+/// media_items の全カラムを ID 順に文字列の行として `on_row` に渡し、書き出した行数を返す (export-csv 用)
+///
+/// 最初にカラム名の行を渡すので、後から追加されたカラムもそのまま出力される。NULL は空文字列になる。
+pub fn export_media_items<E: From<rusqlite::Error>>(
+    conn: &Connection,
+    mut on_row: impl FnMut(&[String]) -> std::result::Result<(), E>,
+) -> std::result::Result<usize, E> {
+    let mut stmt = conn.prepare("SELECT * FROM media_items ORDER BY id")?;
+    let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+    on_row(&columns)?;
+
+    let mut rows = stmt.query([])?;
+    let mut exported = 0;
+    let mut values = Vec::with_capacity(columns.len());
+    while let Some(row) = rows.next()? {
+        values.clear();
+        for index in 0..columns.len() {
+            values.push(match row.get_ref(index)? {
+                ValueRef::Null => String::new(),
+                ValueRef::Integer(value) => value.to_string(),
+                ValueRef::Real(value) => value.to_string(),
+                ValueRef::Text(text) => String::from_utf8_lossy(text).into_owned(),
+                ValueRef::Blob(blob) => blob.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            });
+        }
+        on_row(&values)?;
+        exported += 1;
+    }
+    Ok(exported)
+}
+
+/// `SELECT id, original_path, data_path, thumbnail_path` の行を変換する
+fn media_item_paths_from_row(row: &rusqlite::Row) -> Result<MediaItemPaths> {
+    Ok(MediaItemPaths {
+        id: row.get(0)?,
+        original_path: PathBuf::from(row.get::<_, String>(1)?),
+        data_path: PathBuf::from(row.get::<_, String>(2)?),
+        thumbnail_path: row.get::<_, Option<String>>(3)?.map(PathBuf::from),
+    })
+}
+
+/// 指定した ID のレコードをまとめて削除する (削除件数を返す)
+/// コピー済みのデータファイルやサムネイルは削除しない
+pub fn delete_items(conn: &mut Connection, ids: &[i64]) -> Result<usize> {
+    let tx = conn.transaction()?;
+    let mut deleted = 0;
+    {
+        let mut stmt = tx.prepare("DELETE FROM media_items WHERE id = ?1")?;
+        for id in ids {
+            deleted += stmt.execute(params![id])?;
+        }
+    }
+    tx.commit()?;
+    Ok(deleted)
+}
+
+/// 指定したインポートセッションのレコードを削除する (削除件数を返す)
+/// コピー済みのデータファイルやサムネイルは削除しない
+pub fn delete_session(conn: &Connection, session_id: &str) -> Result<usize> {
+    conn.execute(
+        "DELETE FROM media_items WHERE import_session_id = ?1",
+        params![session_id],
+    )
+}
+
+/// サムネイル再生成の対象となるレコード
+#[derive(Debug)]
+pub struct ThumbnailSource {
+    pub id: i64,
+    pub data_path: PathBuf,
+}
+
+/// 全レコードの id と data_path を取得する (reindex-thumbnails 用)
+pub fn thumbnail_sources(conn: &Connection) -> Result<Vec<ThumbnailSource>> {
+    let mut stmt = conn.prepare("SELECT id, data_path FROM media_items ORDER BY id")?;
+    let rows = stmt.query_map([], |row| {
+        Ok(ThumbnailSource {
+            id: row.get(0)?,
+            data_path: PathBuf::from(row.get::<_, String>(1)?),
+        })
+    })?;
+    rows.collect()
+}
+
+/// 再生成したサムネイルのパス・サイズと元画像サイズでレコードを更新する
+pub fn update_thumbnail(
+    conn: &Connection,
+    id: i64,
+    thumbnail_path: &Path,
+    source_dimensions: Option<(u32, u32)>,
+    thumbnail_dimensions: Option<(u32, u32)>,
+) -> Result<usize> {
+    let (width, height) = source_dimensions.unzip();
+    let (thumbnail_width, thumbnail_height) = thumbnail_dimensions.unzip();
+    conn.execute(
+        "UPDATE media_items SET thumbnail_path = ?1, width = COALESCE(?2, width), height = COALESCE(?3, height),
+         thumbnail_width = ?4, thumbnail_height = ?5
+         WHERE id = ?6",
+        params![
+            path_to_sql(thumbnail_path)?,
+            width,
+            height,
+            thumbnail_width,
+            thumbnail_height,
+            id
+        ],
+    )
+}
+
+/// カタログ全体の集計 (stats サブコマンド用)
+#[derive(Debug, Default)]
+pub struct CatalogStats {
+    pub total_items: i64,
+    /// カメラモデルごとの件数 (件数の多い順、モデル不明は None)
+    pub items_by_camera_model: Vec<(Option<String>, i64)>,
+    /// 最も古い撮影日時 (ISO 8601)
+    pub earliest_datetime: Option<String>,
+    /// 最も新しい撮影日時 (ISO 8601)
+    pub latest_datetime: Option<String>,
+    /// サムネイルのないレコード数
+    pub missing_thumbnails: i64,
+}
+
+/// カタログの件数や撮影期間などを集計する
+pub fn catalog_stats(conn: &Connection) -> Result<CatalogStats> {
+    let (total_items, earliest_datetime, latest_datetime, missing_thumbnails) = conn.query_row(
+        "SELECT COUNT(*), MIN(datetime_original), MAX(datetime_original),
+                COUNT(*) - COUNT(thumbnail_path)
+         FROM media_items",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+    )?;
+
+    let mut stmt = conn.prepare(
+        "SELECT camera_model, COUNT(*) AS n FROM media_items
+         GROUP BY camera_model ORDER BY n DESC, camera_model",
+    )?;
+    let items_by_camera_model = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(CatalogStats {
+        total_items,
+        items_by_camera_model,
+        earliest_datetime,
+        latest_datetime,
+        missing_thumbnails,
+    })
+}
+
+/// タグ付けの対象を絞り込む条件 (None の条件は絞り込まない)
+#[derive(Debug, Default)]
+pub struct ItemFilter {
+    /// この日以降に撮影したもの (datetime_indexed で比較)
+    pub from: Option<NaiveDate>,
+    /// この日までに撮影したもの (この日を含む)
+    pub to: Option<NaiveDate>,
+    /// カメラモデル (大文字小文字は区別しない)
+    pub camera_model: Option<String>,
+}
+
+/// タグを登録してその ID を返す (同名のタグがあればその ID)
+pub fn add_tag(conn: &Connection, name: &str) -> Result<i64> {
+    conn.execute("INSERT OR IGNORE INTO tags (name) VALUES (?1)", params![name])?;
+    conn.query_row("SELECT id FROM tags WHERE name = ?1", params![name], |row| row.get(0))
+}
+
+/// 条件に合う全レコードにタグを付ける (新たにタグを付けた件数を返す。付いていたものは数えない)
+pub fn tag_items(conn: &Connection, tag_id: i64, filter: &ItemFilter) -> Result<usize> {
+    // datetime_indexed は YYYYMMDDHH 形式なので、日付の範囲は 00 時から 23 時までの文字列で比べる
+    conn.execute(
+        "INSERT OR IGNORE INTO media_tags (media_item_id, tag_id)
+         SELECT id, ?1 FROM media_items
+         WHERE (?2 IS NULL OR datetime_indexed >= ?2)
+           AND (?3 IS NULL OR datetime_indexed <= ?3)
+           AND (?4 IS NULL OR camera_model = ?4 COLLATE NOCASE)",
+        params![
+            tag_id,
+            filter.from.map(|date| date.format("%Y%m%d00").to_string()),
+            filter.to.map(|date| date.format("%Y%m%d23").to_string()),
+            filter.camera_model,
+        ],
+    )
+}
+
+/// 指定したタグ (大文字小文字は区別しない) が付いたレコードを撮影日時順に取得する
+pub fn items_by_tag(conn: &Connection, name: &str) -> Result<Vec<MediaItemPaths>> {
+    let mut stmt = conn.prepare(
+        "SELECT m.id, m.original_path, m.data_path, m.thumbnail_path FROM media_items m
+         JOIN media_tags mt ON mt.media_item_id = m.id
+         JOIN tags t ON t.id = mt.tag_id
+         WHERE t.name = ?1 ORDER BY m.datetime_indexed, m.id",
+    )?;
+    let rows = stmt.query_map(params![name], media_item_paths_from_row)?;
+    rows.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_database_enables_wal_and_busy_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = open_database(&dir.path().join("casket.db"), Duration::from_millis(1234)).unwrap();
+
+        let journal_mode: String = conn.query_row("PRAGMA journal_mode", [], |row| row.get(0)).unwrap();
+        assert_eq!(journal_mode, "wal");
+        let busy_timeout: i64 = conn.query_row("PRAGMA busy_timeout", [], |row| row.get(0)).unwrap();
+        assert_eq!(busy_timeout, 1234);
+    }
+
+    /// `EXPLAIN QUERY PLAN` の detail 列をつなげて返す
+    fn query_plan(conn: &Connection, sql: &str) -> String {
+        let mut stmt = conn.prepare(&format!("EXPLAIN QUERY PLAN {}", sql)).unwrap();
+        let details = stmt
+            .query_map([], |row| row.get::<_, String>(3))
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        details.join("\n")
+    }
+
+    #[test]
+    fn date_and_camera_queries_use_indexes() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+
+        // 5万件 (3機種、約3年分を1時間ずつ) の合成データ
+        let tx = conn.transaction().unwrap();
+        {
+            let mut stmt = tx
+                .prepare(
+                    "INSERT INTO media_items (original_path, data_path, datetime_indexed, camera_model)
+                     VALUES (?1, ?1, ?2, ?3)",
+                )
+                .unwrap();
+            let start = chrono::NaiveDate::from_ymd_opt(2021, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+            for i in 0..50_000i64 {
+                let datetime = start + chrono::Duration::hours(i / 2);
+                let camera_model = ["ILCE-7M4", "X-T5", "iPhone 15 Pro"][(i % 3) as usize];
+                stmt.execute(params![
+                    format!("/src/IMG_{:05}.JPG", i),
+                    datetime.format("%Y%m%d%H").to_string(),
+                    camera_model,
+                ])
+                .unwrap();
+            }
+        }
+        tx.commit().unwrap();
+        conn.execute_batch("ANALYZE").unwrap();
+
+        let by_date = query_plan(
+            &conn,
+            "SELECT id FROM media_items WHERE datetime_indexed BETWEEN '2022050100' AND '2022053123'",
+        );
+        assert!(by_date.contains("idx_media_items_datetime_indexed"), "{}", by_date);
+
+        let by_camera_and_date = query_plan(
+            &conn,
+            "SELECT id FROM media_items
+             WHERE camera_model = 'X-T5' AND datetime_indexed BETWEEN '2022050100' AND '2022053123'",
+        );
+        assert!(by_camera_and_date.contains("idx_media_items_camera_datetime"), "{}", by_camera_and_date);
+    }
+}
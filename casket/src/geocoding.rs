@@ -0,0 +1,161 @@
+//! GPS 座標から地名を引く逆ジオコーディング
+//!
+//! インポートでは DB 保存の前に [`Geocoder`] で地名を引き、media_items の location_name に保存する。
+//! カタログに `geocoding` の設定がなければ何もしない [`NoGeocoder`] を使う。
+//! 問い合わせやキャッシュの失敗はインポートを止めず、警告を出して地名なしで続ける。
+
+use crate::config::Geocoding;
+use crate::database;
+use rusqlite::Connection;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 座標から地名を引く処理 (独自の実装は [`crate::ImportOptions::geocoder`] で渡す)
+pub trait Geocoder: fmt::Debug + Send + Sync {
+    /// 座標 (度、南緯・西経は負) の地名を返す
+    ///
+    /// 地名が見つからない場合や問い合わせに失敗した場合は None を返す (インポートは続ける)。
+    fn place_name(&self, latitude: f64, longitude: f64) -> Option<String>;
+}
+
+/// 地名を引かない Geocoder (設定がない場合の既定)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoGeocoder;
+
+impl Geocoder for NoGeocoder {
+    fn place_name(&self, _latitude: f64, _longitude: f64) -> Option<String> {
+        None
+    }
+}
+
+/// カタログの `geocoding` 設定から Geocoder を作る
+///
+/// 設定がない場合と、キャッシュを開けない場合 (警告を出す) は [`NoGeocoder`] を返す。
+/// キャッシュなしで問い合わせると同じ場所を何度も引いてしまうため、キャッシュは必須にしている。
+pub fn from_config(config: Option<&Geocoding>) -> Box<dyn Geocoder> {
+    let Some(config) = config else {
+        return Box::new(NoGeocoder);
+    };
+    match HttpGeocoder::new(config.clone()) {
+        Ok(geocoder) => Box::new(geocoder),
+        Err(e) => {
+            warn!("Warning: reverse geocoding is disabled: {}", e);
+            Box::new(NoGeocoder)
+        }
+    }
+}
+
+/// 設定した URL に問い合わせ、結果をローカルの SQLite にキャッシュする Geocoder
+///
+/// 座標は `precision` 桁に丸めてから問い合わせ、その値をキャッシュのキーにする。
+/// 問い合わせに一度失敗したら (オフラインなど)、その Geocoder ではキャッシュだけを使う。
+#[derive(Debug)]
+pub struct HttpGeocoder {
+    config: Geocoding,
+    agent: ureq::Agent,
+    state: Mutex<HttpState>,
+}
+
+/// 問い合わせごとに更新する状態 (並列処理から呼ばれても問い合わせは1つずつ行う)
+#[derive(Debug)]
+struct HttpState {
+    cache: Connection,
+    last_request: Option<Instant>,
+    offline: bool,
+}
+
+impl HttpGeocoder {
+    /// キャッシュDBを開いて Geocoder を作る (キャッシュのディレクトリがなければ作成する)
+    pub fn new(config: Geocoding) -> Result<Self, Box<dyn Error>> {
+        let cache_path = config
+            .cache_path()
+            .ok_or("no cache directory found; set geocoding.cache_path")?;
+        if let Some(parent) = cache_path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("could not create cache directory {:?}: {}", parent, e))?;
+        }
+        let cache = database::open_geocoding_cache(&cache_path)
+            .map_err(|e| format!("could not open cache {:?}: {}", cache_path, e))?;
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .user_agent(concat!("casket/", env!("CARGO_PKG_VERSION")))
+            .build();
+        Ok(HttpGeocoder {
+            config,
+            agent,
+            state: Mutex::new(HttpState {
+                cache,
+                last_request: None,
+                offline: false,
+            }),
+        })
+    }
+
+    /// 丸めた座標で問い合わせ、応答の JSON から `name_field` の文字列を取り出す
+    fn request(&self, latitude: &str, longitude: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let url = self
+            .config
+            .endpoint
+            .replace("{lat}", latitude)
+            .replace("{lon}", longitude);
+        verbose!("  Reverse geocoding: {}", url);
+        let response = self.agent.get(&url).call()?;
+        let body: serde_json::Value = serde_json::from_reader(response.into_reader())?;
+        Ok(body
+            .pointer(&self.config.name_field)
+            .and_then(serde_json::Value::as_str)
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(String::from))
+    }
+}
+
+impl Geocoder for HttpGeocoder {
+    fn place_name(&self, latitude: f64, longitude: f64) -> Option<String> {
+        let precision = usize::from(self.config.precision);
+        let latitude = format!("{:.*}", precision, latitude);
+        let longitude = format!("{:.*}", precision, longitude);
+        let key = format!("{},{}", latitude, longitude);
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        match database::cached_place_name(&state.cache, &key) {
+            Ok(Some(place_name)) => return place_name,
+            Ok(None) => {}
+            Err(e) => warn!("  Warning: could not read the geocoding cache: {}", e),
+        }
+        if state.offline {
+            return None;
+        }
+
+        // 問い合わせの間隔を空ける (公開サービスの利用制限を超えないように)
+        let interval = Duration::from_secs_f64(self.config.min_interval_secs);
+        if let Some(last_request) = state.last_request {
+            let wait = interval.saturating_sub(last_request.elapsed());
+            if !wait.is_zero() {
+                thread::sleep(wait);
+            }
+        }
+        state.last_request = Some(Instant::now());
+
+        match self.request(&latitude, &longitude) {
+            Ok(place_name) => {
+                if let Err(e) = database::cache_place_name(&state.cache, &key, place_name.as_deref()) {
+                    warn!("  Warning: could not update the geocoding cache: {}", e);
+                }
+                place_name
+            }
+            Err(e) => {
+                warn!(
+                    "  Warning: reverse geocoding failed for {}: {} (using cached place names only for the rest of this import)",
+                    key, e
+                );
+                state.offline = true;
+                None
+            }
+        }
+    }
+}
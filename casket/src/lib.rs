@@ -0,0 +1,854 @@
+//! Casket: 写真・動画ファイルを日付ベースのカタログにインポートするライブラリ
+//!
+//! CLI (`main.rs`) と同じインポート処理 (スキャン → コピー/サムネイル生成 → DB保存) を
+//! [`import`] から利用できる。
+
+#[macro_use]
+pub mod output; // 出力詳細度制御 (info!/verbose! マクロ) を他モジュールより先に宣言
+pub mod config;
+pub mod database;
+pub mod geocoding;
+pub mod processor;
+pub mod scanner;
+
+pub use config::Catalog;
+pub use geocoding::Geocoder;
+pub use processor::{DateSource, LinkMode, Metadata, ProcessOptions, ProcessedInfo, ProcessorError};
+pub use scanner::{FileInfo, LivePhoto, ScanOptions, SortBy};
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use processor::ProcessorResult;
+
+/// ファイル単位の処理エラーが起きたときの方針
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnError {
+    /// エラーを記録して残りのファイルの処理を続ける
+    #[default]
+    Continue,
+    /// 最初のエラーで中断し、DBには何も保存しない
+    Stop,
+}
+
+impl FromStr for OnError {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "continue" => Ok(Self::Continue),
+            "stop" => Ok(Self::Stop),
+            other => Err(format!(
+                "unknown error policy {:?} (expected \"stop\" or \"continue\")",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Continue => "continue",
+            Self::Stop => "stop",
+        })
+    }
+}
+
+/// インポート処理のオプション
+#[derive(Debug, Default)]
+pub struct ImportOptions {
+    /// スキャン時の絞り込み条件
+    pub scan: ScanOptions,
+    /// カタログのデータ/サムネイル保存先が存在しない場合に作成する
+    pub create_dirs: bool,
+    /// ファイル単位の処理 (コピー検証、リトライ) の設定
+    pub process: ProcessOptions,
+    /// DB保存で1件でもエラーがあればバッチ全体をロールバックする
+    pub strict_db: bool,
+    /// DB保存で1トランザクションにまとめる件数 (0 なら全件を1トランザクション)
+    pub db_chunk_size: usize,
+    /// ファイル単位の処理エラー時に続行するか中断するか
+    pub on_error: OnError,
+    /// 並行して処理するファイル数 (0 と 1 は逐次処理)
+    pub jobs: usize,
+    /// DB保存に成功したら、カタログに取り込んだソースファイルを削除する
+    pub move_files: bool,
+    /// `move_files` でソースを削除した後、ソース配下の空になったディレクトリを削除する
+    pub prune_empty_dirs: bool,
+    /// `move_files` で読み取り専用のため削除できなかったソースは、読み取り専用属性を外して削除し直す
+    /// (Windows の読み取り専用属性向け。書き込み禁止のメディアやファイルシステムでは効果がない)
+    pub force_remove_read_only: bool,
+    /// カタログのDBに元ファイルのパスが登録済みのファイルは処理せずに飛ばす
+    pub skip_existing: bool,
+    /// 同じソースからの中断したインポートがあれば、そのセッションを引き継いでコピー済みのファイルを再利用する
+    pub resume: bool,
+    /// GPS 座標から地名を引く Geocoder (None なら各カタログの `geocoding` 設定から作る)
+    pub geocoder: Option<Box<dyn Geocoder>>,
+    /// 処理を始める前にソース全体をスキャンし、[`ImportProgress::total`] に処理するファイルの総数を入れる
+    /// (スキャンが終わるまで処理を始めない。`scan.sort_by` が `Path` 以外の場合は常にそうなる)
+    pub count_first: bool,
+    /// 事前に数えたソースのファイル数 (CLI の `--max-auto` の確認で数えた数など)
+    /// スキャンしながら処理する場合に [`ImportProgress::total`] の見込みとして使う。
+    pub expected_files: Option<usize>,
+}
+
+/// [`import`] / [`import_many`] の進捗通知
+#[derive(Debug)]
+pub struct ImportProgress<'a> {
+    /// この実行で何番目のファイルか (0始まり)
+    pub index: usize,
+    /// この実行で処理するファイルの総数 (登録済みで飛ばすファイルは含まない)
+    ///
+    /// 処理の前にスキャンを終える場合 ([`ImportOptions::count_first`] か、`sort_by` が `Path` 以外) は
+    /// 正確な数。スキャンしながら処理する場合は [`ImportOptions::expected_files`] を渡したときだけ
+    /// 見込みの数が入り (登録済みで飛ばしたファイルの分だけ減っていく)、渡さなければ None。
+    pub total: Option<usize>,
+    /// [`import_many`] に渡したカタログのうち何番目への処理か ([`ImportStage::Started`] と [`import`] では 0)
+    pub catalog: usize,
+    /// 処理対象のソースファイル
+    pub path: &'a Path,
+    /// 処理の段階と結果
+    pub stage: ImportStage<'a>,
+}
+
+/// [`ImportProgress`] の段階
+#[derive(Debug)]
+pub enum ImportStage<'a> {
+    /// 処理を始める前 (複数のカタログにインポートする場合も1ファイルにつき1回)
+    Started,
+    /// 処理に成功した
+    Processed(&'a ProcessedInfo),
+    /// 処理に失敗した
+    Failed(&'a ProcessorError),
+}
+
+/// インポート結果
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// この実行のインポートセッションID (DBの import_session_id)
+    pub session_id: String,
+    /// `since` より古いため除外したファイル数
+    pub filtered_by_since: usize,
+    /// サイズ不足、ヘッダー読み込み失敗、UTF-8 でないパスのため除外したファイル
+    pub skipped: Vec<PathBuf>,
+    /// `skip_existing` でカタログに登録済みのため飛ばしたファイル数
+    pub skipped_existing: usize,
+    /// 処理に成功したファイル
+    pub processed: Vec<ProcessedInfo>,
+    /// 処理に失敗したファイルとそのエラー
+    pub failed: Vec<(PathBuf, ProcessorError)>,
+    /// 取り込んだがサムネイルを作れなかったファイルとそのエラー (RAW デコードのタイムアウト)
+    /// (ファイルは [`ImportReport::processed`] にも含まれる)
+    pub thumbnail_failed: Vec<(PathBuf, ProcessorError)>,
+    /// 処理には成功したが、DBへの保存に失敗したファイルとそのエラー
+    /// (コピーしたデータファイルとサムネイルは保存先に残る)
+    pub db_failed: Vec<(PathBuf, rusqlite::Error)>,
+    /// `move_files` で削除したソースファイル数 ([`import_many`] では全カタログで共通)
+    pub moved: usize,
+    /// `move_files` で削除できなかったソースファイルとそのエラー (コピーとDB保存は成功している)
+    pub not_removed: Vec<(PathBuf, io::Error)>,
+    /// `prune_empty_dirs` で削除した空ディレクトリ数 ([`import_many`] では全カタログで共通)
+    pub pruned_dirs: usize,
+}
+
+/// ソースディレクトリをカタログにインポートする
+///
+/// 個別ファイルのエラーは [`ImportReport::failed`] に、DB保存のエラーは [`ImportReport::db_failed`] に
+/// ファイルのパスと組で記録して処理を続行する。
+/// [`OnError::Stop`] の場合は最初のエラーでスキャンを打ち切り、DBに保存せず `Err` を返す
+/// (それまでにコピーしたファイルは保存先に残る)。
+/// 保存先の検証、スキャン、DB接続の失敗など継続できないエラーは `Err` を返す。
+///
+/// `progress` を渡すと、各ファイルの処理前後に [`ImportProgress`] で呼び出す
+/// (GUI などで独自の進捗表示をする場合に使う)。
+pub fn import(
+    source: &Path,
+    catalog: &Catalog,
+    options: ImportOptions,
+    progress: Option<&mut dyn FnMut(ImportProgress<'_>)>,
+) -> Result<ImportReport, Box<dyn Error>> {
+    let mut reports = import_many(source, &[catalog], options, progress)?;
+    Ok(reports.pop().expect("one report per catalog"))
+}
+
+/// 1回のスキャンでソースディレクトリを複数のカタログにインポートする
+///
+/// 見つけたファイルはカタログごとに続けて処理するので、2つ目以降のカタログでは
+/// 元ファイルの読み込みが OS のキャッシュに乗りやすい (RAW などのデコードはカタログごとに行う)。
+/// 結果は `catalogs` と同じ順の [`ImportReport`] で返す。エラーの扱いは [`import`] と同じで、
+/// [`OnError::Stop`] ではどのカタログのエラーでも中断し、どのDBにも保存しない。
+/// `move_files` では全カタログでDB保存まで成功したファイルだけをソースから削除する。
+pub fn import_many(
+    source: &Path,
+    catalogs: &[&Catalog],
+    mut options: ImportOptions,
+    progress: Option<&mut dyn FnMut(ImportProgress<'_>)>,
+) -> Result<Vec<ImportReport>, Box<dyn Error>> {
+    if catalogs.is_empty() {
+        return Err("No catalog to import into".into());
+    }
+
+    for catalog in catalogs {
+        // 保存先の検証 (処理ループに入ってから全ファイルで失敗するのを防ぐ)
+        // database_path を別に指定している場合はその親ディレクトリも確認する
+        let database_dir = catalog.database_path.as_deref().and_then(Path::parent);
+        let dirs = [
+            ("Data path", catalog.data_path.as_path()),
+            ("Thumbnail path", catalog.thumbnail_path.as_path()),
+        ]
+        .into_iter()
+        .chain(database_dir.map(|dir| ("Database directory", dir)));
+        for (label, path) in dirs {
+            config::ensure_writable_dir(path, options.create_dirs)
+                .map_err(|e| format!("{} {:?} is not usable: {}", label, path, e))?;
+        }
+
+        // カタログ内のファイルを再インポートしないよう、保存先はスキャン対象から外す
+        options
+            .scan
+            .exclude_dirs
+            .extend([catalog.data_path.clone(), catalog.thumbnail_path.clone()]);
+    }
+
+    // 同じ実行で各カタログに保存するレコードには共通のセッションIDを使う
+    let session_id = database::new_import_session_id();
+    let mut targets = Vec::with_capacity(catalogs.len());
+    for catalog in catalogs {
+        targets.push(open_target(source, catalog, &session_id, &mut options)?);
+    }
+    let mut collector = Collector {
+        targets,
+        progress,
+        on_error: options.on_error,
+        total: options.expected_files.map_or(FileTotal::Unknown, FileTotal::Expected),
+        skipped_everywhere: 0,
+    };
+
+    // スキャンしながらファイル処理（コピー、サムネイル生成、メタデータ抽出）
+    // 見つけたファイルから順に処理するため、大きなツリーでもすぐに進捗が出る
+    info!("\nScanning and processing files...");
+    let scanned = if options.jobs > 1 {
+        scan_and_process_parallel(source, catalogs, &options, &mut collector)
+    } else {
+        let mut index = 0;
+        let mut sequences = SequenceNumbers::default();
+        scan_source(source, &options, &mut collector, |collector, file_info| {
+            let sequence = sequences.assign(&file_info);
+            let wanted = collector.wanted(&file_info.path);
+            if wanted.is_empty() {
+                return ControlFlow::Continue(());
+            }
+            collector.started(index, &file_info.path);
+            let mut flow = ControlFlow::Continue(());
+            for target in wanted {
+                let result =
+                    processor::process_file(&file_info, catalogs[target], &options.process, sequence);
+                flow = collector.finished(target, index, file_info.path.clone(), result);
+                if flow.is_break() {
+                    break;
+                }
+            }
+            index += 1;
+            flow
+        })
+    };
+    let (mut reports, mut connections): (Vec<_>, Vec<_>) = collector
+        .targets
+        .into_iter()
+        .map(|target| (target.report, target.journal.conn))
+        .unzip();
+
+    if options.on_error == OnError::Stop {
+        if let Some((path, e)) = reports.iter().find_map(|report| report.failed.first()) {
+            let processed: usize = reports.iter().map(|report| report.processed.len()).sum();
+            return Err(format!(
+                "Stopped after error processing {:?}: {} ({} files copied before the error were not saved to the database)",
+                path, e, processed
+            )
+            .into());
+        }
+    }
+
+    let summary = match scanned {
+        Ok(summary) => summary,
+        // 何も処理していなければ中断、処理済みのファイルがあればDBには保存する
+        Err(e) if reports.iter().all(|report| report.processed.is_empty()) => {
+            return Err(format!("Could not scan source directory {:?}: {}", source, e).into());
+        }
+        Err(e) => {
+            error!(
+                "Error scanning source directory {:?}: {} (scan stopped early)",
+                source, e
+            );
+            scanner::ScanSummary::default()
+        }
+    };
+
+    let mut all_saved = true;
+    for ((catalog, report), conn) in catalogs.iter().zip(&mut reports).zip(&mut connections) {
+        report.filtered_by_since = summary.filtered_by_since;
+        report.skipped = summary.skipped.clone();
+        if catalogs.len() > 1 {
+            info!("\nCatalog at {:?}:", catalog.data_path);
+        }
+        match &options.geocoder {
+            Some(geocoder) => add_location_names(report, geocoder.as_ref()),
+            None => add_location_names(report, geocoding::from_config(catalog.geocoding.as_ref()).as_ref()),
+        }
+        all_saved &= summarize_and_save(conn, report, source, &options)?;
+    }
+
+    // 移動モード: 全カタログのDBに記録できた場合のみソースを削除する
+    if options.move_files {
+        if !all_saved {
+            warn!("Warning: source files were not removed because the database save failed.");
+            return Ok(reports);
+        }
+        let (moved, not_removed) = remove_sources(&reports, options.force_remove_read_only);
+        info!("Removed {} source files.", moved);
+        let read_only = not_removed.iter().filter(|(_, e)| is_read_only_error(e)).count();
+        if read_only > 0 {
+            let hint = if options.force_remove_read_only {
+                "Unlock the card's write-protect switch or check the permissions"
+            } else {
+                "Unlock the card or re-run with --force to clear read-only attributes"
+            };
+            warn!(
+                "Warning: {} source files were left in place because the source is read-only (the copies succeeded). {}.",
+                read_only, hint
+            );
+        }
+        let mut pruned_dirs = 0;
+        if options.prune_empty_dirs {
+            match scanner::prune_empty_dirs(source, &options.scan.exclude_dirs) {
+                Ok(pruned) => {
+                    pruned_dirs = pruned;
+                    info!("Pruned {} empty directories from {:?}.", pruned, source);
+                }
+                Err(e) => error!("Error pruning empty directories in {:?}: {}", source, e),
+            }
+        }
+        for report in &mut reports {
+            report.moved = moved;
+            report.pruned_dirs = pruned_dirs;
+            report.not_removed = not_removed
+                .iter()
+                .map(|(path, e)| (path.clone(), io::Error::new(e.kind(), e.to_string())))
+                .collect();
+        }
+    }
+
+    Ok(reports)
+}
+
+/// カタログのDBを開き、`skip_existing` 用の登録済みパスと中断したインポートを確認する
+///
+/// 中断したインポートを再開しない限り、レコードには `session_id` を記録する。
+/// `resume` で再開する場合、中断したセッションで処理済みのファイルを
+/// `options.process.reuse_copies` に加える (コピー先のサイズを確かめてから使うので、
+/// 複数カタログの分をまとめても他のカタログのコピーを誤って使うことはない)。
+fn open_target(
+    source: &Path,
+    catalog: &Catalog,
+    session_id: &str,
+    options: &mut ImportOptions,
+) -> Result<Target, Box<dyn Error>> {
+    // 処理中もジャーナルに書き込むため、DBはスキャンの前に開く
+    let db_path = catalog.database_path();
+    let conn = database::open_database(&db_path, catalog.busy_timeout())
+        .map_err(|e| format!("Could not open database to {:?}: {}", db_path, e))?;
+    database::create_tables(&conn).map_err(|e| format!("Could not create database tables: {}", e))?;
+
+    // 登録済みのパスを先に読み込んでおく
+    let existing = if options.skip_existing {
+        database::imported_original_paths(&conn)
+            .map_err(|e| format!("Could not read imported paths from database: {}", e))?
+    } else {
+        HashSet::new()
+    };
+
+    // 同じソースからのインポートが中断していれば、再開するか案内する
+    let mut session_id = session_id.to_string();
+    match database::unfinished_import(&conn, source) {
+        Ok(Some((previous, completed))) if options.resume => {
+            info!(
+                "Resuming interrupted import session {} ({} files were already processed).",
+                previous,
+                completed.len()
+            );
+            session_id = previous;
+            options.process.reuse_copies.extend(completed);
+        }
+        Ok(Some((previous, completed))) => warn!(
+            "Warning: import session {} from {:?} was interrupted after {} files. Re-run with --resume to reuse the files it already copied.",
+            previous,
+            source,
+            completed.len()
+        ),
+        Ok(None) if options.resume => info!("No interrupted import of {:?} to resume.", source),
+        Ok(None) => {}
+        Err(e) => warn!("Warning: could not read the import journal: {}", e),
+    }
+
+    Ok(Target {
+        report: ImportReport {
+            session_id,
+            ..ImportReport::default()
+        },
+        existing,
+        journal: Journal {
+            conn,
+            source: source.to_path_buf(),
+            failed: false,
+        },
+    })
+}
+
+/// GPS 座標のあるファイルの地名を引いて `location_name` に設定する (DB保存の前に呼ぶ)
+fn add_location_names(report: &mut ImportReport, geocoder: &dyn Geocoder) {
+    let mut located = 0;
+    for info in &mut report.processed {
+        let metadata = &mut info.metadata;
+        if let (Some(latitude), Some(longitude)) = (metadata.gps_latitude, metadata.gps_longitude) {
+            metadata.location_name = geocoder.place_name(latitude, longitude);
+            if metadata.location_name.is_some() {
+                located += 1;
+            }
+        }
+    }
+    if located > 0 {
+        info!("Found place names for {} files.", located);
+    }
+}
+
+/// 1カタログ分の処理結果を表示し、DBに保存する (保存に成功したか、保存するものがなければ `true`)
+fn summarize_and_save(
+    conn: &mut rusqlite::Connection,
+    report: &mut ImportReport,
+    source: &Path,
+    options: &ImportOptions,
+) -> Result<bool, Box<dyn Error>> {
+    info!(
+        "Found {} files to process.",
+        report.processed.len() + report.failed.len()
+    );
+    if options.scan.since.is_some() {
+        info!(
+            "Skipped {} files modified before --since.",
+            report.filtered_by_since
+        );
+    }
+    if options.skip_existing {
+        info!(
+            "Skipped {} files already in the catalog.",
+            report.skipped_existing
+        );
+    }
+    if !report.skipped.is_empty() {
+        info!("Skipped {} empty, unreadable or non-UTF-8 named files.", report.skipped.len());
+    }
+
+    if report.processed.is_empty() && report.failed.is_empty() {
+        if report.skipped_existing > 0 {
+            info!("No new files to import.");
+        } else {
+            info!("No files found in the source directory.");
+        }
+        return Ok(true);
+    }
+
+    info!(
+        "\nProcessing complete. {} files processed successfully, {} errors, {} skipped.",
+        report.processed.len(),
+        report.failed.len(),
+        report.skipped.len()
+    );
+    if !report.thumbnail_failed.is_empty() {
+        warn!(
+            "Warning: {} files were imported without a thumbnail because of errors:",
+            report.thumbnail_failed.len()
+        );
+        for (path, e) in &report.thumbnail_failed {
+            warn!("  {:?}: {}", path, e);
+        }
+    }
+
+    if report.processed.is_empty() {
+        return Ok(true);
+    }
+
+    // データベースへの保存
+    let saved = database::save_all_processed_info(
+        conn,
+        &report.processed,
+        &report.session_id,
+        options.strict_db,
+        options.db_chunk_size,
+    );
+    let saved = match saved {
+        Ok(failed) => {
+            report.db_failed = failed;
+            true
+        }
+        // strictモードではロールバック済みなのでエラーとして返す
+        // それ以外は警告に留め、処理は完了とする
+        Err(e) if options.strict_db => {
+            return Err(format!("Could not save data to database: {}", e).into());
+        }
+        Err(e) => {
+            error!("Error saving data to database: {}", e);
+            false
+        }
+    };
+    info!("Import session ID: {}", report.session_id);
+
+    // DBに保存できたので、中断時の再開用の記録はもう要らない
+    if saved {
+        if let Err(e) = database::clear_journal(conn, source) {
+            warn!("Warning: could not clear the import journal: {}", e);
+        }
+    }
+    Ok(saved)
+}
+
+/// `--rename-pattern` の `{seq}` に入れる番号を、スキャンした順に元ファイルごとに振る
+///
+/// 全カタログで同じ番号を使い、Live Photo の静止画と動画には同じ番号を振る (組の名前を揃える)。
+/// 登録済みで飛ばすファイルにも番号を振るので、同じソースなら `--resume` や `--skip-existing` でも
+/// 前回と同じ名前になる。
+#[derive(Default)]
+struct SequenceNumbers {
+    last: u64,
+    /// 片方だけ見つけた Live Photo の組の番号 (組の識別子ごと)
+    pending_pairs: HashMap<String, u64>,
+}
+
+impl SequenceNumbers {
+    fn assign(&mut self, file_info: &FileInfo) -> u64 {
+        let Some(pair) = &file_info.live_photo else {
+            self.last += 1;
+            return self.last;
+        };
+        let group_key = pair.group_key();
+        if let Some(sequence) = self.pending_pairs.remove(&group_key) {
+            return sequence;
+        }
+        self.last += 1;
+        self.pending_pairs.insert(group_key, self.last);
+        self.last
+    }
+}
+
+/// `options` に従ってソースをスキャンし、見つけたファイルを順に `on_file` に渡す
+///
+/// `count_first` の場合と並べ替える場合は先に全体をスキャンし、処理するファイルの総数を
+/// `collector` に設定してから渡す (途中でエラーになった場合は何も渡さない)。
+fn scan_source<'p, F>(
+    source: &Path,
+    options: &ImportOptions,
+    collector: &mut Collector<'p>,
+    mut on_file: F,
+) -> io::Result<scanner::ScanSummary>
+where
+    F: FnMut(&mut Collector<'p>, FileInfo) -> ControlFlow<()>,
+{
+    if !options.count_first && options.scan.sort_by == SortBy::Path {
+        return scanner::scan_directory_each(source, &options.scan, |file_info| on_file(collector, file_info));
+    }
+
+    let mut files = Vec::new();
+    let summary = scanner::scan_directory_each(source, &options.scan, |file_info| {
+        files.push(file_info);
+        ControlFlow::Continue(())
+    })?;
+    let total = files.iter().filter(|file_info| collector.is_wanted(&file_info.path)).count();
+    collector.total = FileTotal::Exact(total);
+    for file_info in files {
+        if on_file(collector, file_info).is_break() {
+            break;
+        }
+    }
+    Ok(summary)
+}
+
+/// 進捗通知に入れるファイルの総数
+#[derive(Debug, Clone, Copy)]
+enum FileTotal {
+    Unknown,
+    /// 先にスキャンして数えた、処理するファイルの数
+    Exact(usize),
+    /// 事前に数えたソースのファイル数 (登録済みで飛ばすファイルも含む)
+    Expected(usize),
+}
+
+/// インポート先のカタログ1つ分の状態
+struct Target {
+    report: ImportReport,
+    /// `skip_existing` で飛ばす、DBに登録済みの元ファイルパス
+    existing: HashSet<PathBuf>,
+    /// 処理を終えたファイルの記録先
+    journal: Journal,
+}
+
+/// 処理結果をカタログごとの [`ImportReport`] に記録し、進捗コールバックに通知する
+struct Collector<'p> {
+    targets: Vec<Target>,
+    progress: Option<&'p mut dyn FnMut(ImportProgress<'_>)>,
+    on_error: OnError,
+    total: FileTotal,
+    /// 全カタログで登録済みのため飛ばしたファイル数 (`FileTotal::Expected` から除く)
+    skipped_everywhere: usize,
+}
+
+/// 中断したインポートを再開するためのジャーナル (DBの import_journal テーブル)
+struct Journal {
+    conn: rusqlite::Connection,
+    source: PathBuf,
+    /// 書き込みに失敗したら、警告を1回だけ出して以降は記録しない
+    failed: bool,
+}
+
+impl Journal {
+    fn record(&mut self, session_id: &str, path: &Path) {
+        if self.failed {
+            return;
+        }
+        if let Err(e) = database::journal_completed(&self.conn, session_id, &self.source, path) {
+            warn!(
+                "Warning: could not write the import journal ({}); an interrupted import cannot be resumed.",
+                e
+            );
+            self.failed = true;
+        }
+    }
+}
+
+impl Collector<'_> {
+    fn notify(&mut self, index: usize, catalog: usize, path: &Path, stage: ImportStage<'_>) {
+        let total = match self.total {
+            FileTotal::Unknown => None,
+            FileTotal::Exact(total) => Some(total),
+            // ファイルが増えていても、何番目かが総数を超えないようにする
+            FileTotal::Expected(files) => Some(files.saturating_sub(self.skipped_everywhere).max(index + 1)),
+        };
+        if let Some(callback) = self.progress.as_mut() {
+            callback(ImportProgress {
+                index,
+                total,
+                catalog,
+                path,
+                stage,
+            });
+        }
+    }
+
+    /// ファイルを処理するカタログの番号を返す
+    /// (DBに登録済みで飛ばすカタログは、飛ばした数を数えて除く)
+    fn wanted(&mut self, path: &Path) -> Vec<usize> {
+        let mut wanted = Vec::with_capacity(self.targets.len());
+        for (index, target) in self.targets.iter_mut().enumerate() {
+            if target.existing.contains(path) {
+                verbose!("Skipping already imported file: {:?}", path);
+                target.report.skipped_existing += 1;
+            } else {
+                wanted.push(index);
+            }
+        }
+        if wanted.is_empty() {
+            self.skipped_everywhere += 1;
+        }
+        wanted
+    }
+
+    /// どれかのカタログで処理するファイルか (`wanted` と違って飛ばした数は数えない)
+    fn is_wanted(&self, path: &Path) -> bool {
+        self.targets.iter().any(|target| !target.existing.contains(path))
+    }
+
+    fn started(&mut self, index: usize, path: &Path) {
+        self.notify(index, 0, path, ImportStage::Started);
+    }
+
+    /// 1ファイルの処理結果を記録する (`OnError::Stop` でエラーなら `Break`)
+    fn finished(
+        &mut self,
+        catalog: usize,
+        index: usize,
+        path: PathBuf,
+        result: ProcessorResult<ProcessedInfo>,
+    ) -> ControlFlow<()> {
+        match result {
+            Ok(mut info) => {
+                info!("Successfully processed: {:?}", info.original_path);
+                self.notify(index, catalog, &path, ImportStage::Processed(&info));
+                let target = &mut self.targets[catalog];
+                if let Some(e) = info.thumbnail_error.take() {
+                    warn!("Warning: imported {:?} without a thumbnail: {}", path, e);
+                    target.report.thumbnail_failed.push((path, e));
+                }
+                target.journal.record(&target.report.session_id, &info.original_path);
+                target.report.processed.push(info);
+                ControlFlow::Continue(())
+            }
+            Err(e) => {
+                error!("Error processing file {:?}: {}", path, e);
+                self.notify(index, catalog, &path, ImportStage::Failed(&e));
+                self.targets[catalog].report.failed.push((path, e));
+                match self.on_error {
+                    // エラーが発生しても処理は続ける
+                    OnError::Continue => ControlFlow::Continue(()),
+                    OnError::Stop => ControlFlow::Break(()),
+                }
+            }
+        }
+    }
+}
+
+/// スキャンしたファイルを `options.jobs` 個のワーカースレッドで並行して処理する
+///
+/// スキャンと結果の記録 (進捗通知を含む) は呼び出し元のスレッドで行う。
+/// 1つのファイルの全カタログ分は同じワーカーが続けて処理する (元ファイルの読み込みがキャッシュに乗りやすい)。
+/// RAW などの重いデコードの同時実行数は `ProcessOptions::decode_limiter`、ファイルをメモリに
+/// 読み込む処理の同時実行数は `ProcessOptions::in_flight_limiter` で別に制限される。
+fn scan_and_process_parallel(
+    source: &Path,
+    catalogs: &[&Catalog],
+    options: &ImportOptions,
+    collector: &mut Collector<'_>,
+) -> io::Result<scanner::ScanSummary> {
+    // スキャンが処理より大きく先行しないよう、待ち行列は小さく保つ
+    let (task_tx, task_rx) = mpsc::sync_channel::<(usize, u64, FileInfo, Vec<usize>)>(options.jobs * 2);
+    let task_rx = Mutex::new(task_rx);
+    let (result_tx, result_rx) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..options.jobs {
+            let task_rx = &task_rx;
+            let result_tx = result_tx.clone();
+            scope.spawn(move || loop {
+                let task = task_rx.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                let Ok((index, sequence, file_info, wanted)) = task else {
+                    break;
+                };
+                for target in wanted {
+                    let result =
+                        processor::process_file(&file_info, catalogs[target], &options.process, sequence);
+                    if result_tx.send((target, index, file_info.path.clone(), result)).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        drop(result_tx);
+
+        let mut index = 0;
+        // {seq} はワーカーの処理順ではなく、ここでスキャンした順に振る
+        let mut sequences = SequenceNumbers::default();
+        let mut flow = ControlFlow::Continue(());
+        let scanned = scan_source(source, options, collector, |collector, file_info| {
+            // 終わった分の結果を先に記録する
+            for (target, done, path, result) in result_rx.try_iter() {
+                if collector.finished(target, done, path, result).is_break() {
+                    flow = ControlFlow::Break(());
+                }
+            }
+            if flow.is_break() {
+                return flow;
+            }
+            let sequence = sequences.assign(&file_info);
+            let wanted = collector.wanted(&file_info.path);
+            if wanted.is_empty() {
+                return ControlFlow::Continue(());
+            }
+            collector.started(index, &file_info.path);
+            // 受け手のワーカーがいなくなるのはパニックした場合のみ (scope の終了時に伝播する)
+            let _ = task_tx.send((index, sequence, file_info, wanted));
+            index += 1;
+            ControlFlow::Continue(())
+        });
+
+        // 新しいタスクの投入を止め、処理中のファイルの結果を待つ
+        drop(task_tx);
+        for (target, index, path, result) in result_rx {
+            let _ = collector.finished(target, index, path, result);
+        }
+        scanned
+    })
+}
+
+/// 全カタログにコピーしてDBに保存できたソースファイルを削除し、削除した数と削除できなかったファイルを返す
+/// (どれかのカタログでソースが保存先そのものだった場合と、DBに保存できなかった場合は残す)
+fn remove_sources(reports: &[ImportReport], force_read_only: bool) -> (usize, Vec<(PathBuf, io::Error)>) {
+    let removable: Vec<HashSet<&Path>> = reports
+        .iter()
+        .map(|report| {
+            let db_failed: HashSet<&Path> =
+                report.db_failed.iter().map(|(path, _)| path.as_path()).collect();
+            report
+                .processed
+                .iter()
+                .filter(|info| {
+                    !db_failed.contains(info.original_path.as_path())
+                        && !processor::is_same_file(&info.original_path, &info.data_dest_path)
+                })
+                .map(|info| info.original_path.as_path())
+                .collect()
+        })
+        .collect();
+
+    let mut removed = 0;
+    let mut not_removed = Vec::new();
+    for info in &reports[0].processed {
+        let path = info.original_path.as_path();
+        if !removable.iter().all(|paths| paths.contains(path)) {
+            continue;
+        }
+        let mut result = fs::remove_file(path);
+        if force_read_only && result.as_ref().is_err_and(is_read_only_error) {
+            result = clear_read_only(path).and_then(|()| fs::remove_file(path));
+        }
+        match result {
+            Ok(()) => {
+                verbose!("Removed source file: {:?}", path);
+                removed += 1;
+            }
+            Err(e) if is_read_only_error(&e) => {
+                error!(
+                    "Could not remove source file {:?}: source is read-only, cannot remove; copy succeeded ({})",
+                    path, e
+                );
+                not_removed.push((path.to_path_buf(), e));
+            }
+            Err(e) => {
+                error!("Could not remove source file {:?}: {} (copy succeeded)", path, e);
+                not_removed.push((path.to_path_buf(), e));
+            }
+        }
+    }
+    (removed, not_removed)
+}
+
+/// 書き込み禁止のメディア、読み取り専用のファイルシステムや属性による削除の失敗か
+fn is_read_only_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem
+    )
+}
+
+/// ファイルの読み取り専用属性を外す
+fn clear_read_only(path: &Path) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    #[allow(clippy::permissions_set_readonly_false)] // 削除する直前のファイルなので誰でも書き込めても問題ない
+    permissions.set_readonly(false);
+    fs::set_permissions(path, permissions)
+}
@@ -1,132 +1,1508 @@
-use clap::Parser;
-use std::path::PathBuf;
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+use chrono_tz::Tz;
+use clap::{ArgGroup, CommandFactory, Parser, Subcommand};
+use rusqlite::Connection;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
 use std::process; // For exiting the program
+use std::time::{Duration, SystemTime};
 
-mod config; // configモジュールを宣言
-mod database; // databaseモジュールを宣言
-mod processor; // processorモジュールを宣言
-mod scanner; // scannerモジュールを宣言
+use casket::{config, database, error, info, output, processor, scanner, verbose, warn, ImportOptions, LinkMode, OnError, ProcessOptions, ScanOptions, SortBy};
 
 /// カメラデータをカタログにインポートするアプリケーション
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(subcommand_negates_reqs = true)]
+#[command(group(ArgGroup::new("force_targets").args(["embed_thumbnail", "move_files"]).multiple(true)))]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     /// インポート元のディレクトリパス
-    #[arg(short, long, value_name = "SOURCE_DIR")]
-    source: PathBuf,
+    #[arg(short, long, value_name = "SOURCE_DIR", required = true)]
+    source: Option<PathBuf>,
+
+    /// 使用するカタログ名 (カタログが1つだけ定義されている場合は省略可)。
+    /// カンマ区切りか複数回の指定で、1回のスキャンから複数のカタログにインポートする
+    #[arg(short, long, value_name = "CATALOG_NAME", value_delimiter = ',')]
+    catalog_name: Vec<String>, // 変数名を変更 catalog -> catalog_name
+
+    /// 設定ファイルを使わず、このディレクトリにデータを保存する (--thumbnail-path と併用)
+    #[arg(long, value_name = "DIR", requires = "thumbnail_path", conflicts_with = "catalog_name")]
+    data_path: Option<PathBuf>,
+
+    /// 設定ファイルを使わず、このディレクトリにサムネイルとDBを保存する (--data-path と併用)
+    #[arg(long, value_name = "DIR", requires = "data_path", conflicts_with = "catalog_name")]
+    thumbnail_path: Option<PathBuf>,
+
+    /// カタログのデータ/サムネイル保存先が存在しない場合に作成する
+    #[arg(long)]
+    create_dirs: bool,
+
+    /// この日付 (YYYY-MM-DD) 以降、または期間 (例: 7d, 12h) 以内に更新されたファイルのみ取り込む
+    #[arg(long, value_name = "DATE_OR_DURATION", value_parser = parse_since)]
+    since: Option<SystemTime>,
+
+    /// このバイト数未満のファイルを壊れたファイルとしてスキップする
+    #[arg(long, value_name = "BYTES", default_value_t = 1)]
+    min_size: u64,
+
+    /// シンボリックリンク先のディレクトリも再帰的にスキャンする
+    #[arg(long)]
+    follow_symlinks: bool,
+
+    /// サブディレクトリをたどる深さの上限 (0 はソースディレクトリ直下のファイルのみ)
+    #[arg(long, value_name = "N")]
+    max_depth: Option<usize>,
+
+    /// サブディレクトリに入らず、ソースディレクトリ直下のファイルだけをインポートする (`--max-depth 0` と同じ)
+    #[arg(long, conflicts_with = "max_depth")]
+    no_recursive: bool,
+
+    /// `.` で始まる隠しファイル・ディレクトリもインポート対象にする (`._` のリソースフォークは常に除外)
+    #[arg(long)]
+    include_hidden: bool,
+
+    /// ファイルを処理する順序: path (パス順) / name (ファイル名順) / mtime (更新日時の古い順)。
+    /// name と mtime はスキャンが終わってから処理を始める
+    #[arg(long, value_name = "ORDER", default_value_t = SortBy::Path)]
+    sort_by: SortBy,
+
+    /// DB保存で1件でもエラーがあればバッチ全体をロールバックする
+    #[arg(long)]
+    strict_db: bool,
+
+    /// DB保存で1トランザクションにまとめる件数 (0 で全件を1トランザクション)
+    #[arg(long, value_name = "N", default_value_t = database::DEFAULT_DB_CHUNK_SIZE)]
+    db_chunk_size: usize,
+
+    /// インポートしたファイルをソースから削除する (DB保存に成功した場合のみ)
+    #[arg(long = "move")]
+    move_files: bool,
+
+    /// --move の後、ソース配下で空になったディレクトリを削除する (ソースディレクトリ自体は残す)
+    #[arg(long, requires = "move_files")]
+    prune_empty_dirs: bool,
+
+    /// 並行して処理するファイル数 (1 で逐次処理。コピーは I/O 待ちが多いのでコア数より多くてもよい)
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    jobs: usize,
+
+    /// RAW/HEIC/動画のデコードを同時に行う上限 (省略時は CPU の論理コア数、0 で無制限)。--jobs とは別に CPU 負荷を抑える
+    #[arg(long, value_name = "N")]
+    workers_raw: Option<usize>,
+
+    /// ファイルを読み込んでデコード・サムネイル生成する処理を同時に行う上限 (0 で無制限)。
+    /// --jobs を大きくしてもメモリ使用量を抑えられる
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    max_in_flight: usize,
+
+    /// ファイル処理でエラーが起きたときの方針: continue (記録して続行) / stop (最初のエラーで中断し、DBに保存しない)
+    #[arg(long, value_name = "POLICY", default_value_t = OnError::Continue)]
+    on_error: OnError,
+
+    /// コピー後にチェックサムでコピー先を検証する (読み込みI/Oが倍になる)
+    #[arg(long)]
+    verify: bool,
+
+    /// カタログへの置き方: copy (コピー) / hard (ハードリンク、失敗したらコピー) /
+    /// auto (元ファイルと保存先が同じデバイスならハードリンク、違えばコピー)
+    #[arg(long, value_name = "MODE", default_value_t = LinkMode::Copy)]
+    link: LinkMode,
+
+    /// --verify でハッシュしながらコピーするときの読み書きの単位 (例: 4M、256K。ハッシュしないコピーには影響しない)
+    #[arg(long, value_name = "SIZE", value_parser = parse_copy_buffer_size)]
+    copy_buffer_size: Option<usize>,
+
+    /// 保存先のファイル名テンプレート (例: "{date}_{orig}", "{yyyy}{mm}{dd}_{hhmmss}_{seq}")
+    /// 使えるトークン: {date} {time} {hhmmss} {yyyy} {mm} {dd} {hh} {orig} {ext} {seq}
+    #[arg(long, value_name = "PATTERN", value_parser = parse_rename_pattern)]
+    rename_pattern: Option<String>,
+
+    /// オフセットの記録がない EXIF の撮影日時を解釈するタイムゾーン (IANA 名、例: Asia/Tokyo)。
+    /// 省略時はシステムのローカルタイムゾーン。オフセットが記録された日時には影響しない
+    #[arg(long, value_name = "TZ", value_parser = parse_timezone)]
+    timezone: Option<Tz>,
+
+    /// 一時的なI/Oエラー (タイムアウトなど) でコピーに失敗した場合のリトライ回数
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    retries: u32,
+
+    /// コピー先の JPEG に小さな EXIF サムネイル (160px) を埋め込む (RAW/動画は対象外)
+    #[arg(long)]
+    embed_thumbnail: bool,
+
+    /// --embed-thumbnail では、既に EXIF サムネイルがあるファイルも置き換える。
+    /// --move では、読み取り専用で削除できないソースファイルの読み取り専用属性を外して削除する
+    #[arg(long, requires = "force_targets")]
+    force: bool,
+
+    /// コピー先の JPEG を EXIF の向き (Orientation) どおりに回転して保存し直し、Orientation を 1 にする。
+    /// コピーした元画像を再エンコードする (他の EXIF は保持、RAW/動画は対象外)
+    #[arg(long)]
+    normalize_orientation: bool,
+
+    /// ソースより新しいサムネイルが既にあっても作り直す
+    #[arg(long)]
+    overwrite_thumbnails: bool,
+
+    /// サムネイルを作らずにコピーとメタデータの記録だけ行う (後から reindex-thumbnails で作れる)
+    #[arg(long, conflicts_with = "overwrite_thumbnails")]
+    no_thumbnails: bool,
+
+    /// サムネイルを作れなかったファイル (デコードの失敗や動画など) に、拡張子を描いた代替画像をサムネイルとして保存する
+    #[arg(long, conflicts_with = "no_thumbnails")]
+    placeholder_thumbnails: bool,
+
+    /// カタログのDBに登録済みのファイル (元ファイルのパスで判定) は処理せずに飛ばす
+    #[arg(long)]
+    skip_existing: bool,
+
+    /// 同じソースからの中断したインポートを再開する (セッションを引き継ぎ、コピー済みのファイルはコピーし直さない)
+    #[arg(long)]
+    resume: bool,
+
+    /// カタログに geocoding の設定があっても、GPS 座標から地名を引かない (オフラインでのインポート用)
+    #[arg(long)]
+    no_geocoding: bool,
+
+    /// インポート結果 (件数、ファイルごとの結果、エラー) を JSON でカタログの
+    /// thumbnail_path/imports/<セッションID>.json に書き出す
+    #[arg(long)]
+    report: bool,
+
+    /// コピーやDB保存をせず、取り込む対象のファイルの種類 (image/raw/heic/video/unknown) と
+    /// EXIF の撮影日時を一覧表示する
+    #[arg(long)]
+    scan_only: bool,
+
+    /// 取り込むファイルが多くても確認せずにインポートする (端末がない環境で上限を超える場合は必須)
+    #[arg(short, long)]
+    yes: bool,
+
+    /// 確認なしでインポートするファイル数の上限 (超えると件数と合計サイズを表示して確認する)
+    #[arg(long, value_name = "N", default_value_t = 1000)]
+    max_auto: usize,
+
+    /// 確認なしでインポートする合計サイズの上限 (例: 10G)
+    #[arg(long, value_name = "SIZE", default_value = "10G", value_parser = parse_size)]
+    max_auto_size: u64,
+
+    /// --skip-existing を無効にして全ファイルを処理し直す (DBの重複行は作らない)。
+    /// --overwrite-thumbnails と組み合わせるとサムネイルを作り直せる
+    #[arg(long)]
+    force_rescan: bool,
+
+    /// サムネイルのJPEGクオリティ (1-100、カタログ設定の thumbnail_quality より優先)
+    #[arg(long, value_name = "QUALITY", global = true, value_parser = parse_thumbnail_quality)]
+    thumbnail_quality: Option<u8>,
+
+    /// サムネイル縮小の画質: fast (既定) / good (Catmull-Rom) / best (Lanczos3、最も遅い)。カタログ設定の thumbnail_filter より優先
+    #[arg(long, value_name = "FILTER", global = true, value_parser = parse_thumbnail_filter)]
+    thumbnail_filter: Option<config::ThumbnailFilter>,
+
+    /// サムネイルを thumbnail_path 直下ではなくこのサブディレクトリ以下に保存する (DBは thumbnail_path 直下のまま)。
+    /// カタログ設定の thumbnail_subdir より優先
+    #[arg(long, value_name = "DIR", global = true, value_parser = parse_thumbnail_subdir)]
+    thumbnail_subdir: Option<PathBuf>,
+
+    /// libraw での RAW 現像の制限時間 (秒、0 で無制限)。超えたら埋め込みプレビューにフォールバックする
+    #[arg(long, value_name = "SECONDS", global = true, default_value_t = 30)]
+    raw_timeout: u64,
+
+    /// RAW は libraw で現像する前に埋め込みプレビューを試す (高速、見つからなければ現像する)
+    #[arg(long, global = true)]
+    prefer_embedded_preview: bool,
+
+    /// サムネイル用にデコードする画像の画素数 (幅×高さ) の上限 (0 で無制限)。
+    /// 壊れたヘッダーで巨大な寸法を示すファイルは、メモリを確保する前にエラーにしてサムネイルなしで取り込む
+    #[arg(long, value_name = "PIXELS", global = true, default_value_t = processor::DEFAULT_MAX_PIXELS)]
+    thumbnail_max_pixels: u64,
+
+    /// 設定ファイルのパス (省略時は環境変数 CASKET_CONFIG、それもなければ既定の catalogs.toml)
+    #[arg(long, value_name = "CONFIG_FILE", global = true)]
+    config: Option<PathBuf>,
+
+    /// エラー以外の出力を抑制する
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// デバッグ用の詳細な出力を表示する
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// 出力をタイムスタンプ付きで指定ファイルにも追記する
+    #[arg(long, value_name = "PATH", global = true)]
+    log_file: Option<PathBuf>,
+}
+
+/// インポート以外の補助コマンド
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// 設定ファイルに定義されたカタログの一覧を表示する
+    ListCatalogs {
+        /// カタログ名だけを1行に1つずつ出力する (シェル補完用)
+        #[arg(long, hide = true)]
+        names_only: bool,
+    },
+
+    /// 設定ファイルを検証する (構文エラー、カタログの保存先、data_path と thumbnail_path の重複)
+    CheckConfig,
+
+    /// カタログ内のデータファイルからサムネイルを再生成する
+    ReindexThumbnails {
+        /// 対象のカタログ名 (カタログが1つだけ定義されている場合は省略可)
+        #[arg(short, long, value_name = "CATALOG_NAME")]
+        catalog_name: Option<String>,
+    },
+
+    /// カタログの件数、カメラモデル別件数、撮影期間などを表示する
+    Stats {
+        /// 対象のカタログ名 (カタログが1つだけ定義されている場合は省略可)
+        #[arg(short, long, value_name = "CATALOG_NAME")]
+        catalog_name: Option<String>,
+    },
+
+    /// カタログの全レコードを CSV で書き出す (表計算ソフトでの分析用)
+    ExportCsv {
+        /// 対象のカタログ名 (カタログが1つだけ定義されている場合は省略可)
+        #[arg(short, long, value_name = "CATALOG_NAME")]
+        catalog_name: Option<String>,
+
+        /// 書き出し先のファイル ("-" で標準出力)
+        #[arg(short, long, value_name = "PATH")]
+        output: PathBuf,
+    },
+
+    /// 最後のインポートで登録したレコードを取り消す (--confirm がなければ対象の表示のみ)
+    UndoLastImport {
+        /// 対象のカタログ名 (カタログが1つだけ定義されている場合は省略可)
+        #[arg(short, long, value_name = "CATALOG_NAME")]
+        catalog_name: Option<String>,
+
+        /// コピーしたデータファイルとサムネイルも削除する
+        #[arg(long)]
+        remove_files: bool,
+
+        /// 実際に削除を実行する
+        #[arg(long)]
+        confirm: bool,
+    },
+
+    /// DBに登録されたデータファイルとサムネイルが存在するか確認する
+    VerifyCatalog {
+        /// 対象のカタログ名 (カタログが1つだけ定義されている場合は省略可)
+        #[arg(short, long, value_name = "CATALOG_NAME")]
+        catalog_name: Option<String>,
+
+        /// データファイルが見つからないレコードを削除する
+        #[arg(long)]
+        prune: bool,
+    },
+
+    /// 条件 (撮影日の範囲、カメラモデル) に合うレコードにまとめてタグを付ける (--list で付いたレコードを表示)
+    Tag {
+        /// 対象のカタログ名 (カタログが1つだけ定義されている場合は省略可)
+        #[arg(short, long, value_name = "CATALOG_NAME")]
+        catalog_name: Option<String>,
+
+        /// タグの名前 (大文字小文字は区別しない)
+        name: String,
+
+        /// この日以降に撮影したレコード (YYYY-MM-DD)
+        #[arg(long, value_name = "DATE", value_parser = parse_date)]
+        from: Option<NaiveDate>,
+
+        /// この日までに撮影したレコード (YYYY-MM-DD、この日を含む)
+        #[arg(long, value_name = "DATE", value_parser = parse_date)]
+        to: Option<NaiveDate>,
+
+        /// このカメラモデルのレコード (stats の表示と同じ名前、大文字小文字は区別しない)
+        #[arg(long, value_name = "MODEL")]
+        camera: Option<String>,
+
+        /// タグを付けずに、このタグが付いたレコードを表示する
+        #[arg(long, conflicts_with_all = ["from", "to", "camera"])]
+        list: bool,
+    },
+
+    /// シェル補完スクリプトを標準出力に書き出す (例: casket completions zsh > ~/.zfunc/_casket)
+    ///
+    /// 生成されるのはフラグとサブコマンドの補完のみ。--catalog-name の候補に設定済みの
+    /// カタログ名を出すには `casket list-catalogs --names-only` を補完関数から呼ぶ。
+    #[command(hide = true)]
+    Completions {
+        /// 対象のシェル (bash, zsh, fish, elvish, powershell)
+        shell: clap_complete::Shell,
+    },
+}
+
+/// `--raw-timeout` (0 は無制限) と `--prefer-embedded-preview` から RAW デコードの設定を作る
+fn raw_options(cli: &Cli) -> processor::RawOptions {
+    processor::RawOptions {
+        timeout: (cli.raw_timeout > 0).then(|| Duration::from_secs(cli.raw_timeout)),
+        prefer_embedded_preview: cli.prefer_embedded_preview,
+        max_pixels: cli.thumbnail_max_pixels,
+    }
+}
+
+/// `--thumbnail-quality` の値を解析する (1-100 以外はエラー)
+fn parse_thumbnail_quality(value: &str) -> Result<u8, String> {
+    let quality: u8 = value
+        .parse()
+        .map_err(|_| format!("thumbnail quality must be between 1 and 100, got '{}'", value))?;
+    config::validate_thumbnail_quality(quality)
+}
+
+/// --thumbnail-subdir の値が thumbnail_path の内側を指す相対パスか検証する
+fn parse_thumbnail_subdir(value: &str) -> Result<PathBuf, String> {
+    let subdir = PathBuf::from(value);
+    config::validate_thumbnail_subdir(&subdir)?;
+    Ok(subdir)
+}
+
+/// `--thumbnail-filter` の値を解析する
+fn parse_thumbnail_filter(value: &str) -> Result<config::ThumbnailFilter, String> {
+    config::ThumbnailFilter::try_from(value.to_string())
+}
+
+/// バイト数を解析する (K/M/G/T の接尾辞は 1024 単位)
+fn parse_size(value: &str) -> Result<u64, String> {
+    let upper = value.to_ascii_uppercase();
+    let (amount, shift) = match upper.char_indices().last() {
+        Some((i, 'K')) => (&upper[..i], 10),
+        Some((i, 'M')) => (&upper[..i], 20),
+        Some((i, 'G')) => (&upper[..i], 30),
+        Some((i, 'T')) => (&upper[..i], 40),
+        _ => (upper.as_str(), 0),
+    };
+    amount
+        .parse::<u64>()
+        .ok()
+        .and_then(|amount| amount.checked_mul(1 << shift))
+        .ok_or_else(|| format!("expected a size like 65536, 256K, 4M or 10G, got '{}'", value))
+}
+
+/// `--copy-buffer-size` の値を解析する
+fn parse_copy_buffer_size(value: &str) -> Result<usize, String> {
+    let size = parse_size(value)?;
+    if !(4096..=256 * 1024 * 1024).contains(&size) {
+        return Err(format!("copy buffer size must be between 4K and 256M, got '{}'", value));
+    }
+    Ok(size as usize)
+}
+
+/// `--rename-pattern` のテンプレートを検証する
+fn parse_rename_pattern(value: &str) -> Result<String, String> {
+    processor::RenamePattern::parse(value)?;
+    Ok(value.to_string())
+}
+
+/// `--timezone` の IANA タイムゾーン名を解析する
+fn parse_timezone(value: &str) -> Result<Tz, String> {
+    value
+        .parse::<Tz>()
+        .map_err(|_| format!("unknown time zone {:?} (expected an IANA name such as Asia/Tokyo)", value))
+}
 
-    /// 使用するカタログ名
-    #[arg(short, long, value_name = "CATALOG_NAME")]
-    catalog_name: String, // 変数名を変更 catalog -> catalog_name
+/// `tag` の `--from` / `--to` の日付 (YYYY-MM-DD) を解析する
+fn parse_date(value: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| format!("invalid date {:?} (expected YYYY-MM-DD)", value))
+}
+
+/// `--since` の値を解析する (YYYY-MM-DD はローカル時刻の0時、期間は現在からの相対)
+fn parse_since(value: &str) -> Result<SystemTime, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        let midnight = date.and_hms_opt(0, 0, 0).expect("midnight is always valid");
+        return Local
+            .from_local_datetime(&midnight)
+            .earliest()
+            .map(SystemTime::from)
+            .ok_or_else(|| format!("'{}' is not a valid local date", value));
+    }
+
+    // 数値 + 単位 (s, m, h, d, w)
+    let split_at = value.char_indices().last().map_or(0, |(i, _)| i);
+    let (amount, unit) = value.split_at(split_at);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("expected YYYY-MM-DD or a duration like 7d, got '{}'", value))?;
+    let unit_seconds: u64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        _ => return Err(format!("unknown duration unit '{}' (use s, m, h, d or w)", unit)),
+    };
+    amount
+        .checked_mul(unit_seconds)
+        .and_then(|seconds| SystemTime::now().checked_sub(Duration::from_secs(seconds)))
+        .ok_or_else(|| format!("duration '{}' is too large", value))
 }
 
 fn main() {
     let cli = Cli::parse();
 
-    println!("Source directory: {:?}", cli.source);
-    println!("Catalog name: {}", cli.catalog_name);
+    output::set_verbosity(if cli.quiet {
+        output::Verbosity::Quiet
+    } else if cli.verbose {
+        output::Verbosity::Verbose
+    } else {
+        output::Verbosity::Normal
+    });
+
+    if let Some(log_file) = &cli.log_file {
+        if let Err(e) = output::set_log_file(log_file) {
+            error!("Error opening log file {:?}: {}", log_file, e);
+            std::process::exit(1);
+        }
+    }
 
-    // カタログ設定の読み込み
-    let config = match config::load_config() {
+    let config_path = cli.config.as_deref();
+    match &cli.command {
+        Some(Commands::ListCatalogs { names_only: true }) => list_catalog_names(config_path),
+        Some(Commands::ListCatalogs { names_only: false }) => list_catalogs(config_path),
+        Some(Commands::Completions { shell }) => {
+            clap_complete::generate(*shell, &mut Cli::command(), "casket", &mut io::stdout());
+        }
+        Some(Commands::CheckConfig) => check_config(config_path),
+        Some(Commands::ReindexThumbnails { catalog_name }) => reindex_thumbnails(
+            config_path,
+            catalog_name.as_deref(),
+            cli.thumbnail_quality,
+            cli.thumbnail_filter,
+            cli.thumbnail_subdir.as_deref(),
+            raw_options(&cli),
+        ),
+        Some(Commands::Stats { catalog_name }) => show_stats(config_path, catalog_name.as_deref()),
+        Some(Commands::ExportCsv {
+            catalog_name,
+            output,
+        }) => export_csv(config_path, catalog_name.as_deref(), output),
+        Some(Commands::UndoLastImport {
+            catalog_name,
+            remove_files,
+            confirm,
+        }) => undo_last_import(config_path, catalog_name.as_deref(), *remove_files, *confirm),
+        Some(Commands::VerifyCatalog {
+            catalog_name,
+            prune,
+        }) => verify_catalog(config_path, catalog_name.as_deref(), *prune),
+        Some(Commands::Tag {
+            catalog_name,
+            name,
+            from,
+            to,
+            camera,
+            list,
+        }) => {
+            let filter = database::ItemFilter {
+                from: *from,
+                to: *to,
+                camera_model: camera.clone(),
+            };
+            tag_items(config_path, catalog_name.as_deref(), name, &filter, *list)
+        }
+        None => run_import(&cli),
+    }
+}
+
+/// カタログ名だけを名前順に出力する (補完スクリプトから呼ばれるので他の出力は抑える)
+fn list_catalog_names(config_path: Option<&Path>) {
+    output::set_verbosity(output::Verbosity::Quiet);
+    let Ok(config) = config::load_config(config_path) else {
+        process::exit(1);
+    };
+    let mut names: Vec<&String> = config.catalogs.keys().collect();
+    names.sort();
+    for name in names {
+        println!("{}", name);
+    }
+}
+
+/// カタログ一覧を表形式で表示する
+fn list_catalogs(config_path: Option<&Path>) {
+    match global_config_path(config_path) {
+        Ok(path) => println!("Config file: {:?}", path),
+        Err(e) => println!("Config file: (unresolved: {})", e),
+    }
+    if let Some(local_path) = std::env::current_dir()
+        .ok()
+        .and_then(|cwd| config::find_local_config(&cwd))
+    {
+        println!("Local overrides: {:?}", local_path);
+    }
+
+    let config = match config::load_config(config_path) {
         Ok(cfg) => cfg,
         Err(e) => {
-            eprintln!("Error loading configuration: {}", e);
+            error!("Error loading configuration: {}", e);
             process::exit(1);
         }
     };
 
-    // 指定されたカタログを取得
-    let catalog = match config.catalogs.get(&cli.catalog_name) {
-        Some(cat) => cat,
+    if config.catalogs.is_empty() {
+        println!("No catalogs are defined.");
+        return;
+    }
+
+    // 名前順に並べて列幅を揃える
+    let mut names: Vec<&String> = config.catalogs.keys().collect();
+    names.sort();
+
+    let name_width = names.iter().map(|n| n.len()).max().unwrap_or(0).max("NAME".len());
+    let data_width = config
+        .catalogs
+        .values()
+        .map(|c| c.data_path.display().to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max("DATA PATH".len());
+
+    println!();
+    println!(
+        "{:<name_width$}  {:<data_width$}  THUMBNAIL PATH",
+        "NAME", "DATA PATH"
+    );
+    for name in names {
+        let catalog = &config.catalogs[name];
+        println!(
+            "{:<name_width$}  {:<data_width$}  {}",
+            name,
+            catalog.data_path.display().to_string(),
+            catalog.thumbnail_path.display()
+        );
+    }
+}
+
+/// グローバル設定ファイルのパス (`--config` 指定があればそれ、なければ `CASKET_CONFIG` か既定のパス)
+fn global_config_path(config_path: Option<&Path>) -> io::Result<PathBuf> {
+    match config_path {
+        Some(path) => Ok(path.to_path_buf()),
+        None => config::default_config_path(),
+    }
+}
+
+/// 設定ファイルを検証し、問題があれば終了コード 1 で終了する
+fn check_config(config_path: Option<&Path>) {
+    let mut problems = 0;
+
+    // 読み込み対象のファイル (グローバル設定 → ローカル設定)
+    let mut files = Vec::new();
+    match global_config_path(config_path) {
+        Ok(path) if path.exists() => files.push(path),
+        Ok(path) if config_path.is_some() || config::env_config_path().is_some() => {
+            error!("Error: config file {:?} not found", path);
+            problems += 1;
+        }
+        Ok(path) => println!("Global config {:?} does not exist (skipped)", path),
+        Err(e) => {
+            error!("Error: could not resolve the config path: {}", e);
+            problems += 1;
+        }
+    }
+    if let Some(local_path) = std::env::current_dir()
+        .ok()
+        .and_then(|cwd| config::find_local_config(&cwd))
+    {
+        files.push(local_path);
+    }
+
+    // 各ファイルを個別に解析する (TOML の構文エラーは行・列付きで表示される)
+    for path in &files {
+        match config::load_config_from_path(path) {
+            Ok(config) => println!("OK: {:?} ({} catalogs)", path, config.catalogs.len()),
+            Err(e) => {
+                error!("Error in {:?}:\n{}", path, e);
+                problems += 1;
+            }
+        }
+    }
+
+    if problems > 0 {
+        error!("\n{} problem(s) found.", problems);
+        process::exit(1);
+    }
+
+    let config = match config::load_config(config_path) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            error!("Error loading configuration: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut names: Vec<&String> = config.catalogs.keys().collect();
+    names.sort();
+    for name in names {
+        let catalog = &config.catalogs[name];
+        println!("\nCatalog '{}':", name);
+
+        for (label, path) in [
+            ("data_path", &catalog.data_path),
+            ("thumbnail_path", &catalog.thumbnail_path),
+        ] {
+            match config::ensure_writable_dir(path, false) {
+                Ok(()) => println!("  {}: {:?} (ok)", label, path),
+                Err(e) => {
+                    error!("  {}: {:?} is not usable: {}", label, path, e);
+                    problems += 1;
+                }
+            }
+        }
+
+        // 同じディレクトリだとオリジナルとサムネイルが混ざる
+        let same_dir = match (
+            fs::canonicalize(&catalog.data_path),
+            fs::canonicalize(&catalog.thumbnail_path),
+        ) {
+            (Ok(data), Ok(thumbnail)) => data == thumbnail,
+            _ => catalog.data_path == catalog.thumbnail_path,
+        };
+        if same_dir {
+            warn!("  Warning: data_path and thumbnail_path are the same directory; originals and thumbnails would be mixed");
+            problems += 1;
+        }
+    }
+
+    if problems > 0 {
+        error!("\n{} problem(s) found.", problems);
+        process::exit(1);
+    }
+    println!("\nConfiguration looks good.");
+}
+
+/// 設定を読み込み、使用するカタログを決定する (見つからなければ終了)
+/// 名前が省略された場合、定義されているカタログが1つだけならそれを使う
+fn load_catalog(config_path: Option<&Path>, catalog_name: Option<&str>) -> (String, config::Catalog) {
+    let mut config = match config::load_config(config_path) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            error!("Error loading configuration: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let name = match catalog_name {
+        Some(name) => name.to_string(),
+        None if config.catalogs.len() == 1 => {
+            let name = config.catalogs.keys().next().cloned().unwrap_or_default();
+            info!("No --catalog-name given, using the only configured catalog '{}'.", name);
+            name
+        }
         None => {
-            eprintln!("Error: Catalog '{}' not found in configuration.", cli.catalog_name);
-            eprintln!("Available catalogs: {:?}", config.catalogs.keys());
+            error!(
+                "Error: --catalog-name is required when {} catalogs are configured.",
+                config.catalogs.len()
+            );
+            error!("Available catalogs: {:?}", config.catalogs.keys());
+            process::exit(1);
+        }
+    };
+
+    take_catalog(&mut config, &name)
+}
+
+/// 設定からカタログを取り出す (名前は大文字小文字を区別しない)
+///
+/// 見つからなければ編集距離の近い名前を提案し、定義されているカタログを表示して終了する。
+fn take_catalog(config: &mut config::Config, name: &str) -> (String, config::Catalog) {
+    let Some(found) = config.find_catalog_name(name).map(str::to_string) else {
+        error!("Error: Catalog '{}' not found in configuration.", name);
+        if let Some(suggestion) = config.closest_catalog_name(name) {
+            error!("Did you mean '{}'?", suggestion);
+        }
+        error!("Available catalogs: {:?}", config.catalogs.keys());
+        process::exit(1);
+    };
+    if found != name {
+        info!("Using catalog '{}' for '{}'.", found, name);
+    }
+    let catalog = config.catalogs.remove(&found).expect("name was found in the config");
+    (found, catalog)
+}
+
+/// インポート対象のファイルの種類と EXIF の撮影日時を一覧表示する (コピー、サムネイル生成、DB操作はしない)
+/// 拡張子の追加設定は最初のカタログのものを使う
+fn scan_only(
+    source: &Path,
+    catalogs: &[(String, config::Catalog)],
+    mut scan: ScanOptions,
+    timezone: Option<Tz>,
+) {
+    // インポート時と同じく、カタログの保存先はスキャン対象から外す
+    for (_, catalog) in catalogs {
+        scan.exclude_dirs
+            .extend([catalog.data_path.clone(), catalog.thumbnail_path.clone()]);
+    }
+    let extensions = &catalogs[0].1.extensions;
+
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    let mut undated = 0;
+    println!("{:<8}  {:<25}  PATH", "TYPE", "DATE");
+    let scanned = scanner::scan_directory_each(source, &scan, |file_info| {
+        let preview = processor::preview_file(&file_info.path, extensions, timezone);
+        let date = match preview.datetime_original {
+            Some(dt) => dt.format("%Y-%m-%d %H:%M:%S%:z").to_string(),
+            None => {
+                undated += 1;
+                "-".to_string()
+            }
+        };
+        println!("{:<8}  {:<25}  {}", preview.category, date, file_info.path.display());
+        match counts.iter_mut().find(|(category, _)| *category == preview.category) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((preview.category, 1)),
+        }
+        ControlFlow::Continue(())
+    });
+    let summary = match scanned {
+        Ok(summary) => summary,
+        Err(e) => {
+            error!("Error scanning source directory {:?}: {}", source, e);
             process::exit(1);
         }
     };
 
-    println!("Using catalog '{}':", cli.catalog_name);
-    println!("  Data path: {:?}", catalog.data_path);
-    println!("  Thumbnail path: {:?}", catalog.thumbnail_path);
+    let by_category: Vec<String> = counts
+        .iter()
+        .map(|(category, count)| format!("{} {}", count, category))
+        .collect();
+    info!(
+        "\n{} files ({}), {} without an EXIF date.",
+        summary.found,
+        if by_category.is_empty() { "none".to_string() } else { by_category.join(", ") },
+        undated
+    );
+    if scan.since.is_some() {
+        info!("Skipped {} files modified before --since.", summary.filtered_by_since);
+    }
+    if !summary.skipped.is_empty() {
+        info!("Skipped {} empty, unreadable or non-UTF-8 named files.", summary.skipped.len());
+    }
+}
+
+/// ソースを数え、ファイル数か合計サイズが上限を超えていれば続けるか確認する (中止なら終了)
+///
+/// `--source` にホームディレクトリなどを指定してしまった場合に、大量のコピーが始まるのを防ぐ。
+/// 端末から実行していなければ確認できないため、`--yes` なしでは中止する。
+/// 数えたファイル数を返す (数えられなかった場合は None)。
+fn confirm_large_import(
+    source: &Path,
+    catalogs: &[(String, config::Catalog)],
+    scan: &ScanOptions,
+    max_files: usize,
+    max_bytes: u64,
+) -> Option<usize> {
+    // インポート時と同じく、カタログの保存先はスキャン対象から外す
+    let mut scan = scan.clone();
+    for (_, catalog) in catalogs {
+        scan.exclude_dirs
+            .extend([catalog.data_path.clone(), catalog.thumbnail_path.clone()]);
+    }
+
+    verbose!("Counting files in {:?}...", source);
+    let mut files = 0;
+    let mut bytes = 0;
+    let scanned = scanner::scan_directory_each(source, &scan, |file_info| {
+        files += 1;
+        bytes += fs::metadata(&file_info.path).map_or(0, |meta| meta.len());
+        ControlFlow::Continue(())
+    });
+    if let Err(e) = scanned {
+        // スキャンのエラーはインポート本体でも報告されるので、ここでは確認を省く
+        verbose!("Could not count files in {:?}: {}", source, e);
+        return None;
+    }
+    if files <= max_files && bytes <= max_bytes {
+        return Some(files);
+    }
+
+    let summary = format!("{} files ({}) in {:?}", files, format_size(bytes), source);
+    if !io::stdin().is_terminal() {
+        error!(
+            "Error: {} exceed the --max-auto limits ({} files, {}). Re-run with --yes to import them.",
+            summary,
+            max_files,
+            format_size(max_bytes)
+        );
+        process::exit(1);
+    }
+
+    // --quiet でも確認は必要なので、出力の詳細度によらず表示する
+    eprint!("About to import {}. Continue? [y/N] ", summary);
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err()
+        || !matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+    {
+        error!("Import cancelled.");
+        process::exit(1);
+    }
+    Some(files)
+}
 
-    // ソースディレクトリのスキャン
-    println!("\nScanning source directory...");
-    let files_to_process = match scanner::scan_directory(&cli.source) {
-        Ok(files) => {
-            println!("Found {} files to process.", files.len());
-            files
+/// バイト数を読みやすい単位で表示する (例: 1.5 GiB)
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next in UNITS {
+        if size < 1024.0 {
+            break;
         }
+        size /= 1024.0;
+        unit = next;
+    }
+    format!("{:.1} {}", size, unit)
+}
+
+/// インポート結果を JSON でカタログの thumbnail_path/imports/<セッションID>.json に書き出す
+fn write_import_report(
+    source: &Path,
+    catalog_name: &str,
+    catalog: &config::Catalog,
+    report: &casket::ImportReport,
+    started_at: DateTime<Local>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let path_str = |path: &Path| path.to_string_lossy().into_owned();
+
+    let db_failed: HashSet<&Path> = report.db_failed.iter().map(|(path, _)| path.as_path()).collect();
+    let thumbnail_failed: HashMap<&Path, String> = report
+        .thumbnail_failed
+        .iter()
+        .map(|(path, e)| (path.as_path(), e.to_string()))
+        .collect();
+    let mut files = Vec::new();
+    for info in &report.processed {
+        let status = if db_failed.contains(info.original_path.as_path()) { "db_failed" } else { "imported" };
+        files.push(json!({
+            "path": path_str(&info.original_path),
+            "status": status,
+            "data_path": path_str(&info.data_dest_path),
+            "thumbnail_path": info.thumbnail_dest_path.as_deref().map(path_str),
+            "thumbnail_error": thumbnail_failed.get(info.original_path.as_path()),
+            "content_hash": info.content_hash,
+        }));
+    }
+    for (path, e) in &report.failed {
+        files.push(json!({ "path": path_str(path), "status": "failed", "error": e.to_string() }));
+    }
+    for path in &report.skipped {
+        files.push(json!({ "path": path_str(path), "status": "skipped" }));
+    }
+
+    let errors: Vec<_> = report
+        .failed
+        .iter()
+        .map(|(path, e)| ("process", path, e.to_string()))
+        .chain(report.thumbnail_failed.iter().map(|(path, e)| ("thumbnail", path, e.to_string())))
+        .chain(report.db_failed.iter().map(|(path, e)| ("database", path, e.to_string())))
+        .chain(report.not_removed.iter().map(|(path, e)| ("remove_source", path, e.to_string())))
+        .map(|(stage, path, message)| json!({ "stage": stage, "path": path_str(path), "error": message }))
+        .collect();
+
+    let document = json!({
+        "session_id": report.session_id,
+        "catalog": catalog_name,
+        "source": path_str(source),
+        "started_at": started_at.to_rfc3339(),
+        "finished_at": Local::now().to_rfc3339(),
+        "counts": {
+            "processed": report.processed.len() - report.db_failed.len(),
+            "failed": report.failed.len(),
+            "thumbnail_failed": report.thumbnail_failed.len(),
+            "db_failed": report.db_failed.len(),
+            "skipped": report.skipped.len(),
+            "skipped_existing": report.skipped_existing,
+            "filtered_by_since": report.filtered_by_since,
+            "moved": report.moved,
+            "not_removed": report.not_removed.len(),
+            "pruned_dirs": report.pruned_dirs,
+        },
+        "files": files,
+        "errors": errors,
+    });
+
+    let dir = catalog.thumbnail_path.join("imports");
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("{}.json", report.session_id));
+    fs::write(&path, serde_json::to_string_pretty(&document)?)?;
+    Ok(path)
+}
+
+/// インポート先のカタログを読み込む (名前が1つ以下なら `load_catalog` と同じ、見つからなければ終了)
+fn load_catalogs(config_path: Option<&Path>, catalog_names: &[String]) -> Vec<(String, config::Catalog)> {
+    if catalog_names.len() <= 1 {
+        return vec![load_catalog(config_path, catalog_names.first().map(String::as_str))];
+    }
+
+    let mut config = match config::load_config(config_path) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            error!("Error loading configuration: {}", e);
+            process::exit(1);
+        }
+    };
+    let mut catalogs: Vec<(String, config::Catalog)> = Vec::with_capacity(catalog_names.len());
+    for name in catalog_names {
+        // 大文字小文字だけ違う指定も同じカタログとして扱う (取り出し済みなので設定には残っていない)
+        if catalogs.iter().any(|(loaded, _)| loaded.eq_ignore_ascii_case(name))
+            && config.find_catalog_name(name).is_none()
+        {
+            error!("Error: Catalog '{}' was given more than once.", name);
+            process::exit(1);
+        }
+        catalogs.push(take_catalog(&mut config, name));
+    }
+    catalogs
+}
+
+/// カタログのデータベースを開き、テーブルを準備する (失敗したら終了)
+fn open_catalog_database(catalog: &config::Catalog) -> Connection {
+    let db_path = catalog.database_path();
+    let conn = match database::open_database(&db_path, catalog.busy_timeout()) {
+        Ok(conn) => conn,
         Err(e) => {
-            eprintln!("Error scanning source directory {:?}: {}", cli.source, e);
+            error!("Error opening database connection to {:?}: {}", db_path, e);
             process::exit(1);
         }
     };
+    if let Err(e) = database::create_tables(&conn) {
+        error!("Error creating database tables: {}", e);
+        process::exit(1);
+    }
+    conn
+}
 
-    if files_to_process.is_empty() {
-        println!("No files found in the source directory. Exiting.");
-        process::exit(0);
+/// カタログ内のデータファイルからサムネイルを再生成する
+fn reindex_thumbnails(
+    config_path: Option<&Path>,
+    catalog_name: Option<&str>,
+    thumbnail_quality: Option<u8>,
+    thumbnail_filter: Option<config::ThumbnailFilter>,
+    thumbnail_subdir: Option<&Path>,
+    raw: processor::RawOptions,
+) {
+    let (catalog_name, mut catalog) = load_catalog(config_path, catalog_name);
+    if thumbnail_quality.is_some() {
+        catalog.thumbnail_quality = thumbnail_quality;
     }
+    if thumbnail_filter.is_some() {
+        catalog.thumbnail_filter = thumbnail_filter;
+    }
+    if let Some(subdir) = thumbnail_subdir {
+        catalog.thumbnail_subdir = Some(subdir.to_path_buf());
+    }
+    let conn = open_catalog_database(&catalog);
+
+    let items = match database::thumbnail_sources(&conn) {
+        Ok(items) => items,
+        Err(e) => {
+            error!("Error reading media items: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let total = items.len();
+    info!("Regenerating thumbnails for {} items in catalog '{}'...", total, catalog_name);
 
-    // ファイル処理（コピー、サムネイル生成、メタデータ抽出）
-    println!("\nProcessing files...");
-    let mut processed_results = Vec::new();
-    let mut error_count = 0;
+    let mut regenerated = 0;
+    let mut missing = 0;
+    let mut skipped = 0;
+    let mut failed = 0;
 
-    for file_info in files_to_process {
-        match processor::process_file(&file_info, catalog) {
-            Ok(info) => {
-                println!("Successfully processed: {:?}", info.original_path);
-                processed_results.push(info);
+    for (index, item) in items.iter().enumerate() {
+        info!("[{}/{}] {:?}", index + 1, total, item.data_path);
+
+        if !item.data_path.is_file() {
+            error!("  Data file is missing, skipping: {:?}", item.data_path);
+            missing += 1;
+            continue;
+        }
+        if catalog.skips_thumbnail(&item.data_path) {
+            info!("  Thumbnail disabled for this file type by skip_thumbnails, skipping");
+            skipped += 1;
+            continue;
+        }
+
+        match processor::regenerate_thumbnail(&item.data_path, &catalog, raw) {
+            Ok(processor::ThumbnailOutcome {
+                path: Some(path),
+                source_dimensions,
+                thumbnail_dimensions,
+                ..
+            }) => {
+                if let Err(e) = database::update_thumbnail(
+                    &conn,
+                    item.id,
+                    &path,
+                    source_dimensions,
+                    thumbnail_dimensions,
+                ) {
+                    error!("  Error updating thumbnail path for {:?}: {}", item.data_path, e);
+                    failed += 1;
+                } else {
+                    regenerated += 1;
+                }
+            }
+            Ok(processor::ThumbnailOutcome { error: Some(e), .. }) => {
+                error!("  Error regenerating thumbnail for {:?}: {}", item.data_path, e);
+                failed += 1;
+            }
+            Ok(_) => {
+                error!("  Could not generate thumbnail for {:?}", item.data_path);
+                failed += 1;
             }
             Err(e) => {
-                eprintln!("Error processing file {:?}: {}", file_info.path, e);
-                error_count += 1;
-                // エラーが発生しても処理を続けるか、停止するか？ ここでは続ける
+                error!("  Error regenerating thumbnail for {:?}: {}", item.data_path, e);
+                failed += 1;
             }
         }
     }
 
+    info!(
+        "\nReindex complete. {} regenerated, {} missing data files, {} skipped by skip_thumbnails, {} failed.",
+        regenerated, missing, skipped, failed
+    );
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+/// カタログの集計結果を表示する
+fn show_stats(config_path: Option<&Path>, catalog_name: Option<&str>) {
+    let (catalog_name, catalog) = load_catalog(config_path, catalog_name);
+    let conn = open_catalog_database(&catalog);
+
+    let stats = match database::catalog_stats(&conn) {
+        Ok(stats) => stats,
+        Err(e) => {
+            error!("Error collecting catalog stats: {}", e);
+            process::exit(1);
+        }
+    };
+
+    println!("Catalog: {}", catalog_name);
+    println!("Total items: {}", stats.total_items);
+    println!(
+        "Earliest: {}",
+        stats.earliest_datetime.as_deref().unwrap_or("(unknown)")
+    );
     println!(
-        "\nProcessing complete. {} files processed successfully, {} errors.",
-        processed_results.len(),
-        error_count
+        "Latest: {}",
+        stats.latest_datetime.as_deref().unwrap_or("(unknown)")
     );
+    println!("Missing thumbnails: {}", stats.missing_thumbnails);
 
-    if error_count > 0 {
-        eprintln!("Please check the errors above.");
-        // エラーがあった場合に終了コードを変えることも検討
-        // process::exit(1);
+    if stats.items_by_camera_model.is_empty() {
+        return;
     }
 
-    if processed_results.is_empty() && error_count > 0 {
-         println!("No files were processed successfully.");
-         process::exit(1); // 成功したファイルがなければエラー終了
+    // 件数の桁数に合わせて右寄せする
+    let count_width = stats
+        .items_by_camera_model
+        .iter()
+        .map(|(_, count)| count.to_string().len())
+        .max()
+        .unwrap_or(0);
+
+    println!("\nItems by camera model:");
+    for (model, count) in &stats.items_by_camera_model {
+        println!(
+            "  {:>count_width$}  {}",
+            count,
+            model.as_deref().unwrap_or("(unknown)")
+        );
     }
+}
 
-    // データベースへの保存
-    let db_path = catalog.thumbnail_path.join("casket.db");
-    match database::open_database(&db_path) {
-        Ok(mut conn) => { // Make conn mutable
-            if let Err(e) = database::create_tables(&conn) { // create_tables still takes &conn
-                eprintln!("Error creating database tables: {}", e);
-                // テーブル作成エラーは致命的かもしれないので終了する
+/// カタログの全レコードを CSV で書き出す (カラムは media_items の全カラム、NULL は空欄)
+fn export_csv(config_path: Option<&Path>, catalog_name: Option<&str>, output: &Path) {
+    let to_stdout = output == Path::new("-");
+    // 標準出力に書き出す場合は、CSV にメッセージが混ざらないようにする
+    if to_stdout {
+        output::set_verbosity(output::Verbosity::Quiet);
+    }
+    let (catalog_name, catalog) = load_catalog(config_path, catalog_name);
+    let conn = open_catalog_database(&catalog);
+
+    let writer: Box<dyn io::Write> = if to_stdout {
+        Box::new(io::stdout().lock())
+    } else {
+        match fs::File::create(output) {
+            Ok(file) => Box::new(io::BufWriter::new(file)),
+            Err(e) => {
+                error!("Error creating {:?}: {}", output, e);
                 process::exit(1);
             }
+        }
+    };
+    // csv::Writer はカンマや改行、引用符を含むフィールドを自動で引用符で囲む
+    let mut csv = csv::Writer::from_writer(writer);
+
+    let exported = database::export_media_items(&conn, |row| -> Result<(), Box<dyn std::error::Error>> {
+        csv.write_record(row)?;
+        Ok(())
+    })
+    .and_then(|rows| {
+        csv.flush()?;
+        Ok(rows)
+    });
+    match exported {
+        Ok(rows) => info!("Exported {} records from catalog '{}' to {:?}.", rows, catalog_name, output),
+        Err(e) => {
+            error!("Error exporting catalog '{}': {}", catalog_name, e);
+            process::exit(1);
+        }
+    }
+}
+
+/// 最後のインポートセッションのレコード (と必要ならファイル) を削除する
+fn undo_last_import(
+    config_path: Option<&Path>,
+    catalog_name: Option<&str>,
+    remove_files: bool,
+    confirm: bool,
+) {
+    let (catalog_name, catalog) = load_catalog(config_path, catalog_name);
+    let conn = open_catalog_database(&catalog);
+
+    let session_id = match database::latest_import_session(&conn) {
+        Ok(Some(id)) => id,
+        Ok(None) => {
+            println!("No import sessions found in catalog '{}'.", catalog_name);
+            return;
+        }
+        Err(e) => {
+            error!("Error finding the last import session: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let items = match database::session_items(&conn, &session_id) {
+        Ok(items) => items,
+        Err(e) => {
+            error!("Error reading media items for session {}: {}", session_id, e);
+            process::exit(1);
+        }
+    };
 
-            // Pass mutable reference to save_all_processed_info
-            if let Err(e) = database::save_all_processed_info(&mut conn, &processed_results) {
-                 eprintln!("Error saving data to database: {}", e);
-                 // 保存エラーは警告に留め、処理は完了とするか？
-                 // ここでは警告のみ表示
+    println!(
+        "Last import session in catalog '{}': {} ({} items)",
+        catalog_name,
+        session_id,
+        items.len()
+    );
+    for item in &items {
+        println!("  record #{}: {:?}", item.id, item.original_path);
+        if remove_files {
+            println!("    file: {:?}", item.data_path);
+            if let Some(thumbnail_path) = &item.thumbnail_path {
+                println!("    thumbnail: {:?}", thumbnail_path);
+            }
+        }
+    }
+
+    if !confirm {
+        println!("\nNothing was deleted. Re-run with --confirm to delete the items listed above.");
+        return;
+    }
+
+    let mut failed = 0;
+    if remove_files {
+        for item in &items {
+            let paths = std::iter::once(&item.data_path).chain(item.thumbnail_path.as_ref());
+            for path in paths {
+                match fs::remove_file(path) {
+                    Ok(()) => verbose!("Removed {:?}", path),
+                    // 既に消えているファイルは削除済みとみなす
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        verbose!("Already removed: {:?}", path)
+                    }
+                    Err(e) => {
+                        error!("Error removing {:?}: {}", path, e);
+                        failed += 1;
+                    }
+                }
             }
         }
+    }
+
+    match database::delete_session(&conn, &session_id) {
+        Ok(deleted) => info!("Deleted {} records from import session {}.", deleted, session_id),
         Err(e) => {
-            eprintln!("Error opening database connection to {:?}: {}", db_path, e);
-            // DB接続エラーは致命的かもしれないので終了する
+            error!("Error deleting records for session {}: {}", session_id, e);
+            process::exit(1);
+        }
+    }
+
+    if failed > 0 {
+        error!("{} files could not be removed.", failed);
+        process::exit(1);
+    }
+}
+
+/// DBのレコードが参照するデータファイル・サムネイルが存在するか確認する
+/// `prune` が true の場合、データファイルが見つからないレコードを削除する
+fn verify_catalog(config_path: Option<&Path>, catalog_name: Option<&str>, prune: bool) {
+    let (catalog_name, catalog) = load_catalog(config_path, catalog_name);
+    let mut conn = open_catalog_database(&catalog);
+
+    let items = match database::all_items(&conn) {
+        Ok(items) => items,
+        Err(e) => {
+            error!("Error reading media items: {}", e);
+            process::exit(1);
+        }
+    };
+    info!("Verifying {} records in catalog '{}'...", items.len(), catalog_name);
+
+    let mut orphaned = Vec::new();
+    let mut missing_thumbnails = 0;
+    for item in &items {
+        if !item.data_path.is_file() {
+            println!("  record #{}: data file missing: {:?}", item.id, item.data_path);
+            orphaned.push(item.id);
+            continue;
+        }
+        // データファイルが残っているレコードのみ (サムネイルは reindex-thumbnails で作り直せる)
+        if let Some(thumbnail_path) = &item.thumbnail_path {
+            if !thumbnail_path.is_file() {
+                println!("  record #{}: thumbnail missing: {:?}", item.id, thumbnail_path);
+                missing_thumbnails += 1;
+            }
+        }
+    }
+
+    println!(
+        "\n{} records checked: {} missing data files, {} missing thumbnails.",
+        items.len(),
+        orphaned.len(),
+        missing_thumbnails
+    );
+    if missing_thumbnails > 0 {
+        println!("Run reindex-thumbnails to regenerate missing thumbnails.");
+    }
+
+    if !orphaned.is_empty() {
+        if !prune {
+            println!("Re-run with --prune to delete the records whose data file is missing.");
+            process::exit(1);
+        }
+        match database::delete_items(&mut conn, &orphaned) {
+            Ok(deleted) => info!("Deleted {} orphaned records.", deleted),
+            Err(e) => {
+                error!("Error deleting orphaned records: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if missing_thumbnails > 0 {
+        process::exit(1);
+    }
+}
+
+/// 条件に合うレコードにタグを付ける (`list` が true ならタグの付いたレコードを表示する)
+///
+/// 条件なしで全レコードにタグを付けてしまわないよう、絞り込みの条件を1つ以上必須にしている。
+fn tag_items(
+    config_path: Option<&Path>,
+    catalog_name: Option<&str>,
+    name: &str,
+    filter: &database::ItemFilter,
+    list: bool,
+) {
+    let name = name.trim();
+    if name.is_empty() {
+        error!("Error: the tag name must not be empty.");
+        process::exit(1);
+    }
+    if !list && filter.from.is_none() && filter.to.is_none() && filter.camera_model.is_none() {
+        error!("Error: specify at least one of --from, --to or --camera (or --list to show tagged items).");
+        process::exit(1);
+    }
+    if let (Some(from), Some(to)) = (filter.from, filter.to) {
+        if from > to {
+            error!("Error: --from {} is after --to {}.", from, to);
             process::exit(1);
         }
     }
 
+    let (catalog_name, catalog) = load_catalog(config_path, catalog_name);
+    let conn = open_catalog_database(&catalog);
+
+    if list {
+        let items = match database::items_by_tag(&conn, name) {
+            Ok(items) => items,
+            Err(e) => {
+                error!("Error reading items tagged '{}': {}", name, e);
+                process::exit(1);
+            }
+        };
+        println!("Items tagged '{}' in catalog '{}': {}", name, catalog_name, items.len());
+        for item in &items {
+            println!("  record #{}: {:?}", item.id, item.data_path);
+        }
+        return;
+    }
+
+    let tagged = database::add_tag(&conn, name).and_then(|tag_id| database::tag_items(&conn, tag_id, filter));
+    match tagged {
+        Ok(tagged) => println!("Tagged {} items with '{}' in catalog '{}'.", tagged, name, catalog_name),
+        Err(e) => {
+            error!("Error tagging items with '{}': {}", name, e);
+            process::exit(1);
+        }
+    }
+}
+
+/// インポート処理本体 (スキャン → ファイル処理 → DB保存)
+fn run_import(cli: &Cli) {
+    // subcommand_negates_reqs により、サブコマンドがなければ必須
+    let Some(source) = &cli.source else {
+        unreachable!("clap enforces --source without a subcommand");
+    };
+
+    info!("Source directory: {:?}", source);
+
+    // カタログ設定の読み込み (保存先が直接指定されていれば設定ファイルは読まない)
+    let mut catalogs = match (&cli.data_path, &cli.thumbnail_path) {
+        (Some(data_path), Some(thumbnail_path)) => vec![(
+            "(ad-hoc)".to_string(),
+            config::Catalog::new(data_path.clone(), thumbnail_path.clone()),
+        )],
+        _ => load_catalogs(cli.config.as_deref(), &cli.catalog_name),
+    };
+    for (catalog_name, catalog) in &mut catalogs {
+        info!("Catalog name: {}", catalog_name);
+        if cli.thumbnail_quality.is_some() {
+            catalog.thumbnail_quality = cli.thumbnail_quality;
+        }
+        if cli.thumbnail_filter.is_some() {
+            catalog.thumbnail_filter = cli.thumbnail_filter;
+        }
+        if cli.thumbnail_subdir.is_some() {
+            catalog.thumbnail_subdir = cli.thumbnail_subdir.clone();
+        }
+
+        info!("Using catalog '{}':", catalog_name);
+        info!("  Data path: {:?}", catalog.data_path);
+        info!("  Thumbnail path: {:?}", catalog.thumbnail_dir());
+    }
+
+    let scan = ScanOptions {
+        since: cli.since,
+        min_size: cli.min_size,
+        follow_symlinks: cli.follow_symlinks,
+        max_depth: if cli.no_recursive { Some(0) } else { cli.max_depth },
+        include_hidden: cli.include_hidden,
+        sort_by: cli.sort_by,
+        ..ScanOptions::default()
+    };
+    if cli.scan_only {
+        scan_only(source, &catalogs, scan, cli.timezone);
+        return;
+    }
+    let expected_files = if cli.yes {
+        None
+    } else {
+        confirm_large_import(source, &catalogs, &scan, cli.max_auto, cli.max_auto_size)
+    };
+
+    let options = ImportOptions {
+        scan,
+        create_dirs: cli.create_dirs,
+        process: ProcessOptions {
+            verify: cli.verify,
+            retries: cli.retries,
+            copy_buffer_size: cli.copy_buffer_size.unwrap_or(processor::DEFAULT_COPY_BUFFER_SIZE),
+            link: cli.link,
+            timezone: cli.timezone,
+            // 空のテンプレートは元のファイル名のまま
+            rename: cli
+                .rename_pattern
+                .as_deref()
+                .filter(|pattern| !pattern.is_empty())
+                .map(|pattern| processor::RenamePattern::parse(pattern).expect("validated by clap")),
+            raw: raw_options(cli),
+            embed_thumbnail: cli.embed_thumbnail,
+            force_embed_thumbnail: cli.force,
+            normalize_orientation: cli.normalize_orientation,
+            overwrite_thumbnails: cli.overwrite_thumbnails,
+            no_thumbnails: cli.no_thumbnails,
+            placeholder_thumbnails: cli.placeholder_thumbnails,
+            // --resume で再開する場合は casket::import が中断したセッションの記録から設定する
+            reuse_copies: Default::default(),
+            decode_limiter: processor::ConcurrencyLimiter::new(cli.workers_raw.unwrap_or_else(|| {
+                std::thread::available_parallelism().map_or(1, |cores| cores.get())
+            })),
+            in_flight_limiter: processor::ConcurrencyLimiter::new(cli.max_in_flight),
+        },
+        strict_db: cli.strict_db,
+        db_chunk_size: cli.db_chunk_size,
+        on_error: cli.on_error,
+        jobs: cli.jobs,
+        move_files: cli.move_files,
+        prune_empty_dirs: cli.prune_empty_dirs,
+        force_remove_read_only: cli.move_files && cli.force,
+        // --force-rescan はこの実行だけ --skip-existing を打ち消す
+        skip_existing: cli.skip_existing && !cli.force_rescan,
+        resume: cli.resume,
+        geocoder: cli
+            .no_geocoding
+            .then(|| Box::new(casket::geocoding::NoGeocoder) as Box<dyn casket::Geocoder>),
+        count_first: false,
+        expected_files,
+    };
+
+    let started_at = Local::now();
+    let targets: Vec<&config::Catalog> = catalogs.iter().map(|(_, catalog)| catalog).collect();
+    let reports = match casket::import_many(source, &targets, options, None) {
+        Ok(reports) => reports,
+        Err(e) => {
+            error!("Error: {}", e);
+            let missing_dirs = targets
+                .iter()
+                .any(|catalog| !catalog.data_path.exists() || !catalog.thumbnail_path.exists());
+            if !cli.create_dirs && missing_dirs {
+                error!("Re-run with --create-dirs to create missing catalog directories.");
+            }
+            process::exit(1);
+        }
+    };
+
+    if cli.report {
+        for ((catalog_name, catalog), report) in catalogs.iter().zip(&reports) {
+            match write_import_report(source, catalog_name, catalog, report, started_at) {
+                Ok(path) => info!("Wrote import report to {:?}", path),
+                Err(e) => error!("Error writing the import report for catalog '{}': {}", catalog_name, e),
+            }
+        }
+    }
+
+    if reports
+        .iter()
+        .any(|report| !report.failed.is_empty() || !report.db_failed.is_empty())
+    {
+        error!("Please check the errors above.");
+        // エラーがあった場合に終了コードを変えることも検討
+        // process::exit(1);
+
+        if reports.iter().all(|report| report.processed.is_empty()) {
+            error!("No files were processed successfully.");
+            process::exit(1); // 成功したファイルがなければエラー終了
+        }
+    }
 
-    println!("\nAll tasks finished.");
+    info!("\nAll tasks finished.");
 }
@@ -0,0 +1,123 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+
+use chrono::Local;
+
+/// 出力の詳細度 (--quiet / --verbose で切り替え)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// エラー (error!) のみ
+    Quiet = 0,
+    /// 通常の進捗表示
+    Normal = 1,
+    /// デバッグ用の詳細表示も含める
+    Verbose = 2,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+/// プロセス全体の出力詳細度を設定する
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+/// 指定した詳細度の出力が有効かどうか
+pub fn enabled(level: Verbosity) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= level as u8
+}
+
+/// --log-file で指定されたログファイル (未指定なら None)
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// 出力をログファイルにも追記する (既存の内容は残す)
+pub fn set_log_file(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()) = Some(file);
+    Ok(())
+}
+
+/// メッセージの種類 (出力先と詳細度の判定に使う)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    /// エラー (stderr、--quiet でも出力)
+    Error,
+    /// 警告 (stderr、--quiet でも出力)
+    Warning,
+    /// 通常の情報出力 (stdout)
+    Info,
+    /// デバッグ用の詳細出力 (stdout)
+    Verbose,
+}
+
+impl MessageKind {
+    fn label(self) -> &'static str {
+        match self {
+            MessageKind::Error => "ERROR",
+            MessageKind::Warning => "WARN",
+            MessageKind::Info => "INFO",
+            MessageKind::Verbose => "DEBUG",
+        }
+    }
+}
+
+/// メッセージをコンソールとログファイルに出力する (info! などのマクロから呼ばれる)
+///
+/// ログファイルには --quiet に関係なく情報出力も書き込む。詳細出力は --verbose 指定時のみ。
+#[doc(hidden)]
+pub fn emit(kind: MessageKind, args: fmt::Arguments<'_>) {
+    match kind {
+        MessageKind::Error | MessageKind::Warning => eprintln!("{}", args),
+        MessageKind::Info if enabled(Verbosity::Normal) => println!("{}", args),
+        MessageKind::Verbose if enabled(Verbosity::Verbose) => println!("{}", args),
+        _ => {}
+    }
+    if kind == MessageKind::Verbose && !enabled(Verbosity::Verbose) {
+        return;
+    }
+
+    let mut guard = LOG_FILE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(file) = guard.as_mut() {
+        let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
+        let message = args.to_string();
+        // 書き込みに失敗しても本来の処理は止めない
+        for line in message.lines().filter(|line| !line.trim().is_empty()) {
+            let _ = writeln!(file, "{} [{}] {}", timestamp, kind.label(), line);
+        }
+    }
+}
+
+/// エラーの出力 (stderr、--quiet でも抑制されない)
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::output::emit($crate::output::MessageKind::Error, format_args!($($arg)*))
+    };
+}
+
+/// 警告の出力 (stderr、--quiet でも抑制されない)
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::output::emit($crate::output::MessageKind::Warning, format_args!($($arg)*))
+    };
+}
+
+/// 通常の情報出力 (--quiet で抑制される)
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::output::emit($crate::output::MessageKind::Info, format_args!($($arg)*))
+    };
+}
+
+/// デバッグ用の詳細出力 (--verbose 指定時のみ)
+#[macro_export]
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        $crate::output::emit($crate::output::MessageKind::Verbose, format_args!($($arg)*))
+    };
+}
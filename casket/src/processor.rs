@@ -1,17 +1,99 @@
-use crate::config::Catalog;
-use crate::scanner::FileInfo;
-use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use crate::config::{Catalog, MediaExtensions, RawConverter, RawRendering, ThumbnailFilter, ThumbnailFormat};
+use crate::scanner::{FileInfo, LivePhoto};
+use chrono::{DateTime, FixedOffset, Local, LocalResult, NaiveDateTime, Offset, TimeZone, Timelike};
+use chrono_tz::Tz;
 use exif;
-use image::{ImageFormat, DynamicImage, codecs::jpeg::JpegEncoder};
-use libraw::{Processor};
-use std::error::Error;
+use image::{ImageDecoder, ImageFormat, ImageReader, DynamicImage, codecs::jpeg::JpegEncoder};
+#[cfg(feature = "raw")]
+use libraw::Processor;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
 use std::fs::{self, File};
-use std::io::{self, BufReader, Read, Seek};
+use std::io::{self, BufReader, Cursor, Read, Seek, Write};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "raw")]
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
 
 // --- エラー型定義 ---
-type ProcessorResult<T> = Result<T, Box<dyn Error>>;
+
+/// ファイル処理のエラー
+///
+/// EXIF/XMP の読み込み失敗はメタデータが欠けるだけで処理は続けるため、エラーにはならない。
+#[derive(Debug, thiserror::Error)]
+pub enum ProcessorError {
+    /// ファイルの読み書きやディレクトリ作成の失敗
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    /// 特定のパスに対する読み書きやディレクトリ作成の失敗
+    #[error("I/O error on {path:?}: {source}")]
+    IoAt { path: PathBuf, source: io::Error },
+    /// カタログへのコピーの失敗 (コピー元とコピー先のどちらの問題かは `source` で判断する)
+    #[error("could not copy {source_path:?} to {dest_path:?}: {source}")]
+    Copy {
+        source_path: PathBuf,
+        dest_path: PathBuf,
+        source: io::Error,
+    },
+    /// 処理できないパス (ファイル名がない、カタログ外など)
+    #[error("invalid path {path:?}: {reason}")]
+    InvalidPath { path: PathBuf, reason: String },
+    /// コピー先のハッシュが元ファイルと一致しない (--verify)
+    #[error("checksum mismatch after copying {source_path:?} to {dest_path:?} (source {source_hash}, destination {dest_hash})")]
+    ChecksumMismatch {
+        source_path: PathBuf,
+        dest_path: PathBuf,
+        source_hash: String,
+        dest_hash: String,
+    },
+    /// この環境ではデコードできない形式
+    #[error("unsupported format: {0}")]
+    UnsupportedFormat(String),
+    /// RAW ファイルのデコード失敗
+    #[error("RAW decode failed: {0}")]
+    RawDecode(String),
+    /// libraw の処理が制限時間内に終わらず、フォールバックでもデコードできなかった
+    #[error("RAW decoding timed out after {0:?}")]
+    RawTimeout(Duration),
+    /// HEIC/HEIF ファイルのデコード失敗
+    #[error("HEIC decode failed: {0}")]
+    HeicDecode(String),
+    /// 画像のデコード失敗
+    #[error("image decode failed: {0}")]
+    ImageDecode(#[from] image::ImageError),
+    /// 画素数が上限 (`RawOptions::max_pixels`) を超えるためデコードしなかった (破損したヘッダーなど)
+    #[error("image is {width}x{height}, more than the limit of {max_pixels} pixels")]
+    TooManyPixels { width: u32, height: u32, max_pixels: u64 },
+    /// コピー先 JPEG への EXIF サムネイル埋め込みに失敗した
+    #[error("could not embed EXIF thumbnail: {0}")]
+    EmbedThumbnail(String),
+    /// コピー先 JPEG の向きの正規化に失敗した
+    #[error("could not normalize orientation: {0}")]
+    NormalizeOrientation(String),
+    /// サムネイルの書き出し失敗
+    #[error("could not write thumbnail {path:?}: {source}")]
+    ThumbnailWrite {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+}
+
+pub type ProcessorResult<T> = Result<T, ProcessorError>;
+
+/// I/O エラーに対象のパスを付けて [`ProcessorError::IoAt`] にする (`map_err` 用)
+fn io_at(path: &Path) -> impl FnOnce(io::Error) -> ProcessorError + '_ {
+    move |source| ProcessorError::IoAt {
+        path: path.to_path_buf(),
+        source,
+    }
+}
 
 // --- 処理結果の情報 ---
 #[derive(Debug)]
@@ -19,445 +101,2732 @@ pub struct ProcessedInfo {
     pub original_path: PathBuf,
     pub data_dest_path: PathBuf,
     pub thumbnail_dest_path: Option<PathBuf>,
+    /// サムネイルを作れなかった原因のうち報告するエラー (RAW デコードのタイムアウト)。
+    /// [`crate::ImportReport::thumbnail_failed`] に移してから返す
+    pub thumbnail_error: Option<ProcessorError>,
+    pub width: Option<u32>,  // 元画像の幅 (デコードできた場合)
+    pub height: Option<u32>, // 元画像の高さ (デコードできた場合)
     pub metadata: Metadata,
     pub datetime_indexed: String, // YYYYMMDDHH形式の絞り込み用日時
+    pub content_hash: Option<String>, // 検証済みSHA-256 (16進小文字、--verify 指定時のみ)
+    pub file_size: u64, // 元ファイルのバイト数
+    pub thumbnail_width: Option<u32>,  // 保存したサムネイルの幅 (サムネイルなしなら None)
+    pub thumbnail_height: Option<u32>, // 保存したサムネイルの高さ (サムネイルなしなら None)
+    pub live_photo_group: Option<String>, // Live Photo の静止画と動画で共通の識別子
+    pub date_source: DateSource, // 保存先フォルダと datetime_indexed に使った日時の出どころ
+    pub media_kind: &'static str, // ファイルの種類 ("photo" / "raw" / "heic" / "video" / "other")
+}
+
+/// 保存先の日付フォルダを決めた日時の出どころ (DB の date_source カラム)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DateSource {
+    /// EXIF DateTimeOriginal (撮影日時)
+    ExifOriginal,
+    /// EXIF DateTimeDigitized (デジタル化日時)
+    ExifDigitized,
+    /// EXIF DateTime (ファイル変更日時、撮影日時のタグがない場合)
+    ExifDateTime,
+    /// MP4/MOV の mvhd ボックスの作成日時 (動画で EXIF の日時がない場合)
+    VideoCreated,
+    /// ファイルの作成日時 (更新日時が取得できない場合)
+    FileCreated,
+    /// ファイルの更新日時 (EXIF の日時がない場合)
+    FileModified,
+}
+
+impl DateSource {
+    /// DB に保存する名前
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DateSource::ExifOriginal => "exif_original",
+            DateSource::ExifDigitized => "exif_digitized",
+            DateSource::ExifDateTime => "exif_datetime",
+            DateSource::VideoCreated => "video_created",
+            DateSource::FileCreated => "file_created",
+            DateSource::FileModified => "file_modified",
+        }
+    }
 }
 
 // --- メタデータ構造体 ---
 #[derive(Debug, Default)]
 pub struct Metadata {
-    pub datetime_original: Option<DateTime<Local>>,
+    pub datetime_original: Option<DateTime<FixedOffset>>, // 撮影地のオフセット付き (不明ならローカル)
+    pub datetime_source: Option<DateSource>, // datetime_original を読んだ EXIF タグ
     pub camera_make: Option<String>,
     pub camera_model: Option<String>,
+    pub body_serial: Option<String>, // カメラ本体のシリアル番号 (EXIF BodySerialNumber)
+    pub lens_serial: Option<String>, // レンズのシリアル番号 (EXIF LensSerialNumber)
+    pub rating: Option<u8>,     // XMPサイドカーのレーティング (0-5)
+    pub keywords: Vec<String>,  // XMPサイドカーのキーワード (dc:subject)
+    pub focal_length: Option<f64>,      // 焦点距離 (mm)
+    pub focal_length_35mm: Option<u32>, // 35mm判換算の焦点距離 (mm)
+    pub exposure_program: Option<String>, // 露出プログラム (例: "manual", "aperture priority")
+    pub white_balance: Option<String>,    // ホワイトバランス (例: "auto white balance", "manual white balance")
+    pub flash_fired: Option<bool>,        // フラッシュが発光したか (Flash タグの bit 0)
+    pub color_space: Option<String>,      // 色空間 (EXIF ColorSpace: "sRGB" / "Adobe RGB" / "uncalibrated")
+    pub has_icc_profile: Option<bool>,    // ICC プロファイルが埋め込まれているか (調べられない形式では None)
+    pub gps_latitude: Option<f64>,  // 緯度 (度、南緯は負)
+    pub gps_longitude: Option<f64>, // 経度 (度、西経は負)
+    pub gps_altitude: Option<f64>,  // 高度 (m、海面下は負)
+    pub gps_direction: Option<f64>, // 撮影方向 (度、0-360。真北/磁北は GPSImgDirectionRef による)
+    pub artist: Option<String>,     // 撮影者 (EXIF Artist)
+    pub copyright: Option<String>,  // 著作権表示 (EXIF Copyright)
+    pub location_name: Option<String>, // 撮影地の地名 (逆ジオコーディングの設定がある場合のみ、DB保存前に設定)
     // TODO: 他のメタデータフィールドを追加
 }
 
+/// ファイル単位の処理オプション
+#[derive(Debug, Default)]
+pub struct ProcessOptions {
+    /// コピー後にコピー先を再ハッシュして元ファイルと一致するか検証する (I/Oが倍になる)
+    pub verify: bool,
+    /// 一時的なI/Oエラーでコピーに失敗した場合のリトライ回数 (0 でリトライしない)
+    pub retries: u32,
+    /// ハッシュしながらコピーする場合 (`verify`) の読み書きの単位 (バイト、0 なら `DEFAULT_COPY_BUFFER_SIZE`)
+    /// ハッシュが不要なコピーは OS に任せる `fs::copy` を使うので影響しない
+    pub copy_buffer_size: usize,
+    /// 保存先のファイル名テンプレート (None なら元のファイル名のまま)
+    pub rename: Option<RenamePattern>,
+    /// オフセットの記録がない EXIF 日時を解釈するタイムゾーン (None ならシステムのローカルタイムゾーン)
+    pub timezone: Option<Tz>,
+    /// カタログへの置き方 (コピーかハードリンクか)
+    pub link: LinkMode,
+    /// RAW デコードの設定
+    pub raw: RawOptions,
+    /// コピー先の JPEG に小さなサムネイルを EXIF (IFD1) として埋め込む
+    pub embed_thumbnail: bool,
+    /// 既に EXIF サムネイルがある場合も置き換える
+    pub force_embed_thumbnail: bool,
+    /// コピー先の JPEG を EXIF Orientation に従って回転して保存し直し、Orientation を 1 にする
+    /// (コピーした元画像を書き換える。RAW/動画は対象外)
+    pub normalize_orientation: bool,
+    /// 既存のサムネイルがソースより新しくても作り直す
+    pub overwrite_thumbnails: bool,
+    /// サムネイルを一切作らない (コピーとメタデータの記録だけ行う。後から reindex-thumbnails で作れる)
+    pub no_thumbnails: bool,
+    /// サムネイルを作れなかったファイル (デコード失敗や動画など) に拡張子を描いた代替画像を保存する
+    /// (`skip_thumbnails` で除外した拡張子は対象外)
+    pub placeholder_thumbnails: bool,
+    /// 中断したインポートで処理を終えていた元ファイル (コピー先に同じサイズのファイルがあればコピーし直さない)
+    pub reuse_copies: HashSet<PathBuf>,
+    /// RAW/HEIC/動画など CPU 負荷の高いデコードの同時実行数の上限 (並行インポート時)
+    pub decode_limiter: ConcurrencyLimiter,
+    /// ファイルを読み込んでデコード・サムネイル生成する処理の同時実行数の上限 (並行インポート時のメモリ上限)
+    pub in_flight_limiter: ConcurrencyLimiter,
+}
+
+/// 元ファイルをカタログに置く方法
+///
+/// ハードリンクは元ファイルと中身を共有するが、`--normalize-orientation` などでコピー先を
+/// 書き換える処理は一時ファイルからの置き換えなので、元ファイルは変わらない。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LinkMode {
+    /// 常にコピーする
+    #[default]
+    Copy,
+    /// ハードリンクを作る (失敗したら警告を出してコピーする)
+    Hard,
+    /// 元ファイルと保存先が同じデバイスならハードリンク、違えばコピー
+    Auto,
+}
+
+impl FromStr for LinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "copy" => Ok(Self::Copy),
+            "hard" => Ok(Self::Hard),
+            "auto" => Ok(Self::Auto),
+            other => Err(format!(
+                "unknown link mode {:?} (expected \"hard\", \"copy\" or \"auto\")",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LinkMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Copy => "copy",
+            Self::Hard => "hard",
+            Self::Auto => "auto",
+        })
+    }
+}
+
+impl ProcessOptions {
+    /// ハッシュ計算とハッシュしながらのコピーで使うバッファのサイズ
+    fn copy_buffer_size(&self) -> usize {
+        match self.copy_buffer_size {
+            0 => DEFAULT_COPY_BUFFER_SIZE,
+            size => size,
+        }
+    }
+}
+
+/// 処理の同時実行数を制限するカウンティングセマフォ
+///
+/// 並行インポートで I/O 待ちの多いコピーは多めに走らせつつ、RAW 現像などは
+/// CPU コア数程度に、ファイル全体をメモリに読み込む処理はメモリに収まる数に抑えるために使う。
+#[derive(Debug, Default)]
+pub struct ConcurrencyLimiter {
+    /// 同時実行数の上限 (0 は無制限)
+    limit: usize,
+    running: Mutex<usize>,
+    released: Condvar,
+}
+
+impl ConcurrencyLimiter {
+    /// 同時に `limit` 件まで実行できるリミッターを作る (0 は無制限)
+    pub fn new(limit: usize) -> Self {
+        ConcurrencyLimiter {
+            limit,
+            ..ConcurrencyLimiter::default()
+        }
+    }
+
+    /// 空きが出るまで待ってから枠を確保する (戻り値を drop すると解放)
+    fn acquire(&self) -> LimiterSlot<'_> {
+        if self.limit > 0 {
+            let mut running = self.running.lock().unwrap_or_else(|e| e.into_inner());
+            while *running >= self.limit {
+                running = self.released.wait(running).unwrap_or_else(|e| e.into_inner());
+            }
+            *running += 1;
+        }
+        LimiterSlot(self)
+    }
+}
+
+/// [`ConcurrencyLimiter::acquire`] で確保した枠
+struct LimiterSlot<'a>(&'a ConcurrencyLimiter);
+
+impl Drop for LimiterSlot<'_> {
+    fn drop(&mut self) {
+        let limiter = self.0;
+        if limiter.limit > 0 {
+            *limiter.running.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+            limiter.released.notify_one();
+        }
+    }
+}
+
+/// サムネイル用のデコードの設定 (RAW の現像方法と、全形式に共通の画素数の上限)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RawOptions {
+    /// libraw での RAW 現像の制限時間 (None なら無制限)
+    pub timeout: Option<Duration>,
+    /// libraw より先に埋め込みプレビューを試す (高速だが、画質はカメラが埋め込んだプレビュー次第)
+    pub prefer_embedded_preview: bool,
+    /// デコードする画像の画素数 (幅×高さ) の上限 (0 なら image クレートの既定の制限のみ)
+    /// 超える画像はデコードせずにエラーとし、サムネイルなしで取り込む
+    pub max_pixels: u64,
+}
+
+/// 保存先ファイル名のテンプレート (`--rename-pattern`)
+///
+/// 使えるトークン:
+/// - `{date}`: YYYYMMDD, `{time}` / `{hhmmss}`: HHMMSS
+/// - `{yyyy}`, `{mm}`, `{dd}`, `{hh}`: 年, 月, 日, 時
+/// - `{orig}`: 元のファイル名 (拡張子なし), `{ext}`: 元の拡張子 (ドットなし)
+/// - `{seq}`: 実行ごとの連番 (4桁ゼロ埋め、1から。スキャンした順に元ファイルごとに振るので、
+///   複数のカタログや `--jobs` でも同じファイルは同じ番号、Live Photo の組も同じ番号になる)
+///
+/// 日時は撮影日時 (なければファイル更新日時)。
+/// `{ext}` を含まないテンプレートには元の拡張子が自動で付く。
+#[derive(Debug)]
+pub struct RenamePattern {
+    segments: Vec<RenameSegment>,
+}
+
+#[derive(Debug)]
+enum RenameSegment {
+    Literal(String),
+    Token(RenameToken),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RenameToken {
+    Date,
+    Time,
+    Year,
+    Month,
+    Day,
+    Hour,
+    Original,
+    Extension,
+    Sequence,
+}
+
+impl RenameToken {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "date" => Some(RenameToken::Date),
+            "time" | "hhmmss" => Some(RenameToken::Time),
+            "yyyy" => Some(RenameToken::Year),
+            "mm" => Some(RenameToken::Month),
+            "dd" => Some(RenameToken::Day),
+            "hh" => Some(RenameToken::Hour),
+            "orig" => Some(RenameToken::Original),
+            "ext" => Some(RenameToken::Extension),
+            "seq" => Some(RenameToken::Sequence),
+            _ => None,
+        }
+    }
+}
+
+impl RenamePattern {
+    /// テンプレートを解析する (未知のトークン、閉じていない括弧、パス区切り文字はエラー)
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(RenameSegment::Literal(rest[..start].to_string()));
+            }
+            let after = &rest[start + 1..];
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("unclosed '{{' in rename pattern '{}'", template))?;
+            let name = &after[..end];
+            let token = RenameToken::from_name(name)
+                .ok_or_else(|| format!("unknown token '{{{}}}' in rename pattern '{}'", name, template))?;
+            segments.push(RenameSegment::Token(token));
+            rest = &after[end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(RenameSegment::Literal(rest.to_string()));
+        }
+
+        for segment in &segments {
+            if let RenameSegment::Literal(text) = segment {
+                if text.contains('}') {
+                    return Err(format!("unmatched '}}' in rename pattern '{}'", template));
+                }
+                if text.contains('/') || text.contains('\\') {
+                    return Err(format!("rename pattern '{}' must not contain path separators", template));
+                }
+            }
+        }
+
+        Ok(RenamePattern { segments })
+    }
+
+    /// 保存先のファイル名を組み立てる (`sequence` は `{seq}` に入れる番号)
+    fn render(&self, datetime: &DateTime<FixedOffset>, original_name: &Path, sequence: u64) -> String {
+        let stem = original_name.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+        let ext = original_name.extension().map(|s| s.to_string_lossy()).unwrap_or_default();
+        let mut has_ext = false;
+
+        let mut name = String::new();
+        for segment in &self.segments {
+            match segment {
+                RenameSegment::Literal(text) => name.push_str(text),
+                RenameSegment::Token(token) => {
+                    let value = match token {
+                        RenameToken::Date => datetime.format("%Y%m%d").to_string(),
+                        RenameToken::Time => datetime.format("%H%M%S").to_string(),
+                        RenameToken::Year => datetime.format("%Y").to_string(),
+                        RenameToken::Month => datetime.format("%m").to_string(),
+                        RenameToken::Day => datetime.format("%d").to_string(),
+                        RenameToken::Hour => datetime.format("%H").to_string(),
+                        RenameToken::Original => stem.to_string(),
+                        RenameToken::Extension => {
+                            has_ext = true;
+                            ext.to_string()
+                        }
+                        RenameToken::Sequence => format!("{:04}", sequence),
+                    };
+                    name.push_str(&value);
+                }
+            }
+        }
+
+        if !has_ext && !ext.is_empty() {
+            name.push('.');
+            name.push_str(&ext);
+        }
+        name
+    }
+}
+
+/// 撮影日時として採用する EXIF タグの優先順 (日時, オフセット, 小数秒)
+///
+/// 1. DateTimeOriginal: 撮影日時
+/// 2. DateTimeDigitized: デジタル化日時 (スキャンしたフィルムなどはこれのみの場合がある)
+/// 3. DateTime: ファイル変更日時
+///
+/// 対応する OffsetTime* / SubSecTime* タグがあればタイムゾーンと小数秒も反映する
+const EXIF_DATE_TAGS: &[(exif::Tag, exif::Tag, exif::Tag, DateSource)] = &[
+    (
+        exif::Tag::DateTimeOriginal,
+        exif::Tag::OffsetTimeOriginal,
+        exif::Tag::SubSecTimeOriginal,
+        DateSource::ExifOriginal,
+    ),
+    (
+        exif::Tag::DateTimeDigitized,
+        exif::Tag::OffsetTimeDigitized,
+        exif::Tag::SubSecTimeDigitized,
+        DateSource::ExifDigitized,
+    ),
+    (exif::Tag::DateTime, exif::Tag::OffsetTime, exif::Tag::SubSecTime, DateSource::ExifDateTime),
+];
+
 /// 単一ファイルを処理する（コピー、メタデータ抽出、サムネイル生成）
+///
+/// `sequence` は `--rename-pattern` の `{seq}` に入れる番号。同じ元ファイルを複数のカタログに
+/// 取り込む場合も同じ名前になるよう、呼び出し元が元ファイルごとに決めて渡す。
 pub fn process_file(
     file_info: &FileInfo,
     catalog: &Catalog,
+    options: &ProcessOptions,
+    sequence: u64,
 ) -> ProcessorResult<ProcessedInfo> {
-    println!("Processing file: {:?}", file_info.path);
+    info!("Processing file: {:?}", file_info.path);
+    // ソースの stat は1回だけ (日時のフォールバック、インデックス、ファイルサイズで共用)
+    let source_meta = fs::metadata(&file_info.path).map_err(io_at(&file_info.path))?;
 
-    // 1. メタデータ抽出
-    let metadata = extract_exif_metadata(&file_info.path);
-    println!("  Extracted Metadata: {:?}", metadata);
+    // 1. メタデータ抽出 (EXIF + XMPサイドカー)
+    let mut metadata = extract_exif_metadata(&file_info.path, options.timezone);
+    if let Some(has_icc_profile) = detect_icc_profile(&file_info.path) {
+        metadata.has_icc_profile = Some(has_icc_profile);
+    }
+    if metadata.datetime_original.is_none() {
+        if let Some(created) = video_creation_time(&file_info.path, options.timezone) {
+            metadata.datetime_original = Some(created);
+            metadata.datetime_source = Some(DateSource::VideoCreated);
+        }
+    }
+    extract_xmp_sidecar(&file_info.path, &mut metadata);
+    // Live Photo の動画は EXIF を持たないことが多いので、静止画の撮影日時とカメラで補い同じ場所に保存する
+    if let Some(pair) = file_info.live_photo.as_ref().filter(|pair| pair.video == file_info.path) {
+        let still = extract_exif_metadata(&pair.still, options.timezone);
+        if metadata.datetime_original.is_none() {
+            metadata.datetime_original = still.datetime_original;
+            metadata.datetime_source = still.datetime_source;
+        }
+        metadata.camera_make = metadata.camera_make.or(still.camera_make);
+        metadata.camera_model = metadata.camera_model.or(still.camera_model);
+    }
+    info!("  Extracted Metadata: {:?}", metadata);
 
     // 2. 日付の特定 (メタデータ優先、なければファイル更新日時)
-    let datetime_for_path = match metadata.datetime_original {
-        Some(dt) => dt,
-        None => {
-            println!("  Original datetime not found in metadata, using file modification time.");
-            let file_meta = fs::metadata(&file_info.path)?;
-            let modified_time = file_meta.modified()?;
-            DateTime::from(modified_time)
-        }
+    // 保存先フォルダと datetime_indexed は同じ日時から決める
+    let (datetime_for_path, date_source) = match metadata.datetime_original {
+        Some(dt) => (dt, metadata.datetime_source.unwrap_or(DateSource::ExifOriginal)),
+        None => file_datetime(&file_info.path, &source_meta)?,
     };
+    info!("  Using {} for the destination date: {}", date_source.as_str(), datetime_for_path);
 
-    let year = datetime_for_path.format("%Y").to_string();
-    let month = datetime_for_path.format("%m").to_string();
-    let day = datetime_for_path.format("%d").to_string();
+    let mut subdirectory = PathBuf::new();
+    if catalog.group_by_camera_model {
+        subdirectory.push(camera_model_folder(metadata.camera_model.as_deref()));
+    }
+    subdirectory.push(catalog.path_granularity.subdirectory(&datetime_for_path));
 
     // 3. コピー先パス、サムネイル保存先パスの決定
-    let data_dest_dir = catalog.data_path.join(&year).join(&month).join(&day);
-    let thumbnail_dest_dir = catalog.thumbnail_path.join(&year).join(&month).join(&day);
+    let data_dest_dir = catalog.data_path.join(&subdirectory);
+    let thumbnail_dest_dir = catalog.thumbnail_dir().join(&subdirectory);
 
     // 4. 保存先ディレクトリの作成 (存在しない場合)
-    fs::create_dir_all(&data_dest_dir)?;
-    fs::create_dir_all(&thumbnail_dest_dir)?;
+    fs::create_dir_all(&data_dest_dir).map_err(io_at(&data_dest_dir))?;
+    fs::create_dir_all(&thumbnail_dest_dir).map_err(io_at(&thumbnail_dest_dir))?;
 
-    // 5. ファイル名の決定 (元のファイル名を使用)
-    let file_name = file_info
+    // 5. ファイル名の決定 (テンプレート指定があれば適用、なければ元のファイル名)
+    let original_name = file_info
         .path
         .file_name()
-        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid file path"))?;
+        .ok_or_else(|| ProcessorError::InvalidPath {
+            path: file_info.path.clone(),
+            reason: "no file name".to_string(),
+        })?;
+    let file_name = match &options.rename {
+        Some(pattern) => pattern.render(&datetime_for_path, Path::new(original_name), sequence).into(),
+        None => original_name.to_os_string(),
+    };
+    // 同名の別ファイル (連写を秒単位の名前にした場合など) を上書きしないよう、必要なら番号を付ける
+    let destination = claim_destination(&data_dest_dir, &file_name, &file_info.path, &source_meta, options)?;
 
-    let data_dest_path = data_dest_dir.join(file_name);
-    let thumbnail_dest_path_base = thumbnail_dest_dir.join(file_name);
+    let data_dest_path = data_dest_dir.join(&destination.file_name);
+    let thumbnail_dest_path_base = thumbnail_dest_dir.join(&destination.file_name);
 
     // 6. ファイルコピー
-    println!("Copying {:?} to {:?}", file_info.path, data_dest_path);
-    fs::copy(&file_info.path, &data_dest_path)?;
+    // ソースがカタログ内を指している場合、自分自身への上書きコピーになるためスキップする
+    let in_place = is_same_file(&file_info.path, &data_dest_path);
+    // 中断したインポートでコピー済みのファイルは、サイズが一致すればそのまま使う
+    let reused = !in_place
+        && options.reuse_copies.contains(&file_info.path)
+        && fs::metadata(&data_dest_path).is_ok_and(|meta| meta.len() == source_meta.len());
+    // --verify 指定時は内容まで一致した場合だけ使う (一致しなければコピーし直す)
+    let reused_hash = if reused && options.verify {
+        matching_copy_hash(&file_info.path, &data_dest_path, options.copy_buffer_size())?
+    } else {
+        None
+    };
+    let content_hash = if in_place {
+        warn!(
+            "Warning: {:?} is already at its destination, skipping copy",
+            file_info.path
+        );
+        if options.verify {
+            Some(hash_file(&data_dest_path, options.copy_buffer_size()).map_err(io_at(&data_dest_path))?)
+        } else {
+            None
+        }
+    } else if reused && !options.verify {
+        info!("Reusing copy from the interrupted import: {:?}", data_dest_path);
+        None
+    } else if let Some(hash) = reused_hash {
+        info!("Reusing verified copy from the interrupted import: {:?}", data_dest_path);
+        Some(hash)
+    } else if link_into_catalog(&file_info.path, &source_meta, &data_dest_path, options.link) {
+        // ハードリンクは元ファイルと同じ実体なので、検証はハッシュを記録するだけ
+        if options.verify {
+            Some(hash_file(&data_dest_path, options.copy_buffer_size()).map_err(io_at(&data_dest_path))?)
+        } else {
+            None
+        }
+    } else if options.verify {
+        info!("Copying {:?} to {:?}", file_info.path, data_dest_path);
+        Some(copy_verified(
+            &file_info.path,
+            &data_dest_path,
+            options.retries,
+            options.copy_buffer_size(),
+        )?)
+    } else {
+        info!("Copying {:?} to {:?}", file_info.path, data_dest_path);
+        with_retries(options.retries, || fs::copy(&file_info.path, &data_dest_path)).map_err(
+            |source| ProcessorError::Copy {
+                source_path: file_info.path.clone(),
+                dest_path: data_dest_path.clone(),
+                source,
+            },
+        )?;
+        None
+    };
+    // コピーできたので、確保した名前を残す (ここまでのエラーでは空のファイルを削除する)
+    destination.keep();
 
-    // 7. サムネイル生成
-    println!("Generating thumbnail for {:?}...", file_info.path);
-    let thumbnail_dest_path = generate_thumbnail(&file_info.path, &thumbnail_dest_path_base)?;
+    // ここから先 (EXIF サムネイル埋め込み、サムネイル生成) はファイル全体やデコード結果をメモリに持つ
+    let in_flight = options.in_flight_limiter.acquire();
 
-    // 日時インデックス生成
-    let datetime_indexed = match get_datetime_indexed(&file_info.path, &metadata) {
-        Ok(dt_indexed) => dt_indexed,
-        Err(e) => {
-            eprintln!("Error generating datetime index for {:?}: {}", file_info.path, e);
-            // フォールバック: 現在時刻を使用
-            let now = Local::now();
-            format_datetime_indexed(now)
+    // コピー先 JPEG の向きの正規化 (失敗してもインポート自体は続行し、コピーはそのまま残す)
+    // EXIF サムネイルの埋め込みは回転後の画像から作るよう、先に行う
+    if options.normalize_orientation && !in_place {
+        match normalize_jpeg_orientation(&data_dest_path) {
+            Ok(true) => info!("  Normalized orientation of {:?}", data_dest_path),
+            Ok(false) => {}
+            Err(e) => warn!("  Warning: {:?}: {}", data_dest_path, e),
+        }
+    }
+
+    // コピー先 JPEG への EXIF サムネイル埋め込み (失敗してもインポート自体は続行)
+    if options.embed_thumbnail && !in_place {
+        match embed_exif_thumbnail(&data_dest_path, options.force_embed_thumbnail) {
+            Ok(true) => info!("  Embedded EXIF thumbnail into {:?}", data_dest_path),
+            Ok(false) => {}
+            Err(e) => warn!("  Warning: {:?}: {}", data_dest_path, e),
+        }
+    }
+
+    // 7. サムネイル生成 (ソースより新しいサムネイルが既にあれば再利用する)
+    // Live Photo の動画は、組になっている静止画からサムネイルを作る
+    let thumbnail_source = match &file_info.live_photo {
+        Some(pair) if pair.video == file_info.path => &pair.still,
+        _ => &file_info.path,
+    };
+    let existing_thumbnail = if options.overwrite_thumbnails || options.no_thumbnails {
+        None
+    } else {
+        up_to_date_thumbnail(&file_info.path, &source_meta, &thumbnail_dest_path_base, catalog)
+    };
+    let thumbnail = match existing_thumbnail {
+        Some(thumbnail) => thumbnail,
+        None if options.no_thumbnails => ThumbnailOutcome::default(),
+        None => {
+            info!("Generating thumbnail for {:?}...", file_info.path);
+            generate_thumbnail(
+                thumbnail_source,
+                &thumbnail_dest_path_base,
+                catalog,
+                options.raw,
+                &options.decode_limiter,
+            )?
         }
     };
+    let thumbnail = if thumbnail.path.is_none()
+        && options.placeholder_thumbnails
+        && !options.no_thumbnails
+        && !catalog.skips_thumbnail(&file_info.path)
+    {
+        ThumbnailOutcome {
+            source_dimensions: thumbnail.source_dimensions,
+            error: thumbnail.error,
+            ..placeholder_thumbnail(&file_info.path, &thumbnail_dest_path_base, catalog)
+        }
+    } else {
+        thumbnail
+    };
+    drop(in_flight);
+    let (width, height) = thumbnail.source_dimensions.unzip();
+    let (thumbnail_width, thumbnail_height) = thumbnail.thumbnail_dimensions.unzip();
 
-    println!("Finished processing: {:?} (indexed: {})", file_info.path, datetime_indexed);
+    // 日時インデックス生成 (保存先フォルダと同じ日時)
+    let datetime_indexed = format_datetime_indexed(datetime_for_path);
+
+    info!("Finished processing: {:?} (indexed: {})", file_info.path, datetime_indexed);
 
     Ok(ProcessedInfo {
         original_path: file_info.path.clone(),
         data_dest_path,
-        thumbnail_dest_path,
+        thumbnail_dest_path: thumbnail.path,
+        thumbnail_error: thumbnail.error,
+        width,
+        height,
         metadata,
         datetime_indexed,
+        content_hash,
+        file_size: source_meta.len(),
+        thumbnail_width,
+        thumbnail_height,
+        live_photo_group: file_info.live_photo.as_ref().map(LivePhoto::group_key),
+        date_source,
+        media_kind: MediaKind::detect(&file_info.path, &catalog.extensions).column_value(),
     })
 }
 
 // --- ヘルパー関数 ---
 
+/// 一時的と思われるI/Oエラーの場合のみ、指数バックオフで `retries` 回まで再試行する
+/// (NotFound や PermissionDenied など再試行しても変わらないエラーは即座に返す)
+fn with_retries<T>(retries: u32, mut operation: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    const INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+    const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+    let mut attempt = 0;
+    loop {
+        match operation() {
+            Err(e) if attempt < retries && is_transient_io_error(&e) => {
+                let backoff = (INITIAL_BACKOFF * 2u32.saturating_pow(attempt)).min(MAX_BACKOFF);
+                attempt += 1;
+                error!(
+                    "  Transient I/O error ({}), retrying in {:?} ({}/{})",
+                    e, backoff, attempt, retries
+                );
+                thread::sleep(backoff);
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_transient_io_error(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    )
+}
+
+/// ハッシュ計算とハッシュしながらのコピーの読み書きの単位 (`--copy-buffer-size` の既定値)
+pub const DEFAULT_COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// カメラモデル名をフォルダ名として使える形にする (モデル不明なら "Unknown")
+/// パス区切りや制御文字は `_` に置き換え、前後の空白と引用符は取り除く
+fn camera_model_folder(camera_model: Option<&str>) -> String {
+    let name: String = camera_model
+        .unwrap_or("")
+        .trim()
+        .trim_matches('"')
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    match name.as_str() {
+        "" | "." | ".." => "Unknown".to_string(),
+        _ => name,
+    }
+}
+
+/// `claim_destination` で決めた保存先のファイル名
+///
+/// 空いている名前を確保するために作った空のファイルは、`keep` を呼ばずにドロップすると削除される
+/// (コピーに失敗した場合に、空のファイルがカタログに残らないように)。
+struct ClaimedDestination {
+    file_name: OsString,
+    placeholder: Option<PathBuf>,
+}
+
+impl ClaimedDestination {
+    /// コピーが済んだので、確保した名前のファイルを残す
+    fn keep(mut self) {
+        self.placeholder = None;
+    }
+}
+
+impl Drop for ClaimedDestination {
+    fn drop(&mut self) {
+        if let Some(path) = self.placeholder.take() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// 保存先ディレクトリでのファイル名を決める
+///
+/// `file_name` に別の内容のファイルが既にあれば、`_1`, `_2` ... を拡張子の前に付けた空いている名前を使う。
+/// 空いている名前は空のファイルを作って確保する (並行処理で同じ名前を選ばないように)。
+/// 既にあるのが元ファイル自身か、その以前のコピー (サイズが同じ、`--verify` 指定時は内容も同じ)
+/// ならその名前のまま使う (再インポートでは従来どおり上書きする)。
+fn claim_destination(
+    dir: &Path,
+    file_name: &OsStr,
+    source: &Path,
+    source_meta: &fs::Metadata,
+    options: &ProcessOptions,
+) -> ProcessorResult<ClaimedDestination> {
+    let stem = Path::new(file_name).file_stem().unwrap_or(file_name);
+    let extension = Path::new(file_name).extension();
+    let mut attempt = 0u32;
+    loop {
+        let candidate = if attempt == 0 {
+            file_name.to_os_string()
+        } else {
+            let mut candidate = stem.to_os_string();
+            candidate.push(format!("_{}", attempt));
+            if let Some(extension) = extension {
+                candidate.push(".");
+                candidate.push(extension);
+            }
+            candidate
+        };
+        let path = dir.join(&candidate);
+        match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => {
+                if attempt > 0 {
+                    warn!(
+                        "  Warning: {:?} already exists with different content, saving {:?} as {:?}",
+                        dir.join(file_name),
+                        source,
+                        path
+                    );
+                }
+                return Ok(ClaimedDestination { file_name: candidate, placeholder: Some(path) });
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(io_at(&path)(e)),
+        }
+        if is_same_file(source, &path) || is_copy_of(source, source_meta, &path, options)? {
+            return Ok(ClaimedDestination { file_name: candidate, placeholder: None });
+        }
+        attempt += 1;
+    }
+}
+
+/// `dest` が `source` の以前のコピーかどうか
+///
+/// サイズが同じならコピーとみなす。`--verify` 指定時は、中断したインポートのコピー以外は内容も比べる
+/// (ハッシュのための読み込みは検証を頼まれた場合だけにする)。
+fn is_copy_of(source: &Path, source_meta: &fs::Metadata, dest: &Path, options: &ProcessOptions) -> ProcessorResult<bool> {
+    if fs::metadata(dest).map_err(io_at(dest))?.len() != source_meta.len() {
+        return Ok(false);
+    }
+    if !options.verify || options.reuse_copies.contains(source) {
+        return Ok(true);
+    }
+    Ok(matching_copy_hash(source, dest, options.copy_buffer_size())?.is_some())
+}
+
+/// 2つのパスが同じ実体 (シンボリックリンク等を解決した後で同一パス) を指すかどうか
+///
+/// どちらかが存在しない場合は false。
+pub(crate) fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// `mode` に従ってカタログにハードリンクを作る (作った場合は true、コピーすべき場合は false)
+///
+/// ハードリンクに失敗した場合は警告を出して false を返す (呼び出し側でコピーする)。
+fn link_into_catalog(source: &Path, source_meta: &fs::Metadata, dest: &Path, mode: LinkMode) -> bool {
+    match mode {
+        LinkMode::Copy => return false,
+        LinkMode::Hard => {}
+        LinkMode::Auto => {
+            if same_device(source_meta, dest) == Some(false) {
+                verbose!("  {:?} is on a different device from the catalog, copying", source);
+                return false;
+            }
+        }
+    }
+    info!("Hard-linking {:?} to {:?}", source, dest);
+    match hard_link_replacing(source, dest) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("  Warning: could not hard-link {:?} ({}), copying instead", source, e);
+            false
+        }
+    }
+}
+
+/// 元ファイルと保存先のディレクトリが同じデバイスにあるか (Unix 以外や調べられない場合は None)
+#[cfg(unix)]
+fn same_device(source_meta: &fs::Metadata, dest: &Path) -> Option<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let dest_meta = fs::metadata(dest.parent()?).ok()?;
+    Some(source_meta.dev() == dest_meta.dev())
+}
+
+#[cfg(not(unix))]
+fn same_device(_source_meta: &fs::Metadata, _dest: &Path) -> Option<bool> {
+    None
+}
+
+/// `source` のハードリンクを `dest` に作る (既に `dest` があれば置き換える)
+///
+/// `fs::hard_link` は既存のファイルを上書きしないため、一時的な名前で作ってから名前を変える。
+fn hard_link_replacing(source: &Path, dest: &Path) -> io::Result<()> {
+    let mut temp_path = dest.as_os_str().to_owned();
+    temp_path.push(".casket-link");
+    let temp_path = PathBuf::from(temp_path);
+    let _ = fs::remove_file(&temp_path);
+    fs::hard_link(source, &temp_path)?;
+    let result = fs::rename(&temp_path, dest);
+    // 失敗した場合と、`dest` が既に同じファイルへのリンクだった場合 (rename は何もしない) は一時的な名前が残る
+    let _ = fs::remove_file(&temp_path);
+    result
+}
+
+/// 元ファイルをハッシュしながらコピーし、コピー先を再ハッシュして一致を検証する
+/// 不一致の場合はコピー先を削除してエラーを返す。成功時は SHA-256 の16進文字列を返す
+fn copy_verified(source: &Path, dest: &Path, retries: u32, buffer_size: usize) -> ProcessorResult<String> {
+    let source_hash = with_retries(retries, || copy_with_hash(source, dest, buffer_size)).map_err(|e| {
+        ProcessorError::Copy {
+            source_path: source.to_path_buf(),
+            dest_path: dest.to_path_buf(),
+            source: e,
+        }
+    })?;
+    let dest_hash = hash_file(dest, buffer_size).map_err(io_at(dest))?;
+
+    if source_hash != dest_hash {
+        fs::remove_file(dest).map_err(io_at(dest))?;
+        return Err(ProcessorError::ChecksumMismatch {
+            source_path: source.to_path_buf(),
+            dest_path: dest.to_path_buf(),
+            source_hash,
+            dest_hash,
+        });
+    }
+
+    verbose!("  Verified SHA-256: {}", source_hash);
+    Ok(source_hash)
+}
+
+/// コピー先が元ファイルと同じ内容ならその SHA-256 を返す (異なれば None)
+fn matching_copy_hash(source: &Path, dest: &Path, buffer_size: usize) -> ProcessorResult<Option<String>> {
+    let source_hash = hash_file(source, buffer_size).map_err(io_at(source))?;
+    let dest_hash = hash_file(dest, buffer_size).map_err(io_at(dest))?;
+    Ok((source_hash == dest_hash).then_some(source_hash))
+}
+
+/// 読み込んだデータをハッシュしつつ `buffer_size` バイトずつ書き出す (元ファイルの読み込みは1回のみ)
+fn copy_with_hash(source: &Path, dest: &Path, buffer_size: usize) -> io::Result<String> {
+    let mut reader = File::open(source)?;
+    let mut writer = File::create(dest)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; buffer_size];
+
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        writer.write_all(&buffer[..read])?;
+    }
+    writer.sync_all()?;
+
+    // fs::copy と同様にパーミッションも引き継ぐ
+    fs::set_permissions(dest, reader.metadata()?.permissions())?;
+
+    Ok(hex_digest(hasher))
+}
+
+/// ファイル全体の SHA-256 を計算する
+fn hash_file(path: &Path, buffer_size: usize) -> io::Result<String> {
+    let mut reader = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; buffer_size];
+
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hex_digest(hasher))
+}
+
+fn hex_digest(hasher: Sha256) -> String {
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
 /// 拡大を防ぐリサイズ関数。最大サイズより小さい場合は元のサイズを保持
-fn resize_without_upscaling(img: DynamicImage, max_size: u32) -> DynamicImage {
+/// `filter` が Fast 以外なら指定のフィルターで `resize()` する (遅いが高画質)
+fn resize_without_upscaling(img: DynamicImage, max_size: u32, filter: ThumbnailFilter) -> DynamicImage {
     let (width, height) = (img.width(), img.height());
     let max_dimension = width.max(height);
     
     if max_dimension <= max_size {
         // 元画像が最大サイズより小さい場合はそのまま返す
-        println!("  Image size {}x{} is smaller than max {}, keeping original size", 
+        info!("  Image size {}x{} is smaller than max {}, keeping original size", 
                 width, height, max_size);
         img
     } else {
         // 長辺を基準にアスペクト比を保ってリサイズ
-        let thumbnail = img.thumbnail(max_size, max_size);
-        println!("  Resized from {}x{} to {}x{}", 
+        let thumbnail = match filter.filter_type() {
+            Some(filter_type) => img.resize(max_size, max_size, filter_type),
+            None => img.thumbnail(max_size, max_size),
+        };
+        info!("  Resized from {}x{} to {}x{}", 
                 width, height, thumbnail.width(), thumbnail.height());
         thumbnail
     }
 }
 
 /// 日時をYYYYMMDDHH形式にフォーマットする関数
-fn format_datetime_indexed(dt: DateTime<Local>) -> String {
+fn format_datetime_indexed(dt: DateTime<FixedOffset>) -> String {
     dt.format("%Y%m%d%H").to_string()
 }
 
-/// ファイルから日時を取得し、YYYYMMDDHH形式でフォーマット
-/// 撮影日時が取得できない場合はファイル作成日時を使用
-fn get_datetime_indexed(file_path: &Path, metadata: &Metadata) -> Result<String, Box<dyn Error>> {
-    if let Some(datetime_original) = metadata.datetime_original {
-        // EXIFから撮影日時が取得できた場合
-        println!("  Using EXIF datetime for indexing: {}", datetime_original);
-        Ok(format_datetime_indexed(datetime_original))
-    } else {
-        // EXIFから取得できない場合はファイル作成日時を使用
-        let file_meta = std::fs::metadata(file_path)?;
-        let created_time = file_meta.created()
-            .or_else(|_| file_meta.modified())?; // 作成日時が取得できない場合は更新日時
-        let datetime = DateTime::from(created_time);
-        println!("  Using file creation time for indexing: {}", datetime);
-        Ok(format_datetime_indexed(datetime))
-    }
+/// EXIF の日時がないファイルの日時 (更新日時、取得できなければ作成日時。`file_meta` は取得済みのソースの stat)
+///
+/// カードからのコピーなどで作成日時はコピーした時刻になりがちなので、更新日時を優先する。
+fn file_datetime(path: &Path, file_meta: &fs::Metadata) -> ProcessorResult<(DateTime<FixedOffset>, DateSource)> {
+    let (time, source) = match file_meta.modified() {
+        Ok(modified) => (modified, DateSource::FileModified),
+        Err(_) => (file_meta.created().map_err(io_at(path))?, DateSource::FileCreated),
+    };
+    info!("  Original datetime not found in metadata, using the file time ({}).", source.as_str());
+    Ok((DateTime::<Local>::from(time).fixed_offset(), source))
 }
 
-/// クオリティ指定でJPEGサムネイルを保存するヘルパー関数
-fn save_jpeg_thumbnail(
+/// AVIF エンコードの速度 (1-10、大きいほど速く圧縮率は下がる)
+#[cfg(feature = "avif")]
+const AVIF_ENCODE_SPEED: u8 = 8;
+
+/// 指定の形式とクオリティでサムネイルを保存するヘルパー関数
+///
+/// JPEG は image クレートの `JpegEncoder` で書き出す。このエンコーダーは色差を間引かない
+/// (全コンポーネントのサンプリング係数が 1x1 の 4:4:4) ため、赤い文字などの色にじみは出ない。
+/// 4:2:0 などを選ぶ設定はエンコーダーにないので、サブサンプリングの切り替えは提供していない。
+fn save_thumbnail(
     img: &DynamicImage,
     path: &Path,
-    quality: u8, // 1-10 scale
-) -> Result<(), Box<dyn Error>> {
-    // 1-10スケールを0-100スケールに変換 (1=10%, 10=100%)
-    let jpeg_quality = (quality * 10).min(100);
-    
-    let file = File::create(path)?;
-    let mut encoder = JpegEncoder::new_with_quality(file, jpeg_quality);
-    
+    format: ThumbnailFormat,
+    quality: u8, // 1-100 (100が最高画質)
+) -> ProcessorResult<()> {
+    let write_error = |source| ProcessorError::ThumbnailWrite {
+        path: path.to_path_buf(),
+        source,
+    };
+
+    let file = File::create(path).map_err(|e| write_error(image::ImageError::IoError(e)))?;
     let rgb_image = img.to_rgb8();
-    encoder.encode(
-        rgb_image.as_raw(),
-        img.width(),
-        img.height(),
-        image::ExtendedColorType::Rgb8,
-    )?;
-    
-    println!("  Saved JPEG thumbnail with quality {} ({}%) to {:?}", 
-            quality, jpeg_quality, path);
+    match format {
+        ThumbnailFormat::Jpeg => {
+            let mut encoder = JpegEncoder::new_with_quality(file, quality);
+            encoder.encode(
+                rgb_image.as_raw(),
+                img.width(),
+                img.height(),
+                image::ExtendedColorType::Rgb8,
+            ).map_err(write_error)?;
+            info!("  Saved JPEG thumbnail with quality {} to {:?}", quality, path);
+        }
+        #[cfg(feature = "avif")]
+        ThumbnailFormat::Avif => {
+            use image::ImageEncoder;
+            let encoder = image::codecs::avif::AvifEncoder::new_with_speed_quality(
+                file,
+                AVIF_ENCODE_SPEED,
+                quality,
+            );
+            encoder.write_image(
+                rgb_image.as_raw(),
+                img.width(),
+                img.height(),
+                image::ExtendedColorType::Rgb8,
+            ).map_err(write_error)?;
+            info!("  Saved AVIF thumbnail with quality {} to {:?}", quality, path);
+        }
+    }
     Ok(())
 }
 
-/// EXIF情報からメタデータ (日付, メーカー, モデル) を抽出する
-fn extract_exif_metadata(file_path: &Path) -> Metadata {
-    let mut metadata = Metadata::default();
+/// EXIF サムネイルの長辺 (px)
+const EXIF_THUMBNAIL_SIZE: u32 = 160;
+/// EXIF サムネイルの JPEG クオリティ
+const EXIF_THUMBNAIL_QUALITY: u8 = 75;
+/// APP1 セグメントに格納できるデータの最大長 (長さフィールド 2 バイトを除く)
+const APP1_MAX_LEN: usize = 0xFFFF - 2;
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
 
-    let file = match File::open(file_path) {
-        Ok(f) => f,
-        Err(e) => {
-            eprintln!("  Error opening file for EXIF reading {:?}: {}", file_path, e);
-            return metadata;
-        }
+/// JPEG ファイルの EXIF に縮小サムネイル (IFD1) を書き込む
+///
+/// JPEG 以外は何もせず `Ok(false)` を返す。既にサムネイルがある場合は `force` 指定時のみ置き換える。
+/// 既存の EXIF フィールドは保持するが、MakerNote 内のオフセットに依存する情報は壊れる可能性がある。
+fn embed_exif_thumbnail(path: &Path, force: bool) -> ProcessorResult<bool> {
+    let data = fs::read(path)?;
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        verbose!("  Not a JPEG, skipping EXIF thumbnail: {:?}", path);
+        return Ok(false);
+    }
+
+    let existing = exif::Reader::new().read_from_container(&mut Cursor::new(&data)).ok();
+    let has_thumbnail = existing.as_ref().is_some_and(|exif| {
+        exif.get_field(exif::Tag::JPEGInterchangeFormat, exif::In::THUMBNAIL).is_some()
+    });
+    if has_thumbnail && !force {
+        verbose!("  EXIF thumbnail already present, skipping: {:?}", path);
+        return Ok(false);
+    }
+
+    // サムネイル画像の生成
+    let img = image::load_from_memory_with_format(&data, ImageFormat::Jpeg)?;
+    let thumbnail = exif_thumbnail_jpeg(&img)?;
+
+    // 既存フィールド (IFD1 以外) を引き継ぎ、IFD1 は作り直す
+    let little_endian = existing.as_ref().is_some_and(|exif| exif.little_endian());
+    let mut fields: Vec<exif::Field> = existing
+        .as_ref()
+        .map(|exif| {
+            exif.fields()
+                .filter(|field| field.ifd_num == exif::In::PRIMARY)
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default();
+    // IFD は空にできないため、EXIF がないファイルには解像度だけ書く
+    if fields.is_empty() {
+        push_resolution_fields(&mut fields, exif::In::PRIMARY);
+    }
+
+    let output = exif_app1(&fields, Some(&thumbnail), little_endian)
+        .and_then(|app1| replace_exif_segment(&data, &app1))
+        .map_err(ProcessorError::EmbedThumbnail)?;
+    replace_file_contents(path, &output)?;
+    Ok(true)
+}
+
+/// 向きを正規化した JPEG を書き直すときのクオリティ (再エンコードによる劣化を抑える)
+const NORMALIZED_JPEG_QUALITY: u8 = 95;
+
+/// JPEG の画素を EXIF Orientation に従って回転・反転して保存し直し、Orientation を 1 にする
+///
+/// JPEG 以外と、Orientation がない/既に 1 のファイルは何もせず `Ok(false)` を返す。
+/// 画素データは再エンコードされる (クオリティ `NORMALIZED_JPEG_QUALITY`)。
+/// 他の EXIF フィールドと、ICC プロファイルや XMP などのセグメントは引き継ぐ
+/// (EXIF サムネイルがあれば回転後の画像から作り直す)。
+fn normalize_jpeg_orientation(path: &Path) -> ProcessorResult<bool> {
+    let data = fs::read(path)?;
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        verbose!("  Not a JPEG, skipping orientation normalization: {:?}", path);
+        return Ok(false);
+    }
+    let Ok(existing) = exif::Reader::new().read_from_container(&mut Cursor::new(&data)) else {
+        return Ok(false);
     };
-    let mut bufreader = BufReader::new(&file);
-    let exifreader = match exif::Reader::new().read_from_container(&mut bufreader) {
-        Ok(r) => r,
-        Err(_) => {
-            return metadata;
-        }
+    let orientation = existing
+        .get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+        .and_then(|value| u8::try_from(value).ok())
+        .and_then(image::metadata::Orientation::from_exif);
+    let orientation = match orientation {
+        Some(image::metadata::Orientation::NoTransforms) | None => return Ok(false),
+        Some(orientation) => orientation,
     };
 
-    // 日付 (DateTimeOriginal or DateTime)
-    let date_tag = exifreader
-        .get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY)
-        .or_else(|| exifreader.get_field(exif::Tag::DateTime, exif::In::PRIMARY));
-    if let Some(field) = date_tag {
-        if let exif::Value::Ascii(ref vec) = field.value {
-            if let Some(first_vec) = vec.get(0) {
-                 if let Ok(datetime_str) = std::str::from_utf8(first_vec) {
-                    if let Ok(naive_dt) =
-                        NaiveDateTime::parse_from_str(datetime_str.trim(), "%Y:%m:%d %H:%M:%S")
-                    {
-                        match Local.from_local_datetime(&naive_dt) {
-                            chrono::LocalResult::Single(local_dt) => metadata.datetime_original = Some(local_dt),
-                            chrono::LocalResult::Ambiguous(dt1, _) => metadata.datetime_original = Some(dt1),
-                            _ => eprintln!("  Could not convert NaiveDateTime to Local DateTime: {}", naive_dt),
-                        }
-                    } else {
-                         eprintln!("  Failed to parse EXIF datetime string: '{}'", datetime_str);
-                    }
-                }
-            }
+    let mut img = image::load_from_memory_with_format(&data, ImageFormat::Jpeg)?;
+    img.apply_orientation(orientation);
+    let rgb = img.to_rgb8();
+    let mut encoded = Vec::new();
+    JpegEncoder::new_with_quality(&mut encoded, NORMALIZED_JPEG_QUALITY).encode(
+        rgb.as_raw(),
+        rgb.width(),
+        rgb.height(),
+        image::ExtendedColorType::Rgb8,
+    )?;
+
+    // Orientation と画像サイズ以外の EXIF フィールドはそのまま引き継ぐ
+    let had_thumbnail = existing
+        .get_field(exif::Tag::JPEGInterchangeFormat, exif::In::THUMBNAIL)
+        .is_some();
+    let mut fields: Vec<exif::Field> = existing
+        .fields()
+        .filter(|field| field.ifd_num == exif::In::PRIMARY)
+        .cloned()
+        .collect();
+    for field in &mut fields {
+        match field.tag {
+            exif::Tag::Orientation => field.value = exif::Value::Short(vec![1]),
+            exif::Tag::PixelXDimension => field.value = exif::Value::Long(vec![img.width()]),
+            exif::Tag::PixelYDimension => field.value = exif::Value::Long(vec![img.height()]),
+            _ => {}
         }
     }
+    let thumbnail = if had_thumbnail {
+        Some(exif_thumbnail_jpeg(&img)?)
+    } else {
+        None
+    };
 
-    // メーカー (Make)
-    if let Some(field) = exifreader.get_field(exif::Tag::Make, exif::In::PRIMARY) {
-        metadata.camera_make = Some(field.display_value().to_string());
+    // 元ファイルの APPn/COM セグメント (EXIF 以外) と、再エンコードした画像データを組み合わせる
+    let output = jpeg_segments(&data)
+        .and_then(|(metadata, _)| {
+            let (_, image_data) = jpeg_segments(&encoded)?;
+            let mut combined = vec![0xFF, 0xD8];
+            combined.extend_from_slice(&metadata);
+            combined.extend_from_slice(&image_data);
+            let app1 = exif_app1(&fields, thumbnail.as_deref(), existing.little_endian())?;
+            replace_exif_segment(&combined, &app1)
+        })
+        .map_err(ProcessorError::NormalizeOrientation)?;
+    replace_file_contents(path, &output)?;
+    Ok(true)
+}
+
+/// EXIF (IFD1) に埋め込む縮小サムネイルの JPEG データを作る
+fn exif_thumbnail_jpeg(img: &DynamicImage) -> ProcessorResult<Vec<u8>> {
+    let small = img.thumbnail(EXIF_THUMBNAIL_SIZE, EXIF_THUMBNAIL_SIZE).to_rgb8();
+    let mut thumbnail = Vec::new();
+    JpegEncoder::new_with_quality(&mut thumbnail, EXIF_THUMBNAIL_QUALITY).encode(
+        small.as_raw(),
+        small.width(),
+        small.height(),
+        image::ExtendedColorType::Rgb8,
+    )?;
+    Ok(thumbnail)
+}
+
+/// 解像度 72dpi の XResolution / YResolution / ResolutionUnit を追加する
+fn push_resolution_fields(fields: &mut Vec<exif::Field>, ifd_num: exif::In) {
+    for tag in [exif::Tag::XResolution, exif::Tag::YResolution] {
+        fields.push(exif::Field {
+            tag,
+            ifd_num,
+            value: exif::Value::Rational(vec![exif::Rational { num: 72, denom: 1 }]),
+        });
+    }
+    fields.push(exif::Field {
+        tag: exif::Tag::ResolutionUnit,
+        ifd_num,
+        value: exif::Value::Short(vec![2]),
+    });
+}
+
+/// EXIF フィールド (と IFD1 のサムネイル) から APP1 セグメントの中身 ("Exif\0\0" + TIFF) を作る
+fn exif_app1(
+    fields: &[exif::Field],
+    thumbnail: Option<&[u8]>,
+    little_endian: bool,
+) -> Result<Vec<u8>, String> {
+    let mut thumbnail_fields = Vec::new();
+    if thumbnail.is_some() {
+        thumbnail_fields.push(exif::Field {
+            tag: exif::Tag::Compression,
+            ifd_num: exif::In::THUMBNAIL,
+            value: exif::Value::Short(vec![6]), // 6 = JPEG
+        });
+        push_resolution_fields(&mut thumbnail_fields, exif::In::THUMBNAIL);
+    }
+
+    let mut writer = exif::experimental::Writer::new();
+    for field in fields.iter().chain(&thumbnail_fields) {
+        writer.push_field(field);
+    }
+    if let Some(thumbnail) = thumbnail {
+        writer.set_jpeg(thumbnail, exif::In::THUMBNAIL);
+    }
+    let mut tiff = Cursor::new(Vec::new());
+    writer.write(&mut tiff, little_endian).map_err(|e| e.to_string())?;
+
+    let mut app1 = EXIF_HEADER.to_vec();
+    app1.extend_from_slice(&tiff.into_inner());
+    if app1.len() > APP1_MAX_LEN {
+        return Err(format!(
+            "EXIF data would be {} bytes, larger than a JPEG APP1 segment allows",
+            app1.len()
+        ));
+    }
+    Ok(app1)
+}
+
+/// 途中で失敗しても元ファイルが壊れないよう、一時ファイルに書いてから置き換える
+fn replace_file_contents(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temp_path = path.with_extension("casket-tmp");
+    fs::write(&temp_path, contents)?;
+    if let Err(e) = fs::rename(&temp_path, path) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// JPEG を SOI の後のメタデータセグメント (EXIF と Adobe を除く APPn/COM) とそれ以外
+/// (量子化テーブルなどと SOS 以降の画像データ) に分ける
+///
+/// Adobe (APP14) セグメントは元の色変換の指定なので、再エンコードした画像には引き継がない。
+fn jpeg_segments(data: &[u8]) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let malformed = || "malformed JPEG segment".to_string();
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return Err(malformed());
+    }
+
+    let mut metadata = Vec::new();
+    let mut image_data = Vec::new();
+    let mut offset = 2;
+    while offset + 4 <= data.len() && data[offset] == 0xFF {
+        let marker = data[offset + 1];
+        if marker == 0xDA {
+            break;
+        }
+        let len = u16::from_be_bytes([data[offset + 2], data[offset + 3]]) as usize;
+        let end = offset + 2 + len;
+        if len < 2 || end > data.len() {
+            return Err(malformed());
+        }
+        let segment = &data[offset..end];
+        let is_exif = marker == 0xE1 && segment[4..].starts_with(EXIF_HEADER);
+        match marker {
+            0xEE => {}
+            0xE0..=0xEF | 0xFE if !is_exif => metadata.extend_from_slice(segment),
+            0xE0..=0xEF => {}
+            _ => image_data.extend_from_slice(segment),
+        }
+        offset = end;
+    }
+    image_data.extend_from_slice(&data[offset..]);
+    Ok((metadata, image_data))
+}
+
+/// JPEG の EXIF (APP1) セグメントを `app1` で置き換えた (なければ追加した) データを返す
+///
+/// 新しい APP1 は SOI と JFIF (APP0) の直後に置く。
+fn replace_exif_segment(data: &[u8], app1: &[u8]) -> Result<Vec<u8>, String> {
+    let malformed = || "malformed JPEG segment".to_string();
+
+    let mut output = Vec::with_capacity(data.len() + app1.len() + 4);
+    output.extend_from_slice(&data[..2]); // SOI
+    let mut inserted = false;
+    let mut offset = 2;
+    while offset + 4 <= data.len() && data[offset] == 0xFF {
+        let marker = data[offset + 1];
+        // SOS 以降は画像データなのでそのままコピーする
+        if marker == 0xDA {
+            break;
+        }
+        let len = u16::from_be_bytes([data[offset + 2], data[offset + 3]]) as usize;
+        let end = offset + 2 + len;
+        if len < 2 || end > data.len() {
+            return Err(malformed());
+        }
+        let segment = &data[offset..end];
+
+        if !inserted && marker != 0xE0 {
+            write_app1(&mut output, app1);
+            inserted = true;
+        }
+        let is_exif = marker == 0xE1 && segment[4..].starts_with(EXIF_HEADER);
+        if !is_exif {
+            output.extend_from_slice(segment);
+        }
+        offset = end;
+    }
+    if !inserted {
+        write_app1(&mut output, app1);
+    }
+    output.extend_from_slice(&data[offset..]);
+    Ok(output)
+}
+
+/// ICC プロファイルを持つ TIFF タグ (InterColorProfile)
+const TIFF_TAG_INTER_COLOR_PROFILE: u16 = 0x8773;
+
+/// JPEG の APP2 セグメントで ICC プロファイルを表す識別子
+const ICC_PROFILE_HEADER: &[u8] = b"ICC_PROFILE\0";
+
+/// 埋め込みの ICC プロファイルがあるかを調べる (JPEG は APP2、PNG は iCCP チャンク)
+///
+/// それ以外の形式や読み込みに失敗した場合は None (TIFF 系は EXIF の抽出で調べる)
+fn detect_icc_profile(path: &Path) -> Option<bool> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let mut signature = [0u8; 8];
+    reader.read_exact(&mut signature).ok()?;
+    let result = match signature {
+        [0xFF, 0xD8, ..] => reader
+            .seek(io::SeekFrom::Start(2))
+            .and_then(|_| jpeg_has_icc_profile(&mut reader)),
+        [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A] => png_has_icc_profile(&mut reader),
+        _ => return None,
+    };
+    match result {
+        Ok(has_icc_profile) => Some(has_icc_profile),
+        Err(e) => {
+            verbose!("  Could not check {:?} for an ICC profile: {}", path, e);
+            None
+        }
+    }
+}
+
+/// SOS までの JPEG セグメントに ICC_PROFILE の APP2 があるかを調べる (SOI は読み込み済み)
+fn jpeg_has_icc_profile<R: Read + Seek>(reader: &mut R) -> io::Result<bool> {
+    loop {
+        let mut marker = [0u8; 2];
+        reader.read_exact(&mut marker)?;
+        if marker[0] != 0xFF {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed JPEG segment"));
+        }
+        match marker[1] {
+            // 画像データ (SOS) か終端 (EOI) まで来たらメタデータはもうない
+            0xDA | 0xD9 => return Ok(false),
+            // 長さを持たないマーカー (RSTn、TEM) とフィルバイト
+            0xD0..=0xD7 | 0x01 | 0xFF => continue,
+            _ => {}
+        }
+        let mut length = [0u8; 2];
+        reader.read_exact(&mut length)?;
+        let length = u16::from_be_bytes(length);
+        if length < 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed JPEG segment"));
+        }
+        let mut remaining = i64::from(length) - 2;
+        if marker[1] == 0xE2 && remaining >= ICC_PROFILE_HEADER.len() as i64 {
+            let mut identifier = [0u8; ICC_PROFILE_HEADER.len()];
+            reader.read_exact(&mut identifier)?;
+            if identifier == ICC_PROFILE_HEADER {
+                return Ok(true);
+            }
+            remaining -= identifier.len() as i64;
+        }
+        reader.seek(io::SeekFrom::Current(remaining))?;
+    }
+}
+
+/// 画像データ (IDAT) より前のチャンクに iCCP があるかを調べる (シグネチャは読み込み済み)
+fn png_has_icc_profile<R: Read + Seek>(reader: &mut R) -> io::Result<bool> {
+    loop {
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header)?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        match &header[4..] {
+            b"iCCP" => return Ok(true),
+            b"IDAT" | b"IEND" => return Ok(false),
+            // チャンクのデータと CRC を飛ばす
+            _ => {
+                reader.seek(io::SeekFrom::Current(i64::from(length) + 4))?;
+            }
+        }
+    }
+}
+
+/// mvhd の作成日時の基準 (1904-01-01 UTC) から Unix エポックまでの秒数
+const MP4_EPOCH_OFFSET_SECS: i64 = 2_082_844_800;
+
+/// MP4/MOV (ISO BMFF / QuickTime) の moov/mvhd ボックスから作成日時を読む
+///
+/// ffmpeg の `creation_time` タグと同じ値 (UTC) を、`timezone` (None ならシステムのローカルタイムゾーン) の
+/// 時刻に直して返す。先頭が MP4/MOV のボックスでないファイル、作成日時が 0 (未設定) の場合は None。
+fn video_creation_time(path: &Path, timezone: Option<Tz>) -> Option<DateTime<FixedOffset>> {
+    let file = File::open(path).ok()?;
+    let file_len = file.metadata().ok()?.len();
+    let mut reader = BufReader::new(file);
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header).ok()?;
+    if !matches!(&header[4..], b"ftyp" | b"moov" | b"wide" | b"free" | b"skip" | b"mdat") {
+        return None;
+    }
+    let seconds = match mvhd_creation_time(&mut reader, file_len) {
+        Ok(Some(seconds)) if seconds > 0 => seconds,
+        Ok(_) => return None,
+        Err(e) => {
+            verbose!("  Could not read the video creation time from {:?}: {}", path, e);
+            return None;
+        }
+    };
+    let utc = DateTime::from_timestamp(i64::try_from(seconds).ok()? - MP4_EPOCH_OFFSET_SECS, 0)?;
+    Some(match timezone {
+        Some(tz) => utc.with_timezone(&tz).fixed_offset(),
+        None => utc.with_timezone(&Local).fixed_offset(),
+    })
+}
+
+/// moov ボックスの中の mvhd から作成日時 (1904-01-01 からの秒数) を読む (moov/mvhd がなければ None)
+fn mvhd_creation_time<R: Read + Seek>(reader: &mut R, file_len: u64) -> io::Result<Option<u64>> {
+    reader.seek(io::SeekFrom::Start(0))?;
+    let Some(moov_end) = find_mp4_box(reader, file_len, b"moov")? else {
+        return Ok(None);
+    };
+    if find_mp4_box(reader, moov_end, b"mvhd")?.is_none() {
+        return Ok(None);
+    }
+    // version (1バイト) と flags (3バイト) の後、version 1 は 64 ビット、それ以外は 32 ビットの作成日時
+    let mut version_flags = [0u8; 4];
+    reader.read_exact(&mut version_flags)?;
+    if version_flags[0] == 1 {
+        let mut creation_time = [0u8; 8];
+        reader.read_exact(&mut creation_time)?;
+        Ok(Some(u64::from_be_bytes(creation_time)))
+    } else {
+        let mut creation_time = [0u8; 4];
+        reader.read_exact(&mut creation_time)?;
+        Ok(Some(u64::from(u32::from_be_bytes(creation_time))))
+    }
+}
+
+/// 現在位置から `end` までのボックスを順に見て、`box_type` のボックスの中身の先頭に移動する
+///
+/// 見つかればそのボックスの終端の位置を返す (見つからなければ None)。
+fn find_mp4_box<R: Read + Seek>(reader: &mut R, end: u64, box_type: &[u8; 4]) -> io::Result<Option<u64>> {
+    loop {
+        let start = reader.stream_position()?;
+        if start + 8 > end {
+            return Ok(None);
+        }
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header)?;
+        let mut header_len = 8;
+        // サイズ 1 は直後の 64 ビットが実際のサイズ、0 は外側の終端まで
+        let size = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+            0 => end - start,
+            1 => {
+                let mut large_size = [0u8; 8];
+                reader.read_exact(&mut large_size)?;
+                header_len += 8;
+                u64::from_be_bytes(large_size)
+            }
+            size => u64::from(size),
+        };
+        if size < header_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed MP4 box"));
+        }
+        let box_end = start.saturating_add(size).min(end);
+        if &header[4..] == box_type {
+            return Ok(Some(box_end));
+        }
+        reader.seek(io::SeekFrom::Start(box_end))?;
+    }
+}
+
+fn write_app1(output: &mut Vec<u8>, app1: &[u8]) {
+    output.extend_from_slice(&[0xFF, 0xE1]);
+    output.extend_from_slice(&((app1.len() + 2) as u16).to_be_bytes());
+    output.extend_from_slice(app1);
+}
+
+/// EXIF情報からメタデータ (日付, メーカー, モデル) を抽出する
+///
+/// オフセットの記録がない日時は `timezone` (None ならシステムのローカルタイムゾーン) の時刻として解釈する。
+fn extract_exif_metadata(file_path: &Path, timezone: Option<Tz>) -> Metadata {
+    let mut metadata = Metadata::default();
+
+    let file = match File::open(file_path) {
+        Ok(f) => f,
+        Err(e) => {
+            error!("  Error opening file for EXIF reading {:?}: {}", file_path, e);
+            return metadata;
+        }
+    };
+    let mut bufreader = BufReader::new(&file);
+    // read_from_container は JPEG / TIFF / PNG (eXIf チャンク) / HEIF / WebP に対応している。
+    // スキャナーソフトが書いた TIFF などは壊れたフィールドを含むことが多く、厳密に読むと
+    // 1件のエラーで全体が失敗するため、読めたフィールドだけでも使う
+    let exifreader = match exif::Reader::new()
+        .continue_on_error(true)
+        .read_from_container(&mut bufreader)
+        .or_else(|e| {
+            e.distill_partial_result(|errors| {
+                for e in errors {
+                    verbose!("  Ignoring malformed EXIF field in {:?}: {}", file_path, e);
+                }
+            })
+        }) {
+        Ok(r) => r,
+        Err(e) => {
+            verbose!("  No EXIF data read from {:?}: {}", file_path, e);
+            return metadata;
+        }
+    };
+
+    // 日付 (EXIF_DATE_TAGS の優先順で最初に見つかったもの)
+    if let Some((datetime_str, offset_str, subsec_str, source)) =
+        EXIF_DATE_TAGS.iter().find_map(|&(date_tag, offset_tag, subsec_tag, source)| {
+            let datetime_str = exif_ascii_value(&exifreader, date_tag)?;
+            Some((
+                datetime_str,
+                exif_ascii_value(&exifreader, offset_tag),
+                exif_ascii_value(&exifreader, subsec_tag),
+                source,
+            ))
+        })
+    {
+        metadata.datetime_original =
+            parse_exif_datetime(&datetime_str, offset_str.as_deref(), subsec_str.as_deref(), timezone);
+        metadata.datetime_source = metadata.datetime_original.map(|_| source);
+    }
+
+    // メーカー (Make)
+    if let Some(field) = exifreader.get_field(exif::Tag::Make, exif::In::PRIMARY) {
+        metadata.camera_make = Some(field.display_value().to_string());
+    }
+
+    // モデル (Model)
+    if let Some(field) = exifreader.get_field(exif::Tag::Model, exif::In::PRIMARY) {
+         metadata.camera_model = Some(field.display_value().to_string());
+    }
+
+    // シリアル番号 (同じ機種のボディやレンズを区別する)
+    metadata.body_serial = exif_text_value(&exifreader, exif::Tag::BodySerialNumber);
+    metadata.lens_serial = exif_text_value(&exifreader, exif::Tag::LensSerialNumber);
+
+    // 焦点距離 (FocalLength は有理数、FocalLengthIn35mmFilm は整数。0 は不明を表す)
+    metadata.focal_length = exif_rational_value(&exifreader, exif::Tag::FocalLength).filter(|&mm| mm > 0.0);
+    metadata.focal_length_35mm = exifreader
+        .get_field(exif::Tag::FocalLengthIn35mmFilm, exif::In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+        .filter(|&mm| mm > 0);
+
+    // 露出プログラム (ExposureProgram)
+    if let Some(field) = exifreader.get_field(exif::Tag::ExposureProgram, exif::In::PRIMARY) {
+        metadata.exposure_program = Some(field.display_value().to_string());
+    }
+
+    // ホワイトバランス (WhiteBalance) とフラッシュ発光 (Flash の bit 0)
+    if let Some(field) = exifreader.get_field(exif::Tag::WhiteBalance, exif::In::PRIMARY) {
+        metadata.white_balance = Some(field.display_value().to_string());
+    }
+    metadata.flash_fired = exifreader
+        .get_field(exif::Tag::Flash, exif::In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+        .map(|flash| flash & 0x1 != 0);
+
+    // 色空間 (ColorSpace)。Adobe RGB は ColorSpace = Uncalibrated と相互運用性 IFD の "R03" で表される
+    if let Some(field) = exifreader.get_field(exif::Tag::ColorSpace, exif::In::PRIMARY) {
+        metadata.color_space = match field.value.get_uint(0) {
+            Some(1) => Some("sRGB".to_string()),
+            Some(0xFFFF)
+                if exif_ascii_value(&exifreader, exif::Tag::InteroperabilityIndex).as_deref() == Some("R03") =>
+            {
+                Some("Adobe RGB".to_string())
+            }
+            _ => Some(field.display_value().to_string()),
+        };
+    }
+    // TIFF 系 (TIFF/DNG など) は ICC プロファイルを InterColorProfile タグに持つ
+    // (他の形式はタグがなくても埋め込みがないとは限らないので、見つかった場合だけ設定する)
+    if exifreader
+        .get_field(exif::Tag(exif::Context::Tiff, TIFF_TAG_INTER_COLOR_PROFILE), exif::In::PRIMARY)
+        .is_some()
+    {
+        metadata.has_icc_profile = Some(true);
+    }
+
+    // 位置情報 (GPS IFD)
+    metadata.gps_latitude =
+        exif_gps_coordinate(&exifreader, exif::Tag::GPSLatitude, exif::Tag::GPSLatitudeRef, "S");
+    metadata.gps_longitude =
+        exif_gps_coordinate(&exifreader, exif::Tag::GPSLongitude, exif::Tag::GPSLongitudeRef, "W");
+    // GPSAltitudeRef は 0 = 海抜、1 = 海面下
+    metadata.gps_altitude = exif_rational_value(&exifreader, exif::Tag::GPSAltitude).map(|altitude| {
+        let below_sea_level = exifreader
+            .get_field(exif::Tag::GPSAltitudeRef, exif::In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0))
+            == Some(1);
+        if below_sea_level { -altitude } else { altitude }
+    });
+    metadata.gps_direction = exif_rational_value(&exifreader, exif::Tag::GPSImgDirection)
+        .filter(|degrees| (0.0..=360.0).contains(degrees));
+
+    // 権利情報
+    metadata.artist = exif_text_value(&exifreader, exif::Tag::Artist);
+    metadata.copyright = exif_text_value(&exifreader, exif::Tag::Copyright);
+
+    // TODO: 他のメタデータも同様に抽出
+
+    metadata
+}
+
+/// 同じベース名の XMP サイドカー (IMG_0001.xmp または IMG_0001.ARW.xmp) から
+/// レーティング (xmp:Rating) とキーワード (dc:subject) を読み取り、メタデータに反映する
+fn extract_xmp_sidecar(source_path: &Path, metadata: &mut Metadata) {
+    let Some(sidecar_path) = find_xmp_sidecar(source_path) else {
+        return;
+    };
+
+    let xml = match fs::read_to_string(&sidecar_path) {
+        Ok(xml) => xml,
+        Err(e) => {
+            error!("  Error reading XMP sidecar {:?}: {}", sidecar_path, e);
+            return;
+        }
+    };
+    info!("  Reading XMP sidecar: {:?}", sidecar_path);
+
+    // xmp:Rating は属性 (xmp:Rating="3") と要素 (<xmp:Rating>3</xmp:Rating>) の両方の書き方がある
+    // -1 (リジェクト) など 0-5 以外の値は無視する
+    metadata.rating = xml_attribute(&xml, "xmp:Rating")
+        .or_else(|| xml_element_text(&xml, "xmp:Rating"))
+        .and_then(|value| value.trim().parse::<u8>().ok())
+        .filter(|rating| *rating <= 5);
+
+    // dc:subject は rdf:Bag 内の rdf:li の並び
+    if let Some(subject) = xml_element_text(&xml, "dc:subject") {
+        metadata.keywords = xml_elements_text(subject, "rdf:li")
+            .into_iter()
+            .map(|keyword| decode_xml_entities(keyword.trim()))
+            .filter(|keyword| !keyword.is_empty())
+            .collect();
+    }
+}
+
+/// サイドカーファイルを探す (拡張子の大文字・小文字は問わない)
+fn find_xmp_sidecar(source_path: &Path) -> Option<PathBuf> {
+    let file_name = source_path.file_name()?.to_str()?;
+    let stem = source_path.file_stem()?.to_str()?;
+    [stem, file_name]
+        .iter()
+        .flat_map(|base| [format!("{}.xmp", base), format!("{}.XMP", base)])
+        .map(|name| source_path.with_file_name(name))
+        .find(|candidate| candidate != source_path && candidate.is_file())
+}
+
+/// `name="value"` 形式の属性値を取得する
+fn xml_attribute<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("{}=\"", name))? + name.len() + 2;
+    let len = xml[start..].find('"')?;
+    Some(&xml[start..start + len])
+}
+
+/// 最初の `<name ...>text</name>` の中身を取得する
+fn xml_element_text<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    xml_elements_text(xml, name).into_iter().next()
+}
+
+/// すべての `<name ...>text</name>` の中身を取得する
+fn xml_elements_text<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let open_tag = format!("<{}", name);
+    let close_tag = format!("</{}>", name);
+    let mut texts = Vec::new();
+    let mut rest = xml;
+
+    while let Some(pos) = rest.find(&open_tag) {
+        let after_name = &rest[pos + open_tag.len()..];
+        // <rdf:li> と <rdf:liX> のような前方一致を区別する
+        if !after_name.starts_with(|c: char| c == '>' || c.is_whitespace()) {
+            rest = after_name;
+            continue;
+        }
+        let Some(tag_end) = after_name.find('>') else { break };
+        let body = &after_name[tag_end + 1..];
+        let Some(close) = body.find(&close_tag) else { break };
+        texts.push(&body[..close]);
+        rest = &body[close + close_tag.len()..];
+    }
+    texts
+}
+
+/// 基本的なXMLエンティティをデコードする
+fn decode_xml_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// EXIFのASCII値を文字列として取得する (PRIMARY IFD)
+fn exif_ascii_value(exif: &exif::Exif, tag: exif::Tag) -> Option<String> {
+    let field = exif.get_field(tag, exif::In::PRIMARY)?;
+    match field.value {
+        exif::Value::Ascii(ref vec) => vec
+            .first()
+            .and_then(|bytes| std::str::from_utf8(bytes).ok())
+            .map(|s| s.to_string()),
+        _ => None,
+    }
+}
+
+/// EXIFのASCIIタグを前後の空白を除いた文字列で取得する (空文字列は None)
+/// Copyright のように複数の文字列 (撮影者/編集者) を持つ場合は " / " で連結する
+fn exif_text_value(exif: &exif::Exif, tag: exif::Tag) -> Option<String> {
+    let field = exif.get_field(tag, exif::In::PRIMARY)?;
+    let exif::Value::Ascii(ref vec) = field.value else {
+        return None;
+    };
+    let parts: Vec<String> = vec
+        .iter()
+        .map(|bytes| String::from_utf8_lossy(bytes).trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join(" / "))
+    }
+}
+
+/// EXIFの有理数タグの値を f64 で取得する (分母が0の場合は None)
+fn exif_rational_value(exif: &exif::Exif, tag: exif::Tag) -> Option<f64> {
+    let field = exif.get_field(tag, exif::In::PRIMARY)?;
+    match field.value {
+        exif::Value::Rational(ref vec) => vec
+            .first()
+            .filter(|r| r.denom != 0)
+            .map(|r| r.to_f64()),
+        _ => None,
+    }
+}
+
+/// GPS の緯度・経度 (度, 分, 秒の3つの有理数) を10進の度で取得する
+/// 参照タグが `negative_ref` ("S" / "W") の場合は負の値にする
+fn exif_gps_coordinate(
+    exif: &exif::Exif,
+    tag: exif::Tag,
+    ref_tag: exif::Tag,
+    negative_ref: &str,
+) -> Option<f64> {
+    let field = exif.get_field(tag, exif::In::PRIMARY)?;
+    let exif::Value::Rational(ref parts) = field.value else {
+        return None;
+    };
+    if parts.is_empty() || parts.iter().any(|r| r.denom == 0) {
+        return None;
+    }
+    let degrees = parts
+        .iter()
+        .take(3)
+        .zip([1.0, 60.0, 3600.0])
+        .map(|(r, divisor)| r.to_f64() / divisor)
+        .sum::<f64>();
+
+    let negative = exif_ascii_value(exif, ref_tag).is_some_and(|r| r.trim() == negative_ref);
+    Some(if negative { -degrees } else { degrees })
+}
+
+/// EXIFの日時文字列 ("%Y:%m:%d %H:%M:%S") を解析する
+/// `offset_str` (例: "+09:00") があればその固定オフセットで、なければローカルタイムとして解釈する
+/// `subsec_str` (例: "123") があれば小数秒として反映する
+fn parse_exif_datetime(
+    datetime_str: &str,
+    offset_str: Option<&str>,
+    subsec_str: Option<&str>,
+    timezone: Option<Tz>,
+) -> Option<DateTime<FixedOffset>> {
+    let mut naive_dt = match NaiveDateTime::parse_from_str(datetime_str.trim(), "%Y:%m:%d %H:%M:%S") {
+        Ok(dt) => dt,
+        Err(_) => {
+            error!("  Failed to parse EXIF datetime string: '{}'", datetime_str);
+            return None;
+        }
+    };
+
+    if let Some(nanos) = subsec_str.and_then(parse_subsec_nanos) {
+        naive_dt = naive_dt.with_nanosecond(nanos).unwrap_or(naive_dt);
+    }
+
+    // タイムゾーンオフセットが記録されていればそれを優先
+    if let Some(offset) = offset_str.and_then(|s| s.trim().parse::<FixedOffset>().ok()) {
+        return offset.from_local_datetime(&naive_dt).single();
+    }
+
+    // オフセットがなければ指定のタイムゾーン、なければシステムのローカルタイムゾーンの時刻とみなす
+    let resolved = match timezone {
+        Some(tz) => resolve_local_datetime(&tz, &naive_dt).map(|dt| dt.fixed_offset()),
+        None => resolve_local_datetime(&Local, &naive_dt).map(|dt| dt.fixed_offset()),
+    };
+    match resolved {
+        Some(dt) => Some(dt),
+        None => {
+            error!("  Could not convert NaiveDateTime to Local DateTime: {}", naive_dt);
+            None
+        }
+    }
+}
+
+/// タイムゾーン上のローカル日時を一意の日時に解決する (夏時間の切り替えを考慮)
+///
+/// - 重複する時刻 (夏時間終了時): 早い方を採用
+/// - 存在しない時刻 (夏時間開始時のギャップ): 切り替え前のオフセットで解釈し、ギャップ分だけ先の時刻とする
+///   (例: America/New_York の 02:30 は 03:30 EDT になる。カメラの時計が切り替え前のままだったとみなす)
+fn resolve_local_datetime<Tz: TimeZone>(tz: &Tz, naive_dt: &NaiveDateTime) -> Option<DateTime<Tz>> {
+    match tz.from_local_datetime(naive_dt) {
+        LocalResult::Single(dt) => Some(dt),
+        // 返される順序は実装依存のため、UTC で早い方を選ぶ
+        LocalResult::Ambiguous(first, second) => Some(first.min(second)),
+        LocalResult::None => {
+            // ギャップは長くても数時間なので、1日前のオフセットを切り替え前のものとみなす
+            let before_gap = naive_dt.checked_sub_signed(chrono::Duration::days(1))?;
+            let offset = tz.offset_from_local_datetime(&before_gap).earliest()?.fix();
+            let utc = naive_dt.checked_sub_signed(chrono::Duration::seconds(offset.local_minus_utc() as i64))?;
+            let resolved = tz.from_utc_datetime(&utc);
+            verbose!("  Local time {} falls in a DST gap, using {}", naive_dt, resolved.naive_local());
+            Some(resolved)
+        }
+    }
+}
+
+/// SubSecTime の数字列をナノ秒に変換する ("5" -> 0.5秒, "123" -> 0.123秒)
+fn parse_subsec_nanos(subsec_str: &str) -> Option<u32> {
+    let digits = subsec_str.trim();
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    // ナノ秒の精度 (9桁) を超える分は切り捨てる
+    let digits = &digits[..digits.len().min(9)];
+    format!("{:0<9}", digits).parse().ok()
+}
+
+/// サムネイル生成の結果
+#[derive(Debug, Default)]
+pub struct ThumbnailOutcome {
+    /// 保存したサムネイルのパス (生成できなかった場合は None)
+    pub path: Option<PathBuf>,
+    /// デコードした元画像のサイズ (幅, 高さ)
+    pub source_dimensions: Option<(u32, u32)>,
+    /// 保存したサムネイルのサイズ (幅, 高さ)、保存できなかった場合は None
+    pub thumbnail_dimensions: Option<(u32, u32)>,
+    /// サムネイルを作れなかった原因のうち、ログだけでなく報告するエラー (RAW デコードのタイムアウト)
+    pub error: Option<ProcessorError>,
+}
+
+/// カタログ内のデータファイルからサムネイルを再生成する (既存のサムネイルは上書き)
+/// サムネイルの保存先は data_path からの相対パスを `Catalog::thumbnail_dir` 側に当てはめて決める
+pub fn regenerate_thumbnail(
+    data_file: &Path,
+    catalog: &Catalog,
+    raw: RawOptions,
+) -> ProcessorResult<ThumbnailOutcome> {
+    let relative = data_file
+        .strip_prefix(&catalog.data_path)
+        .map_err(|_| ProcessorError::InvalidPath {
+            path: data_file.to_path_buf(),
+            reason: "not inside the catalog data path".to_string(),
+        })?;
+    let thumbnail_dest_path_base = catalog.thumbnail_dir().join(relative);
+    if let Some(parent) = thumbnail_dest_path_base.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    generate_thumbnail(data_file, &thumbnail_dest_path_base, catalog, raw, &ConcurrencyLimiter::default())
+}
+
+/// サムネイルの保存パス (保存先ベースパスの拡張子を保存形式のもの (.jpg など) にしたもの)
+fn thumbnail_path_for(dest_path_base: &Path, format: ThumbnailFormat) -> PathBuf {
+    let mut thumbnail_path = dest_path_base.to_path_buf();
+    thumbnail_path.set_extension(format.extension());
+    thumbnail_path
+}
+
+/// ソースより新しいサムネイルが既にあれば、その情報を返す (なければ None)
+///
+/// サイズはヘッダーだけ読んで取得する。元画像のサイズは image クレートで読める形式のみ
+/// (RAW などは None)。
+fn up_to_date_thumbnail(
+    source_path: &Path,
+    source_meta: &fs::Metadata,
+    dest_path_base: &Path,
+    catalog: &Catalog,
+) -> Option<ThumbnailOutcome> {
+    let thumbnail_path = thumbnail_path_for(dest_path_base, catalog.thumbnail_format());
+    let thumbnail_modified = fs::metadata(&thumbnail_path).and_then(|meta| meta.modified()).ok()?;
+    if thumbnail_modified < source_meta.modified().ok()? {
+        return None;
+    }
+
+    info!("  Thumbnail is up to date, skipping: {:?}", thumbnail_path);
+    Some(ThumbnailOutcome {
+        source_dimensions: image::image_dimensions(source_path).ok(),
+        thumbnail_dimensions: image::image_dimensions(&thumbnail_path).ok(),
+        path: Some(thumbnail_path),
+        error: None,
+    })
+}
+
+/// サムネイル生成 (保存形式とクオリティ、RAW 変換コマンド、拡張子の対応は `catalog` の設定を使う)
+fn generate_thumbnail(
+    source_path: &Path,
+    dest_path_base: &Path,
+    catalog: &Catalog,
+    raw: RawOptions,
+    decode_limiter: &ConcurrencyLimiter,
+) -> ProcessorResult<ThumbnailOutcome> {
+    const THUMBNAIL_MAX_SIZE: u32 = 2048; // サムネイルの最大長辺サイズ
+
+    let format = catalog.thumbnail_format();
+    let quality = catalog.thumbnail_quality();
+    let raw_converter = catalog.raw_converter();
+    let raw_converter = raw_converter.as_ref();
+    let rendering = catalog.raw_rendering();
+
+    if catalog.skips_thumbnail(source_path) {
+        info!("  (Thumbnail disabled for this file type by skip_thumbnails: {:?})", source_path);
+        return Ok(ThumbnailOutcome::default());
+    }
+
+    // ファイルタイプに応じてデコード処理を分岐 (まずは拡張子で判定)
+    let ext = source_path.extension().and_then(|s| s.to_str()).unwrap_or("");
+    let ext_kind = MediaKind::from_extension(ext, &catalog.extensions);
+    let mut error = None;
+    let mut decoded = match ext_kind {
+        MediaKind::Video => {
+            // ffmpeg-next クレートで処理 (TODO)
+            info!("  (Video thumbnail generation needed for {})", ext);
+            return Ok(ThumbnailOutcome::default()); // 仮実装: スキップ
+        }
+        MediaKind::Unknown => None,
+        kind => keep_decode_error(
+            decode_limited(kind, source_path, raw, raw_converter, rendering, decode_limiter),
+            &mut error,
+        ),
+    };
+
+    // 拡張子が不明、またはデコードに失敗した場合は先頭バイトから形式を判定して再試行
+    if decoded.is_none() {
+        match sniff_media_kind(source_path) {
+            Some(sniffed) if !sniffed.matches_extension_kind(ext_kind) => {
+                if ext_kind == MediaKind::Unknown {
+                    info!("  Detected {} content from the file header", sniffed.label());
+                } else {
+                    warn!(
+                        "  Warning: {:?} has extension '{}' but its content looks like {}",
+                        source_path,
+                        ext,
+                        sniffed.label()
+                    );
+                }
+                if sniffed == MediaKind::Video {
+                    info!("  (Video thumbnail generation needed for {:?})", source_path);
+                    return Ok(ThumbnailOutcome {
+                        error,
+                        ..ThumbnailOutcome::default()
+                    });
+                }
+                decoded = keep_decode_error(
+                    decode_limited(sniffed, source_path, raw, raw_converter, rendering, decode_limiter),
+                    &mut error,
+                );
+            }
+            Some(_) => {}
+            None if ext_kind == MediaKind::Unknown => {
+                info!("  (Skipping thumbnail for unknown type: {})", ext);
+            }
+            None => {}
+        }
+    }
+
+    let Some(decoded) = decoded else {
+        return Ok(ThumbnailOutcome {
+            error,
+            ..ThumbnailOutcome::default()
+        });
+    };
+
+    // リサイズ前に元画像のサイズを記録
+    let source_dimensions = Some((decoded.width(), decoded.height()));
+
+    // リサイズ (拡大防止機能付き)
+    let thumbnail = resize_without_upscaling(decoded, THUMBNAIL_MAX_SIZE, catalog.thumbnail_filter());
+
+    // 保存パス (.jpg / .avif)
+    let thumbnail_path = thumbnail_path_for(dest_path_base, format);
+
+    // カタログ設定の形式で保存 (クオリティ指定)
+    let (path, thumbnail_dimensions) = match save_thumbnail(&thumbnail, &thumbnail_path, format, quality) {
+        Ok(_) => (Some(thumbnail_path), Some((thumbnail.width(), thumbnail.height()))),
+        Err(e) => {
+            error!("  Error saving thumbnail {:?}: {}", thumbnail_path, e);
+            (None, None)
+        }
+    };
+
+    Ok(ThumbnailOutcome {
+        path,
+        source_dimensions,
+        thumbnail_dimensions,
+        error: None,
+    })
+}
+
+/// 代替サムネイルの一辺の長さ
+const PLACEHOLDER_SIZE: u32 = 256;
+
+/// サムネイルを作れなかったファイルの代わりに、拡張子 (大文字、最大5文字) を描いた灰色のタイルを保存する
+///
+/// 保存に失敗した場合はエラーを出して、サムネイルなしの結果を返す。
+/// 保存した代替画像はソースより新しいので、次回のインポートでは再利用される
+/// (デコードできるようになった場合は `--overwrite-thumbnails` か reindex-thumbnails で作り直す)。
+fn placeholder_thumbnail(source_path: &Path, dest_path_base: &Path, catalog: &Catalog) -> ThumbnailOutcome {
+    const BACKGROUND: image::Rgb<u8> = image::Rgb([0x60, 0x60, 0x60]);
+    const FOREGROUND: image::Rgb<u8> = image::Rgb([0xE0, 0xE0, 0xE0]);
+    const MARGIN: u32 = 24;
+
+    let label: String = source_path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_uppercase())
+        .filter(|ext| !ext.is_empty())
+        .unwrap_or_else(|| "FILE".to_string())
+        .chars()
+        .take(5)
+        .collect();
+
+    // 文字は 5x7 のドットを整数倍に拡大して、中央に 1 ドットの間隔で並べる
+    let mut tile = image::RgbImage::from_pixel(PLACEHOLDER_SIZE, PLACEHOLDER_SIZE, BACKGROUND);
+    let columns = label.chars().count() as u32 * 6 - 1;
+    let scale = ((PLACEHOLDER_SIZE - 2 * MARGIN) / columns).min(12);
+    let left = (PLACEHOLDER_SIZE - columns * scale) / 2;
+    let top = (PLACEHOLDER_SIZE - 7 * scale) / 2;
+    for (index, c) in label.chars().enumerate() {
+        let glyph_left = left + index as u32 * 6 * scale;
+        for (row, bits) in placeholder_glyph(c).iter().enumerate() {
+            for column in 0..5u32 {
+                if bits & (0x10 >> column) == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        let x = glyph_left + column * scale + dx;
+                        let y = top + row as u32 * scale + dy;
+                        tile.put_pixel(x, y, FOREGROUND);
+                    }
+                }
+            }
+        }
+    }
+
+    let format = catalog.thumbnail_format();
+    let thumbnail_path = thumbnail_path_for(dest_path_base, format);
+    match save_thumbnail(&DynamicImage::ImageRgb8(tile), &thumbnail_path, format, catalog.thumbnail_quality()) {
+        Ok(()) => {
+            info!("  Saved placeholder thumbnail for {:?}", source_path);
+            ThumbnailOutcome {
+                path: Some(thumbnail_path),
+                source_dimensions: None,
+                thumbnail_dimensions: Some((PLACEHOLDER_SIZE, PLACEHOLDER_SIZE)),
+                error: None,
+            }
+        }
+        Err(e) => {
+            error!("  Error saving placeholder thumbnail {:?}: {}", thumbnail_path, e);
+            ThumbnailOutcome::default()
+        }
+    }
+}
+
+/// 代替サムネイルに描く文字の 5x7 ドットのグリフ (各行の下位 5 ビット、左が上位)
+///
+/// 拡張子を描くだけなのでフォントファイルは同梱せず、英数字だけを持つ。それ以外の文字は '?' で描く。
+fn placeholder_glyph(c: char) -> [u8; 7] {
+    match c {
+        'A' => [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x1E],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
+
+/// `--scan-only` 用に、コピーやデコードをせずに調べたファイルの種類と撮影日時
+#[derive(Debug)]
+pub struct FilePreview {
+    /// ファイルの種類 ("image" / "raw" / "heic" / "video" / "unknown")
+    pub category: &'static str,
+    /// EXIF の撮影日時 (読めなければ None)
+    pub datetime_original: Option<DateTime<FixedOffset>>,
+}
+
+/// ファイルの種類 (拡張子、判定できなければ先頭バイト) と EXIF (動画は mvhd) の撮影日時だけを調べる
+pub fn preview_file(path: &Path, extensions: &MediaExtensions, timezone: Option<Tz>) -> FilePreview {
+    FilePreview {
+        category: MediaKind::detect(path, extensions).category(),
+        datetime_original: extract_exif_metadata(path, timezone)
+            .datetime_original
+            .or_else(|| video_creation_time(path, timezone)),
+    }
+}
+
+/// サムネイル生成の際に使うデコーダーの種類
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MediaKind {
+    /// image クレートで扱える形式
+    Image(ImageFormat),
+    Raw,
+    Heic,
+    Video,
+    Unknown,
+}
+
+/// image クレートが扱えない拡張子の組み込みの対応 (小文字)
+const BUILTIN_EXTENSIONS: &[(&str, MediaKind)] = &[
+    // RAW (libraw で現像)
+    ("nef", MediaKind::Raw),
+    ("nrw", MediaKind::Raw),
+    ("cr2", MediaKind::Raw),
+    ("cr3", MediaKind::Raw),
+    ("arw", MediaKind::Raw),
+    ("srf", MediaKind::Raw),
+    ("sr2", MediaKind::Raw),
+    ("dng", MediaKind::Raw),
+    ("rw2", MediaKind::Raw),
+    ("raf", MediaKind::Raw),
+    ("orf", MediaKind::Raw),
+    ("pef", MediaKind::Raw),
+    ("srw", MediaKind::Raw),
+    ("3fr", MediaKind::Raw),
+    ("iiq", MediaKind::Raw),
+    ("rwl", MediaKind::Raw),
+    ("x3f", MediaKind::Raw),
+    // HEIC/HEIF
+    ("heic", MediaKind::Heic),
+    ("heif", MediaKind::Heic),
+    ("hif", MediaKind::Heic),
+    // 動画
+    ("mov", MediaKind::Video),
+    ("mp4", MediaKind::Video),
+    ("m4v", MediaKind::Video),
+    ("avi", MediaKind::Video),
+    ("mts", MediaKind::Video),
+    ("m2ts", MediaKind::Video),
+    ("3gp", MediaKind::Video),
+];
+
+impl MediaKind {
+    /// 拡張子からデコーダーの種類を決める
+    /// 設定で追加した拡張子 → image クレートの対応形式 → `BUILTIN_EXTENSIONS` の順に探す
+    fn from_extension(ext: &str, extensions: &MediaExtensions) -> Self {
+        let ext = ext.to_lowercase();
+        let configured = |list: &[String]| {
+            list.iter()
+                .any(|configured| configured.trim_start_matches('.').eq_ignore_ascii_case(&ext))
+        };
+        if configured(&extensions.raw) {
+            return MediaKind::Raw;
+        }
+        if configured(&extensions.heic) {
+            return MediaKind::Heic;
+        }
+        if configured(&extensions.video) {
+            return MediaKind::Video;
+        }
+
+        if let Some(format) = ImageFormat::from_extension(&ext) {
+            return MediaKind::Image(format);
+        }
+        BUILTIN_EXTENSIONS
+            .iter()
+            .find(|(builtin, _)| *builtin == ext)
+            .map_or(MediaKind::Unknown, |&(_, kind)| kind)
+    }
+
+    /// 拡張子で判定し、判定できなければ先頭バイトで判定する
+    fn detect(path: &Path, extensions: &MediaExtensions) -> Self {
+        let ext = path.extension().and_then(|s| s.to_str()).unwrap_or("");
+        match MediaKind::from_extension(ext, extensions) {
+            MediaKind::Unknown => sniff_media_kind(path).unwrap_or(MediaKind::Unknown),
+            kind => kind,
+        }
+    }
+
+    /// 判定結果が拡張子からの判定と食い違っていないか (TIFF コンテナの RAW は一致とみなす)
+    fn matches_extension_kind(self, ext_kind: MediaKind) -> bool {
+        self == ext_kind || (self == MediaKind::Image(ImageFormat::Tiff) && ext_kind == MediaKind::Raw)
+    }
+
+    /// 大まかな種類の名前 (`--scan-only` の表示用)
+    fn category(self) -> &'static str {
+        match self {
+            MediaKind::Image(_) => "image",
+            MediaKind::Raw => "raw",
+            MediaKind::Heic => "heic",
+            MediaKind::Video => "video",
+            MediaKind::Unknown => "unknown",
+        }
+    }
+
+    /// DB の media_kind カラムに保存する名前
+    fn column_value(self) -> &'static str {
+        match self {
+            MediaKind::Image(_) => "photo",
+            MediaKind::Raw => "raw",
+            MediaKind::Heic => "heic",
+            MediaKind::Video => "video",
+            MediaKind::Unknown => "other",
+        }
+    }
+
+    fn label(&self) -> String {
+        match self {
+            MediaKind::Image(format) => format!("{:?}", format),
+            MediaKind::Raw => "RAW".to_string(),
+            MediaKind::Heic => "HEIC".to_string(),
+            MediaKind::Video => "video".to_string(),
+            MediaKind::Unknown => "unknown".to_string(),
+        }
+    }
+}
+
+/// 先頭バイト (マジックナンバー) からファイル形式を判定する
+///
+/// TIFF 形式のコンテナは NEF/DNG/ARW などの RAW と区別できないため、
+/// CR2 以外は TIFF として扱う (RAW は TIFF デコード失敗時に埋め込みプレビュー等で処理される)
+fn sniff_media_kind(path: &Path) -> Option<MediaKind> {
+    let mut header = [0u8; 16];
+    let read = File::open(path).and_then(|mut file| file.read(&mut header)).ok()?;
+    let header = &header[..read];
+
+    let kind = match header {
+        [0xFF, 0xD8, 0xFF, ..] => MediaKind::Image(ImageFormat::Jpeg),
+        [0x89, b'P', b'N', b'G', ..] => MediaKind::Image(ImageFormat::Png),
+        [b'G', b'I', b'F', b'8', ..] => MediaKind::Image(ImageFormat::Gif),
+        [b'B', b'M', ..] => MediaKind::Image(ImageFormat::Bmp),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => MediaKind::Image(ImageFormat::WebP),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'A', b'V', b'I', b' ', ..] => MediaKind::Video,
+        [b'I', b'I', 0x2A, 0x00, _, _, _, _, b'C', b'R', ..] => MediaKind::Raw,
+        [b'I', b'I', 0x2A, 0x00, ..] | [b'M', b'M', 0x00, 0x2A, ..] => MediaKind::Image(ImageFormat::Tiff),
+        // ISO BMFF (HEIC/AVIF/MP4/MOV): 4バイト目から "ftyp" + ブランド
+        [_, _, _, _, b'f', b't', b'y', b'p', brand @ ..] if brand.len() >= 4 => match &brand[..4] {
+            b"heic" | b"heix" | b"hevc" | b"hevx" | b"mif1" | b"msf1" => MediaKind::Heic,
+            b"avif" | b"avis" => MediaKind::Image(ImageFormat::Avif),
+            _ => MediaKind::Video,
+        },
+        _ => return None,
+    };
+    Some(kind)
+}
+
+/// デコード結果の画像を返し、報告するエラーは `error` に残す
+fn keep_decode_error(
+    result: ProcessorResult<Option<DynamicImage>>,
+    error: &mut Option<ProcessorError>,
+) -> Option<DynamicImage> {
+    match result {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            *error = Some(e);
+            None
+        }
+    }
+}
+
+/// [`decode_media`] と同じだが、CPU 負荷の高い種類 (RAW/HEIC/動画) は `limiter` の枠内で実行する
+fn decode_limited(
+    kind: MediaKind,
+    path: &Path,
+    raw: RawOptions,
+    raw_converter: Option<&RawConverter>,
+    rendering: RawRendering,
+    limiter: &ConcurrencyLimiter,
+) -> ProcessorResult<Option<DynamicImage>> {
+    let _slot = matches!(kind, MediaKind::Raw | MediaKind::Heic | MediaKind::Video).then(|| limiter.acquire());
+    decode_media(kind, path, raw, raw_converter, rendering)
+}
+
+/// 指定した種類のデコーダーで画像を読み込む (失敗時はログを出して None)
+///
+/// RAW デコードのタイムアウトは、ファイルを取り込んだ上でインポート結果に報告するため `Err` で返す。
+fn decode_media(
+    kind: MediaKind,
+    source_path: &Path,
+    raw: RawOptions,
+    raw_converter: Option<&RawConverter>,
+    rendering: RawRendering,
+) -> ProcessorResult<Option<DynamicImage>> {
+    let result = match kind {
+        MediaKind::Image(format) => {
+            // image クレートで処理可能なフォーマットの場合
+            info!("  Generating image thumbnail for {:?} ({:?})", source_path, format);
+            let decoded = ImageReader::open(source_path).map_err(ProcessorError::from).and_then(|mut reader| {
+                reader.set_format(format);
+                decode_with_pixel_limit(reader, raw.max_pixels)
+            });
+            // TIFF コンテナの RAW (NEF/DNG など) は RAW として再試行する (大きすぎる画像は除く)
+            let over_limit = matches!(
+                decoded,
+                Err(ProcessorError::TooManyPixels { .. } | ProcessorError::ImageDecode(image::ImageError::Limits(_)))
+            );
+            match decoded {
+                Err(e) if format == ImageFormat::Tiff && !over_limit => {
+                    info!("  TIFF decoding failed ({}), trying RAW decoder...", e);
+                    decode_raw_image(source_path, raw, raw_converter, rendering)
+                }
+                other => other.map(Some),
+            }
+        }
+        MediaKind::Raw => {
+            // RAWファイル処理
+            info!("  Processing RAW file: {:?}", source_path);
+            decode_raw_image(source_path, raw, raw_converter, rendering)
+        }
+        MediaKind::Heic => {
+            // HEIC/HEIF処理
+            info!("  Processing HEIC/HEIF file: {:?}", source_path);
+            decode_heic_image(source_path, raw.max_pixels)
+        }
+        MediaKind::Video | MediaKind::Unknown => return Ok(None),
+    };
+
+    match result {
+        Ok(Some(img)) => Ok(Some(img)),
+        Ok(None) => {
+            info!("  Could not generate thumbnail from {} file {:?}", kind.label(), source_path);
+            Ok(None)
+        }
+        Err(e) => {
+            // エラーの場合はサムネイル生成をスキップ (エラーログは出す)
+            error!("  Error processing {} file {:?}: {}", kind.label(), source_path, e);
+            match e {
+                ProcessorError::RawTimeout(_) => Err(e),
+                _ => Ok(None),
+            }
+        }
     }
+}
 
-    // モデル (Model)
-    if let Some(field) = exifreader.get_field(exif::Tag::Model, exif::In::PRIMARY) {
-         metadata.camera_model = Some(field.display_value().to_string());
+/// `--thumbnail-max-pixels` の既定値 (2億5000万画素。中判カメラやパノラマでも収まり、数GBで済む)
+pub const DEFAULT_MAX_PIXELS: u64 = 250_000_000;
+
+/// 1画素あたりの最大バイト数 (image クレートのメモリ確保の上限の計算用、16bit RGBA)
+const MAX_BYTES_PER_PIXEL: u64 = 8;
+
+/// 画素数が `max_pixels` を超えていればエラーにする (0 なら確認しない)
+fn check_pixel_limit(width: u32, height: u32, max_pixels: u64) -> ProcessorResult<()> {
+    if max_pixels > 0 && u64::from(width) * u64::from(height) > max_pixels {
+        return Err(ProcessorError::TooManyPixels {
+            width,
+            height,
+            max_pixels,
+        });
     }
+    Ok(())
+}
 
-    // TODO: 他のメタデータも同様に抽出
+/// ヘッダーの寸法を確かめてから画像をデコードする
+///
+/// 壊れたヘッダーが巨大な寸法を示していても、画素を読み込む前にエラーにする。
+/// `max_pixels` が 0 なら image クレートの既定の制限 (`Limits::default`) のまま読み込む。
+fn decode_with_pixel_limit<R: io::BufRead + io::Seek>(
+    mut reader: ImageReader<R>,
+    max_pixels: u64,
+) -> ProcessorResult<DynamicImage> {
+    let mut limits = image::Limits::default();
+    if max_pixels > 0 {
+        limits.max_alloc = Some(max_pixels.saturating_mul(MAX_BYTES_PER_PIXEL));
+    }
+    reader.limits(limits.clone());
+    let decoder = reader.into_decoder()?;
+    let (width, height) = decoder.dimensions();
+    check_pixel_limit(width, height, max_pixels)?;
+    // into_decoder は出力バッファの分を上限と比べないので、`ImageReader::decode` と同じく確保前に確かめる
+    limits.reserve(decoder.total_bytes())?;
+    Ok(DynamicImage::from_decoder(decoder)?)
+}
 
-    metadata
+/// 形式を内容から判定してファイルを読み込む (外部コマンドの変換結果用、`max_pixels` は [`decode_with_pixel_limit`] と同じ)
+fn open_with_pixel_limit(path: &Path, max_pixels: u64) -> ProcessorResult<DynamicImage> {
+    let reader = ImageReader::open(path)?.with_guessed_format()?;
+    decode_with_pixel_limit(reader, max_pixels)
 }
 
-/// サムネイル生成
-fn generate_thumbnail(
-    source_path: &Path,
-    dest_path_base: &Path,
-) -> ProcessorResult<Option<PathBuf>> {
-    const THUMBNAIL_MAX_SIZE: u32 = 2048; // サムネイルの最大長辺サイズ
-    const THUMBNAIL_QUALITY: u8 = 6; // デフォルトのJPEGクオリティ (1-10, 10が最高画質)
+/// RAWファイルをデコードするヘルパー関数 (リサイズ前のフルサイズ画像を返す)
+///
+/// libraw → 埋め込みプレビュー → 外部変換コマンド (`raw_converter`) の順に試す。
+/// `prefer_embedded_preview` 指定時は埋め込みプレビューを libraw より先に試す。
+/// `timeout` を指定した場合、libraw の処理がその時間内に終わらなければ見切りをつけて
+/// 埋め込みプレビューにフォールバックする (破損ファイルで libraw が固まる対策)。
+/// 見切りをつけたスレッドは止められないため、終了するまでバックグラウンドに残る。
+/// libraw で現像できた場合のみ `rendering` の仕上げを適用する (プレビューや外部コマンドの結果は仕上げ済み)。
+/// `raw` フィーチャーなしでビルドした場合は libraw を使わず、埋め込みプレビューから試す。
+fn decode_raw_image(
+    raw_path: &Path,
+    raw: RawOptions,
+    raw_converter: Option<&RawConverter>,
+    rendering: RawRendering,
+) -> ProcessorResult<Option<DynamicImage>> {
+    // ファイルを読み込む (libraw-rs はバイトバッファを受け取る)
+    let file_data = Arc::new(std::fs::read(raw_path)?);
 
-    // ファイルタイプに応じて処理を分岐
-    let ext = source_path.extension().and_then(|s| s.to_str()).unwrap_or("");
-    let format = match ImageFormat::from_extension(ext) {
-        Some(fmt) => fmt,
-        None => {
-            // image クレートが拡張子からフォーマットを推測できない場合
-            match ext.to_lowercase().as_str() {
-                "nef" | "cr2" | "arw" | "dng" => {
-                    // RAWファイル処理
-                    println!("  Processing RAW file: {}", ext);
-                    match generate_raw_thumbnail(source_path, THUMBNAIL_MAX_SIZE) {
-                        Ok(Some(thumb)) => {
-                            let mut thumbnail_path = dest_path_base.to_path_buf();
-                            thumbnail_path.set_extension("jpg");
-                            match save_jpeg_thumbnail(&thumb, &thumbnail_path, THUMBNAIL_QUALITY) {
-                                Ok(_) => {
-                                    return Ok(Some(thumbnail_path));
-                                }
-                                Err(e) => {
-                                    eprintln!("  Error saving RAW thumbnail {:?}: {}", thumbnail_path, e);
-                                    return Ok(None);
-                                }
-                            }
-                        }
-                        Ok(None) => {
-                            println!("  Could not generate thumbnail from RAW file {:?}", source_path);
-                            return Ok(None);
-                        }
-                        Err(e) => {
-                            eprintln!("  Error processing RAW file {:?}: {}", source_path, e);
-                            return Ok(None);
-                        }
-                    }
-                }
-                "heic" | "heif" => {
-                    // HEIC/HEIF処理
-                    println!("  Processing HEIC/HEIF file: {}", ext);
-                    match generate_heic_thumbnail(source_path, THUMBNAIL_MAX_SIZE) {
-                        Ok(Some(thumb)) => {
-                            let mut thumbnail_path = dest_path_base.to_path_buf();
-                            thumbnail_path.set_extension("jpg");
-                            match save_jpeg_thumbnail(&thumb, &thumbnail_path, THUMBNAIL_QUALITY) {
-                                Ok(_) => {
-                                    return Ok(Some(thumbnail_path));
-                                }
-                                Err(e) => {
-                                    eprintln!("  Error saving HEIC thumbnail {:?}: {}", thumbnail_path, e);
-                                    return Ok(None);
-                                }
-                            }
-                        }
-                        Ok(None) => {
-                            println!("  Could not generate thumbnail from HEIC file {:?}", source_path);
-                            return Ok(None);
-                        }
-                        Err(e) => {
-                            eprintln!("  Error processing HEIC file {:?}: {}", source_path, e);
-                            return Ok(None);
-                        }
-                    }
-                }
-                "mov" | "mp4" | "avi" | "mts" => {
-                    // ffmpeg-next クレートで処理 (TODO)
-                    println!("  (Video thumbnail generation needed for {})", ext);
-                    return Ok(None); // 仮実装: スキップ
-                }
-                _ => {
-                    println!("  (Skipping thumbnail for unknown type: {})", ext);
-                    return Ok(None); // サポート外の形式はスキップ
-                }
+    // 高速モード (と libraw なしのビルド): 現像せずに埋め込みプレビューを使う
+    let preview_first = raw.prefer_embedded_preview || !cfg!(feature = "raw");
+    if preview_first {
+        if let Some(preview_img) = try_embedded_preview(&file_data, raw.max_pixels) {
+            return Ok(Some(preview_img));
+        }
+    }
+
+    #[cfg(feature = "raw")]
+    let timed_out = {
+        if preview_first {
+            info!("  Falling back to libraw processing...");
+        }
+        let libraw_result = match raw.timeout {
+            Some(timeout) => {
+                let data = Arc::clone(&file_data);
+                run_with_timeout(timeout, move || decode_with_libraw(&data, raw.max_pixels))
             }
+            None => Some(decode_with_libraw(&file_data, raw.max_pixels)),
+        };
+        let timed_out = libraw_result.is_none();
+        match libraw_result {
+            Some(Ok(Some(img))) => return Ok(Some(apply_raw_rendering(img, rendering))),
+            Some(Ok(None)) => {}
+            Some(Err(e)) => return Err(e),
+            None => error!(
+                "  libraw did not finish within {:?}, abandoning it for {:?}",
+                raw.timeout.unwrap_or_default(),
+                raw_path
+            ),
         }
+        timed_out
+    };
+    #[cfg(not(feature = "raw"))]
+    let timed_out = {
+        // 仕上げは libraw の現像結果にだけ適用する
+        let _ = rendering;
+        info!("  RAW decoding not compiled in (build with the `raw` feature to enable libraw)");
+        false
     };
 
-    // image クレートで処理可能なフォーマットの場合
-    println!("  Generating image thumbnail for {:?} ({:?})", source_path, format);
-    let img = match image::open(source_path) {
-        Ok(img) => img,
-        Err(e) => {
-            // エラーの場合はサムネイル生成をスキップ (エラーログは出す)
-            eprintln!("  Error opening image {:?}: {}", source_path, e);
-            return Ok(None);
+    // 2. 埋め込みプレビュー画像の抽出を試行（DNG/ARW/CR2/NEF 共通、試行済みなら飛ばす）
+    if !preview_first {
+        if let Some(preview_img) = try_embedded_preview(&file_data, raw.max_pixels) {
+            return Ok(Some(preview_img));
         }
-    };
+    }
 
-    // リサイズ (拡大防止機能付き)
-    let thumbnail = resize_without_upscaling(img, THUMBNAIL_MAX_SIZE);
+    // 3. 最終手段: 外部変換コマンドで JPEG に変換 (設定の raw_converter、macOS では sips)
+    if let Some(converter) = raw_converter {
+        info!("  Attempting RAW conversion using {}...", converter.program());
+        match convert_raw_with_command(raw_path, converter, raw.max_pixels) {
+            Ok(img) => {
+                info!("  RAW decoded via {} conversion: {}x{}",
+                        converter.program(), img.width(), img.height());
+                return Ok(Some(img));
+            }
+            Err(e4) => {
+                error!("  {} conversion error: {}", converter.program(), e4);
+            }
+        }
+    }
 
-    // 保存パス (.jpg)
-    let mut thumbnail_path = dest_path_base.to_path_buf();
-    thumbnail_path.set_extension("jpg");
+    // タイムアウトした場合は通常の「デコードできない」と区別して報告する
+    match raw.timeout {
+        Some(timeout) if timed_out => Err(ProcessorError::RawTimeout(timeout)),
+        _ if !cfg!(feature = "raw") => Err(ProcessorError::UnsupportedFormat(
+            "RAW decoding not compiled in and no embedded preview was found (build with the `raw` feature to enable libraw)"
+                .to_string(),
+        )),
+        _ => Ok(None),
+    }
+}
 
-    // JPEG形式で保存 (クオリティ指定)
-    match save_jpeg_thumbnail(&thumbnail, &thumbnail_path, THUMBNAIL_QUALITY) {
-        Ok(_) => {
-            Ok(Some(thumbnail_path))
+/// 埋め込みプレビュー画像の抽出を試す (見つからない・失敗した場合はログを出して None)
+fn try_embedded_preview(file_data: &[u8], max_pixels: u64) -> Option<DynamicImage> {
+    info!("  Attempting to extract embedded preview image...");
+    match extract_embedded_preview(file_data, max_pixels) {
+        Ok(Some(preview_img)) => {
+            info!("  RAW image taken from embedded preview: {}x{}",
+                    preview_img.width(), preview_img.height());
+            Some(preview_img)
         }
-        Err(e) => {
-            eprintln!("  Error saving image thumbnail {:?}: {}", thumbnail_path, e);
-            Ok(None)
+        Ok(None) => {
+            info!("  No embedded preview found");
+            None
+        }
+        Err(e3) => {
+            error!("  Preview extraction failed: {}", e3);
+            None
         }
     }
 }
 
-/// libraw-rs を使ってRAWファイルのサムネイルを生成するヘルパー関数
-fn generate_raw_thumbnail(
-    raw_path: &Path,
-    target_width: u32,
-) -> Result<Option<DynamicImage>, Box<dyn Error>> {
-    // ファイルを読み込む (libraw-rs はバイトバッファを受け取る)
-    let file_data = std::fs::read(raw_path)?;
-    
+/// libraw で 8bit → 16bit の順に現像する (どちらも失敗したら None)
+///
+/// libraw は現像前に寸法を教えてくれないため、`max_pixels` はまず TIFF/EXIF ヘッダーの寸法で
+/// 確かめてから現像する (現像で画像全体を確保する前に断る)。ヘッダーから寸法を読めない形式では
+/// 現像結果を image の画像に変換する前に確かめる (libraw 自身もメモリ確保の上限を持っている)。
+#[cfg(feature = "raw")]
+fn decode_with_libraw(file_data: &[u8], max_pixels: u64) -> ProcessorResult<Option<DynamicImage>> {
+    if max_pixels > 0 {
+        if let Some((width, height)) = raw_header_dimensions(file_data) {
+            check_pixel_limit(width, height, max_pixels)?;
+        }
+    }
+
     // Processorを作成してRAW画像を処理
     let processor = Processor::new();
-    
+
     // RAW画像を8ビットRGBで処理
-    println!("  Processing RAW image to RGB...");
-    let processed_image = match processor.process_8bit(&file_data) {
+    info!("  Processing RAW image to RGB...");
+    let processed_image = match processor.process_8bit(file_data) {
         Ok(img) => img,
         Err(e) => {
-            eprintln!("  Failed to process RAW file: {}", e);
-            println!("  Attempting alternative processing methods...");
-            
+            error!("  Failed to process RAW file: {}", e);
+            info!("  Attempting alternative processing methods...");
+
             // 1. 16ビット処理を試行
-            match Processor::new().process_16bit(&file_data) {
+            match Processor::new().process_16bit(file_data) {
                 Ok(img16) => {
                     // 16ビットから8ビットに変換
                     let width = img16.width();
                     let height = img16.height();
+                    check_pixel_limit(width, height, max_pixels)?;
                     let data16: &[u16] = &img16;
                     let data8: Vec<u8> = data16.iter().map(|&x| (x >> 8) as u8).collect();
-                    
+
                     if let Some(image_buffer) = image::ImageBuffer::from_raw(width, height, data8) {
-                        let dynamic_img = DynamicImage::ImageRgb8(image_buffer);
-                        let thumbnail = resize_without_upscaling(dynamic_img, target_width);
-                        println!("  RAW thumbnail generated via 16-bit fallback: {}x{} -> {}x{}", 
-                                width, height, thumbnail.width(), thumbnail.height());
-                        return Ok(Some(thumbnail));
+                        info!("  RAW image decoded via 16-bit fallback: {}x{}", width, height);
+                        return Ok(Some(DynamicImage::ImageRgb8(image_buffer)));
                     }
                 }
                 Err(e2) => {
-                    eprintln!("  16-bit processing also failed: {}", e2);
-                }
-            }
-            
-            // 2. 埋め込みプレビュー画像の抽出を試行（特にDNGファイル用）
-            println!("  Attempting to extract embedded preview image...");
-            match extract_dng_preview(raw_path) {
-                Ok(Some(preview_img)) => {
-                    let (orig_width, orig_height) = (preview_img.width(), preview_img.height());
-                    let thumbnail = resize_without_upscaling(preview_img, target_width);
-                    println!("  RAW thumbnail generated from embedded preview: {}x{} -> {}x{}", 
-                            orig_width, orig_height, thumbnail.width(), thumbnail.height());
-                    return Ok(Some(thumbnail));
-                }
-                Ok(None) => {
-                    println!("  No embedded preview found");
-                }
-                Err(e3) => {
-                    eprintln!("  Preview extraction failed: {}", e3);
+                    error!("  16-bit processing also failed: {}", e2);
                 }
             }
-            
-            // 3. 最終手段: sipsコマンドでDNGをJPEGに変換 (macOS)
-            if raw_path.extension().and_then(|s| s.to_str()).unwrap_or("").to_lowercase() == "dng" {
-                println!("  Attempting DNG conversion using sips...");
-                match convert_dng_with_sips(raw_path, target_width) {
-                    Ok(Some(thumb)) => {
-                        println!("  DNG thumbnail generated via sips conversion: {}x{}", 
-                                thumb.width(), thumb.height());
-                        return Ok(Some(thumb));
-                    }
-                    Ok(None) => {
-                        println!("  sips conversion failed");
-                    }
-                    Err(e4) => {
-                        eprintln!("  sips conversion error: {}", e4);
-                    }
-                }
-            }
-            
             return Ok(None);
         }
     };
-    
+
     let width = processed_image.width();
     let height = processed_image.height();
+    check_pixel_limit(width, height, max_pixels)?;
     let rgb_data: &[u8] = &processed_image;
-    
+
     // RGB8データからDynamicImageを作成
     // libraw-rs のProcessedImageは3チャンネル(RGB)のデータを返す
     // データサイズが期待値と一致するかチェック
     let expected_size = (width * height * 3) as usize; // RGB = 3 bytes per pixel
-    if rgb_data.len() == expected_size {
-        if let Some(image_buffer) = image::ImageBuffer::from_raw(width, height, rgb_data.to_vec()) {
-            let dynamic_img = DynamicImage::ImageRgb8(image_buffer);
-            let thumbnail = resize_without_upscaling(dynamic_img, target_width);
-            return Ok(Some(thumbnail));
+    if rgb_data.len() != expected_size {
+        return Err(ProcessorError::RawDecode(format!(
+            "RGB data size mismatch: expected {}, got {}",
+            expected_size,
+            rgb_data.len()
+        )));
+    }
+    Ok(image::ImageBuffer::from_raw(width, height, rgb_data.to_vec()).map(DynamicImage::ImageRgb8))
+}
+
+/// libraw の現像結果にガンマ・レベル補正・コントラストの仕上げを適用する
+#[cfg(feature = "raw")]
+fn apply_raw_rendering(img: DynamicImage, rendering: RawRendering) -> DynamicImage {
+    if rendering.is_neutral() {
+        return img;
+    }
+    let mut rgb = img.into_rgb8();
+
+    // 上位 1% の画素が白になる値を白点にする (全体が暗い現像結果を持ち上げる)
+    let white_point = if rendering.auto_brightness {
+        let mut histogram = [0usize; 256];
+        for pixel in rgb.pixels() {
+            histogram[usize::from(*pixel.0.iter().max().unwrap_or(&0))] += 1;
+        }
+        let clip = (rgb.pixels().len() / 100).max(1);
+        let mut above = 0;
+        (1..=255u8)
+            .rev()
+            .find(|&value| {
+                above += histogram[usize::from(value)];
+                above >= clip
+            })
+            .unwrap_or(255)
+    } else {
+        255
+    };
+
+    let lut: Vec<u8> = (0..=255u32)
+        .map(|value| {
+            let level = (value as f32 / f32::from(white_point)).min(1.0);
+            (level.powf(1.0 / rendering.gamma) * 255.0).round() as u8
+        })
+        .collect();
+    for pixel in rgb.pixels_mut() {
+        for channel in pixel.0.iter_mut() {
+            *channel = lut[usize::from(*channel)];
         }
+    }
+    info!(
+        "  Applied RAW rendering (gamma {}, white point {}, contrast {})",
+        rendering.gamma, white_point, rendering.contrast
+    );
+
+    let rendered = DynamicImage::ImageRgb8(rgb);
+    if rendering.contrast != 0.0 {
+        rendered.adjust_contrast(rendering.contrast)
     } else {
-        eprintln!("  RGB data size mismatch: expected {}, got {}", expected_size, rgb_data.len());
+        rendered
     }
-    
-    Ok(None)
 }
 
-/// HEIC/HEIFファイルのサムネイルを生成するヘルパー関数
-/// macOSのsipsコマンドを使用してHEICをJPEGに変換してからサムネイル生成
-fn generate_heic_thumbnail(
-    heic_path: &Path,
-    target_width: u32,
-) -> Result<Option<DynamicImage>, Box<dyn Error>> {
-    // 一時的な変換ファイルパス
-    let temp_dir = std::env::temp_dir();
-    let temp_file = temp_dir.join(format!("casket_temp_{}.jpg", 
-        std::process::id()));
+/// `operation` を別スレッドで実行し、`timeout` 以内に終われば結果を返す (時間切れなら None)
+#[cfg(feature = "raw")]
+fn run_with_timeout<T, F>(timeout: Duration, operation: F) -> Option<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        // 時間切れ後は受信側がいないので送信エラーは無視する
+        let _ = sender.send(operation());
+    });
+    receiver.recv_timeout(timeout).ok()
+}
+
+/// HEIC/HEIFファイルをデコードするヘルパー関数
+/// `heif` フィーチャー有効時は libheif で直接デコードし、
+/// 失敗した場合やフィーチャー無効時は macOS の sips コマンドにフォールバックする
+fn decode_heic_image(heic_path: &Path, max_pixels: u64) -> ProcessorResult<Option<DynamicImage>> {
+    #[cfg(feature = "heif")]
+    {
+        info!("  Decoding HEIC using libheif...");
+        match decode_heic_with_libheif(heic_path, max_pixels) {
+            Ok(img) => {
+                info!("  HEIC decoded via libheif: {}x{}", img.width(), img.height());
+                return Ok(Some(img));
+            }
+            // 大きすぎる画像は sips でも同じなのでフォールバックしない
+            Err(e @ ProcessorError::TooManyPixels { .. }) => return Err(e),
+            Err(e) => {
+                error!("  libheif decoding failed: {}", e);
+            }
+        }
+    }
+
+    if cfg!(target_os = "macos") {
+        decode_heic_with_sips(heic_path, max_pixels)
+    } else {
+        Err(ProcessorError::UnsupportedFormat(
+            "no HEIC decoder available (build with the `heif` feature to enable libheif)".to_string(),
+        ))
+    }
+}
+
+/// libheif-rs でHEICをRGBにデコードし、DynamicImageに変換する
+#[cfg(feature = "heif")]
+fn decode_heic_with_libheif(heic_path: &Path, max_pixels: u64) -> ProcessorResult<DynamicImage> {
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let path_str = heic_path.to_str().ok_or_else(|| ProcessorError::InvalidPath {
+        path: heic_path.to_path_buf(),
+        reason: "not valid UTF-8".to_string(),
+    })?;
+    let heif_error = |e: libheif_rs::HeifError| ProcessorError::HeicDecode(e.to_string());
+
+    let lib_heif = LibHeif::new();
+    let ctx = HeifContext::read_from_file(path_str).map_err(heif_error)?;
+    let handle = ctx.primary_image_handle().map_err(heif_error)?;
+    check_pixel_limit(handle.width(), handle.height(), max_pixels)?;
+    let image = lib_heif
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)
+        .map_err(heif_error)?;
+
+    let plane = image.planes().interleaved.ok_or_else(|| {
+        ProcessorError::HeicDecode("libheif returned no interleaved RGB plane".to_string())
+    })?;
+
+    // 行ごとのパディング (stride) を取り除いて詰め直す
+    let row_bytes = plane.width as usize * 3;
+    let mut rgb_data = Vec::with_capacity(row_bytes * plane.height as usize);
+    for row in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        rgb_data.extend_from_slice(&row[..row_bytes]);
+    }
+
+    let image_buffer = image::ImageBuffer::from_raw(plane.width, plane.height, rgb_data)
+        .ok_or_else(|| ProcessorError::HeicDecode("RGB buffer size mismatch".to_string()))?;
+    Ok(DynamicImage::ImageRgb8(image_buffer))
+}
+
+/// 変換用の一時ファイル (ドロップ時に削除される)
+/// ファイル名はプロセスID + 連番なので、並列に変換しても衝突しない
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    fn new(prefix: &str, extension: &str) -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let seq = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let file_name = format!("{}_{}_{}.{}", prefix, std::process::id(), seq, extension);
+        TempFile { path: std::env::temp_dir().join(file_name) }
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if self.path.exists() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// macOSのsipsコマンドを使用してHEICをJPEGに変換してから読み込む
+fn decode_heic_with_sips(heic_path: &Path, max_pixels: u64) -> ProcessorResult<Option<DynamicImage>> {
+    // 一時的な変換ファイル (関数を抜けるとエラー時も含めて削除される)
+    let temp_file = TempFile::new("casket_temp", "jpg");
     
-    println!("  Converting HEIC to JPEG using sips...");
+    info!("  Converting HEIC to JPEG using sips...");
     
     // sipsコマンドでHEICをJPEGに変換
     let output = Command::new("sips")
@@ -466,157 +2835,534 @@ fn generate_heic_thumbnail(
         .arg("jpeg")
         .arg(heic_path)
         .arg("--out")
-        .arg(&temp_file)
+        .arg(temp_file.path())
         .output()?;
     
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
-        eprintln!("  sips command failed: {}", stderr);
+        error!("  sips command failed: {}", stderr);
         return Ok(None);
     }
     
-    // 変換されたJPEGファイルからサムネイルを生成
-    let result = if temp_file.exists() {
-        match image::open(&temp_file) {
+    // 変換されたJPEGファイルを読み込む
+    let result = if temp_file.path().exists() {
+        match open_with_pixel_limit(temp_file.path(), max_pixels) {
             Ok(img) => {
-                let thumbnail = resize_without_upscaling(img, target_width);
-                println!("  HEIC thumbnail generated via sips conversion: {}x{}", 
-                        thumbnail.width(), thumbnail.height());
-                Some(thumbnail)
+                info!("  HEIC decoded via sips conversion: {}x{}", 
+                        img.width(), img.height());
+                Some(img)
             }
             Err(e) => {
-                eprintln!("  Error opening converted JPEG: {}", e);
+                error!("  Error opening converted JPEG: {}", e);
                 None
             }
         }
     } else {
-        eprintln!("  Converted JPEG file not found");
+        error!("  Converted JPEG file not found");
         None
     };
     
-    // 一時ファイルを削除
-    if temp_file.exists() {
-        let _ = std::fs::remove_file(&temp_file);
-    }
-    
     Ok(result)
 }
 
-/// DNG/RAWファイルから埋め込みプレビュー画像を抽出する関数
-/// EXIFメタデータを使用してプレビュー画像のオフセットと長さを取得
-fn extract_dng_preview(dng_path: &Path) -> Result<Option<DynamicImage>, Box<dyn Error>> {
-    let file = File::open(dng_path)?;
-    let mut bufreader = BufReader::new(&file);
-    
-    // EXIFデータからプレビュー情報を取得
-    let exif_reader = match exif::Reader::new().read_from_container(&mut bufreader) {
-        Ok(reader) => reader,
-        Err(_) => return Ok(None),
-    };
-    
-    // プレビュー画像の開始位置とサイズを取得（IFD1とPRIMALYの両方を試行）
-    let mut preview_start = None;
-    let mut preview_length = None;
-    
-    // IFD1を試行（一般的にDNGのプレビュー画像が格納される場所）
-    for ifd in [exif::In::THUMBNAIL, exif::In::PRIMARY] {
-        if preview_start.is_none() {
-            preview_start = exif_reader
-                .get_field(exif::Tag::JPEGInterchangeFormat, ifd)
-                .and_then(|field| field.value.get_uint(0));
-        }
-        
-        if preview_length.is_none() {
-            preview_length = exif_reader
-                .get_field(exif::Tag::JPEGInterchangeFormatLength, ifd)
-                .and_then(|field| field.value.get_uint(0));
-        }
-        
-        if preview_start.is_some() && preview_length.is_some() {
-            println!("  Found JPEG preview in {:?} IFD", ifd);
-            break;
-        }
+/// RAWファイルから埋め込みプレビュー画像を抽出する関数
+/// 1. 全IFDの JPEGInterchangeFormat / StripOffsets (JPEG圧縮) タグから候補を収集
+/// 2. メーカーノート内の PreviewImageStart 等は kamadak-exif では読めないため、
+///    ファイル中の JPEG SOI マーカーも走査して候補に加える
+///
+/// デコード可能な候補のうち最も大きい画像を返す (`max_pixels` を超える候補は使わない)
+fn extract_embedded_preview(raw_data: &[u8], max_pixels: u64) -> ProcessorResult<Option<DynamicImage>> {
+    let mut candidates = preview_candidates_from_exif(raw_data);
+    if candidates.is_empty() {
+        info!("  No preview image metadata found in EXIF");
     }
-    
-    if let (Some(start), Some(length)) = (preview_start, preview_length) {
-        println!("  Found preview image at offset {} with length {}", start, length);
-        
-        // ファイルから該当部分を読み込み
-        let mut file = File::open(dng_path)?;
-        let mut buffer = vec![0u8; length as usize];
-        
-        file.seek(std::io::SeekFrom::Start(start as u64))?;
-        file.read_exact(&mut buffer)?;
-        
-        // 画像データとして読み込み
-        match image::load_from_memory(&buffer) {
+    candidates.extend(scan_jpeg_streams(raw_data));
+
+    // 同じ開始位置の候補は1つにまとめる (EXIFタグとマーカー走査の重複)
+    candidates.sort_by_key(|&(start, _)| start);
+    candidates.dedup_by_key(|&mut (start, _)| start);
+
+    // ヘッダーだけ読んで寸法を取得し、大きい順に並べる
+    let mut sized: Vec<(u64, &[u8])> = candidates
+        .iter()
+        .filter_map(|&(start, length)| {
+            let start = usize::try_from(start).ok()?;
+            let end = start.saturating_add(usize::try_from(length).ok()?).min(raw_data.len());
+            let slice = raw_data.get(start..end)?;
+            let (width, height) = ImageReader::with_format(Cursor::new(slice), ImageFormat::Jpeg)
+                .into_dimensions()
+                .ok()?;
+            Some((width as u64 * height as u64, slice))
+        })
+        .collect();
+    sized.sort_by_key(|&(area, _)| std::cmp::Reverse(area));
+
+    for (_, slice) in sized {
+        let reader = ImageReader::with_format(Cursor::new(slice), ImageFormat::Jpeg);
+        match decode_with_pixel_limit(reader, max_pixels) {
             Ok(img) => {
-                println!("  Successfully loaded embedded preview image: {}x{}", img.width(), img.height());
+                info!("  Successfully loaded embedded preview image: {}x{}", img.width(), img.height());
                 return Ok(Some(img));
             }
             Err(e) => {
-                eprintln!("  Failed to load preview image data: {}", e);
+                error!("  Failed to load preview image data: {}", e);
             }
         }
-    } else {
-        println!("  No preview image metadata found in EXIF");
     }
-    
+
     Ok(None)
 }
 
-/// sipsコマンドを使ってDNGファイルをJPEGに変換してサムネイル生成
-fn convert_dng_with_sips(
-    dng_path: &Path,
-    target_width: u32,
-) -> Result<Option<DynamicImage>, Box<dyn Error>> {
-    // 一時的な変換ファイルパス
-    let temp_dir = std::env::temp_dir();
-    let temp_file = temp_dir.join(format!("casket_dng_temp_{}.jpg", 
-        std::process::id()));
-    
-    // sipsコマンドでDNGをJPEGに変換
-    let output = Command::new("sips")
-        .arg("-s")
-        .arg("format")
-        .arg("jpeg")
-        .arg(dng_path)
-        .arg("--out")
-        .arg(&temp_file)
-        .output()?;
-    
+#[cfg(feature = "raw")]
+/// RAW の TIFF/EXIF ヘッダーが示す最大の画像の寸法 (読めなければ None)
+///
+/// 全IFDの ImageWidth / ImageLength と EXIF の PixelXDimension / PixelYDimension のうち、
+/// 画素数が最も大きいものを現像結果の寸法の見込みとする。
+fn raw_header_dimensions(raw_data: &[u8]) -> Option<(u32, u32)> {
+    let exif_reader = exif::Reader::new().read_from_container(&mut Cursor::new(raw_data)).ok()?;
+    (0..8)
+        .flat_map(|ifd_num| {
+            [
+                (exif::Tag::ImageWidth, exif::Tag::ImageLength),
+                (exif::Tag::PixelXDimension, exif::Tag::PixelYDimension),
+            ]
+            .map(|(width_tag, height_tag)| {
+                let get_uint =
+                    |tag| exif_reader.get_field(tag, exif::In(ifd_num)).and_then(|field| field.value.get_uint(0));
+                Some((get_uint(width_tag)?, get_uint(height_tag)?))
+            })
+        })
+        .flatten()
+        .max_by_key(|&(width, height)| u64::from(width) * u64::from(height))
+}
+
+/// EXIFの全IFDからJPEGプレビューの (開始位置, 長さ) を収集する
+fn preview_candidates_from_exif(raw_data: &[u8]) -> Vec<(u64, u64)> {
+    let exif_reader = match exif::Reader::new().read_from_container(&mut Cursor::new(raw_data)) {
+        Ok(reader) => reader,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut candidates = Vec::new();
+    // kamadak-exif は IFD0〜IFD7 までのチェーンを解析する
+    for ifd_num in 0..8 {
+        let ifd = exif::In(ifd_num);
+        let get_uint = |tag| {
+            exif_reader
+                .get_field(tag, ifd)
+                .and_then(|field| field.value.get_uint(0))
+        };
+
+        // JPEGInterchangeFormat: DNG/ARW/NEF のプレビューやサムネイル
+        if let (Some(start), Some(length)) = (
+            get_uint(exif::Tag::JPEGInterchangeFormat),
+            get_uint(exif::Tag::JPEGInterchangeFormatLength),
+        ) {
+            info!("  Found JPEG preview in {:?} IFD", ifd);
+            candidates.push((start as u64, length as u64));
+        }
+
+        // StripOffsets + JPEG圧縮 (6/7): CR2 の IFD0 にあるフルサイズJPEG
+        if matches!(get_uint(exif::Tag::Compression), Some(6) | Some(7)) {
+            if let (Some(start), Some(length)) = (
+                get_uint(exif::Tag::StripOffsets),
+                get_uint(exif::Tag::StripByteCounts),
+            ) {
+                info!("  Found JPEG strip in {:?} IFD", ifd);
+                candidates.push((start as u64, length as u64));
+            }
+        }
+    }
+    candidates
+}
+
+/// ファイル中の JPEG SOI マーカー (FF D8 FF) を走査してプレビュー候補とする
+/// 長さは不明なのでファイル末尾までを候補とし、デコーダーに EOI で止めてもらう
+fn scan_jpeg_streams(raw_data: &[u8]) -> Vec<(u64, u64)> {
+    const MAX_CANDIDATES: usize = 64;
+
+    let total = raw_data.len() as u64;
+    raw_data
+        .windows(3)
+        .enumerate()
+        .filter(|(_, w)| w == &[0xFF, 0xD8, 0xFF])
+        .map(|(pos, _)| (pos as u64, total - pos as u64))
+        .take(MAX_CANDIDATES)
+        .collect()
+}
+
+/// 外部変換コマンドで RAW ファイルを JPEG に変換して読み込む
+///
+/// コマンドが失敗した場合は stdout/stderr をエラーメッセージに含める。
+fn convert_raw_with_command(
+    raw_path: &Path,
+    converter: &RawConverter,
+    max_pixels: u64,
+) -> ProcessorResult<DynamicImage> {
+    // 一時的な変換ファイル (関数を抜けるとエラー時も含めて削除される)
+    let temp_file = TempFile::new("casket_raw_temp", "jpg");
+
+    let output = converter
+        .command(raw_path, temp_file.path())
+        .output()
+        .map_err(|e| ProcessorError::RawDecode(format!("could not run {}: {}", converter.program(), e)))?;
+
     if !output.status.success() {
+        let stdout = String::from_utf8_lossy(&output.stdout);
         let stderr = String::from_utf8_lossy(&output.stderr);
-        eprintln!("  sips DNG conversion failed: {}", stderr);
-        return Ok(None);
+        return Err(ProcessorError::RawDecode(format!(
+            "{} exited with {}\nstdout: {}\nstderr: {}",
+            converter.program(),
+            output.status,
+            stdout.trim(),
+            stderr.trim()
+        )));
     }
-    
-    // 変換されたJPEGファイルからサムネイルを生成
-    let result = if temp_file.exists() {
-        match image::open(&temp_file) {
-            Ok(img) => {
-                let thumbnail = resize_without_upscaling(img, target_width);
-                Some(thumbnail)
-            }
-            Err(e) => {
-                eprintln!("  Error opening converted DNG JPEG: {}", e);
-                None
-            }
-        }
-    } else {
-        eprintln!("  Converted DNG JPEG file not found");
-        None
-    };
-    
-    // 一時ファイルを削除
-    if temp_file.exists() {
-        let _ = std::fs::remove_file(&temp_file);
+
+    if !temp_file.path().exists() {
+        return Err(ProcessorError::RawDecode(format!(
+            "{} did not write {:?}",
+            converter.program(),
+            temp_file.path()
+        )));
     }
-    
-    Ok(result)
+    open_with_pixel_limit(temp_file.path(), max_pixels)
 }
 
 
 // Removed the old get_original_datetime function
 // TODO: RAWファイル用に libraw-rs を使ってメタデータを取得する処理も extract_exif_metadata に統合検討
 // TODO: 動画ファイル用に ffmpeg-next を使ってメタデータを取得する処理も extract_exif_metadata に統合検討
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// テスト用の単色 JPEG をエンコードする
+    fn encode_jpeg(width: u32, height: u32) -> Vec<u8> {
+        let img = image::RgbImage::from_pixel(width, height, image::Rgb([200, 100, 50]));
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, 90)
+            .encode(img.as_raw(), width, height, image::ExtendedColorType::Rgb8)
+            .unwrap();
+        jpeg
+    }
+
+    /// IFD0 の JPEGInterchangeFormat / JPEGInterchangeFormatLength で JPEG を指す、ARW 風の TIFF を作る
+    fn arw_with_preview(jpeg: &[u8]) -> Vec<u8> {
+        const IFD_OFFSET: u32 = 8;
+        const ENTRIES: u16 = 2;
+        // ヘッダー (8) + エントリ数 (2) + エントリ (12 × 2) + 次の IFD (4) の後に JPEG を置く
+        let jpeg_offset = IFD_OFFSET + 2 + 12 * u32::from(ENTRIES) + 4;
+
+        let mut data = b"II*\0".to_vec();
+        data.extend(IFD_OFFSET.to_le_bytes());
+        data.extend(ENTRIES.to_le_bytes());
+        for (tag, value) in [(0x0201u16, jpeg_offset), (0x0202, jpeg.len() as u32)] {
+            data.extend(tag.to_le_bytes());
+            data.extend(4u16.to_le_bytes()); // LONG
+            data.extend(1u32.to_le_bytes());
+            data.extend(value.to_le_bytes());
+        }
+        data.extend(0u32.to_le_bytes());
+        data.extend(jpeg);
+        data
+    }
+
+    #[test]
+    fn extract_embedded_preview_finds_jpeg_in_arw_header() {
+        let jpeg = encode_jpeg(32, 16);
+        let raw = arw_with_preview(&jpeg);
+
+        let candidates = preview_candidates_from_exif(&raw);
+        assert_eq!(candidates, vec![(raw.len() as u64 - jpeg.len() as u64, jpeg.len() as u64)]);
+
+        let preview = extract_embedded_preview(&raw, 0).unwrap().expect("preview should be found");
+        assert_eq!((preview.width(), preview.height()), (32, 16));
+    }
+
+    #[test]
+    fn extract_embedded_preview_respects_max_pixels() {
+        let raw = arw_with_preview(&encode_jpeg(32, 16));
+        assert!(extract_embedded_preview(&raw, 100).unwrap().is_none());
+    }
+
+    /// 圧縮されにくい疑似乱数のバイト列でファイルを作る
+    fn write_noise_file(path: &Path, len: usize) {
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let data: Vec<u8> = (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn copy_with_hash_matches_hash_of_destination() {
+        const BUFFER_SIZE: usize = 64 * 1024;
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source.bin");
+        // バッファ 3 回分と端数
+        write_noise_file(&source, BUFFER_SIZE * 3 + 1234);
+
+        let dest = dir.path().join("streamed.bin");
+        let streamed_hash = copy_with_hash(&source, &dest, BUFFER_SIZE).unwrap();
+        assert_eq!(streamed_hash, hash_file(&dest, BUFFER_SIZE).unwrap());
+        assert_eq!(streamed_hash, hash_file(&source, BUFFER_SIZE).unwrap());
+        assert_eq!(fs::read(&source).unwrap(), fs::read(&dest).unwrap());
+
+        // copy_verified は同じハッシュを返し、バッファサイズが違っても同じ値になる
+        let verified_dest = dir.path().join("verified.bin");
+        assert_eq!(copy_verified(&source, &verified_dest, 0, 4096).unwrap(), streamed_hash);
+    }
+
+    #[test]
+    fn claim_destination_removes_placeholder_unless_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("IMG.jpg");
+        write_noise_file(&source, 100);
+        let source_meta = fs::metadata(&source).unwrap();
+        let catalog_dir = dir.path().join("catalog");
+        fs::create_dir(&catalog_dir).unwrap();
+        let options = ProcessOptions::default();
+        let name = OsStr::new("IMG.jpg");
+
+        // コピー前にドロップした (コピーに失敗した) 場合は、確保した空のファイルを残さない
+        let claimed = claim_destination(&catalog_dir, name, &source, &source_meta, &options).unwrap();
+        assert!(catalog_dir.join("IMG.jpg").exists());
+        drop(claimed);
+        assert!(!catalog_dir.join("IMG.jpg").exists());
+
+        let claimed = claim_destination(&catalog_dir, name, &source, &source_meta, &options).unwrap();
+        fs::copy(&source, catalog_dir.join(&claimed.file_name)).unwrap();
+        claimed.keep();
+        assert!(catalog_dir.join("IMG.jpg").exists());
+
+        // サイズが同じ既存のファイルは以前のコピーとみなしてハッシュせずにそのまま使う
+        let claimed = claim_destination(&catalog_dir, name, &source, &source_meta, &options).unwrap();
+        assert_eq!(claimed.file_name, "IMG.jpg");
+        drop(claimed);
+        assert!(catalog_dir.join("IMG.jpg").exists());
+
+        // サイズの違う同名ファイルがあれば番号を付ける
+        write_noise_file(&catalog_dir.join("IMG.jpg"), 50);
+        let claimed = claim_destination(&catalog_dir, name, &source, &source_meta, &options).unwrap();
+        assert_eq!(claimed.file_name, "IMG_1.jpg");
+        drop(claimed);
+        assert!(!catalog_dir.join("IMG_1.jpg").exists());
+        assert_eq!(fs::metadata(catalog_dir.join("IMG.jpg")).unwrap().len(), 50);
+    }
+
+    /// IFD0 に `width`x`height` の画像、EXIF に 4000x3000 のプレビューの寸法を持つ TIFF 形式の RAW を作る
+    #[cfg(feature = "raw")]
+    fn raw_with_dimensions(width: u32, height: u32) -> Vec<u8> {
+        use exif::experimental::Writer;
+        use exif::{Field, In, Tag, Value};
+
+        let fields = [
+            Field { tag: Tag::ImageWidth, ifd_num: In::PRIMARY, value: Value::Long(vec![width]) },
+            Field { tag: Tag::ImageLength, ifd_num: In::PRIMARY, value: Value::Long(vec![height]) },
+            Field { tag: Tag::PixelXDimension, ifd_num: In::PRIMARY, value: Value::Long(vec![4000]) },
+            Field { tag: Tag::PixelYDimension, ifd_num: In::PRIMARY, value: Value::Long(vec![3000]) },
+        ];
+        let mut writer = Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        let mut raw = io::Cursor::new(Vec::new());
+        writer.write(&mut raw, true).unwrap();
+        raw.into_inner()
+    }
+
+    #[test]
+    #[cfg(feature = "raw")]
+    fn decode_with_libraw_rejects_too_many_pixels_before_processing() {
+        let raw = raw_with_dimensions(30000, 20000);
+        assert_eq!(raw_header_dimensions(&raw), Some((30000, 20000)));
+
+        match decode_with_libraw(&raw, 100_000_000) {
+            Err(ProcessorError::TooManyPixels { width, height, max_pixels }) => {
+                assert_eq!((width, height, max_pixels), (30000, 20000, 100_000_000));
+            }
+            Err(e) => panic!("expected TooManyPixels, got {}", e),
+            Ok(img) => panic!("expected TooManyPixels, got {:?}", img.map(|img| (img.width(), img.height()))),
+        }
+    }
+
+    /// ハッシュしながらの1回の読み込みでのコピーと、fs::copy の後に両方をハッシュする方法の比較
+    /// (`cargo test --release -- --ignored --nocapture copy_timing` で実行)
+    #[test]
+    #[ignore]
+    fn copy_timing_streaming_vs_copy_then_hash() {
+        const LEN: usize = 256 * 1024 * 1024;
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source.bin");
+        write_noise_file(&source, LEN);
+
+        let started = std::time::Instant::now();
+        let streamed = copy_verified(&source, &dir.path().join("streamed.bin"), 0, DEFAULT_COPY_BUFFER_SIZE).unwrap();
+        let streaming = started.elapsed();
+
+        let started = std::time::Instant::now();
+        let copied = dir.path().join("copied.bin");
+        fs::copy(&source, &copied).unwrap();
+        let source_hash = hash_file(&source, DEFAULT_COPY_BUFFER_SIZE).unwrap();
+        let dest_hash = hash_file(&copied, DEFAULT_COPY_BUFFER_SIZE).unwrap();
+        let copy_then_hash = started.elapsed();
+
+        assert_eq!(streamed, source_hash);
+        assert_eq!(source_hash, dest_hash);
+        println!(
+            "{} MiB: streaming copy + verify {:?}, fs::copy + hash both {:?}",
+            LEN / (1024 * 1024),
+            streaming,
+            copy_then_hash
+        );
+    }
+
+    /// AVIF のコンテナを開き、meta/iprp/ipco の ispe (画像の幅と高さ) を読む
+    #[cfg(feature = "avif")]
+    fn avif_dimensions(path: &Path) -> (u32, u32) {
+        let mut file = File::open(path).unwrap();
+        let len = file.metadata().unwrap().len();
+        let meta_end = find_mp4_box(&mut file, len, b"meta").unwrap().expect("meta box");
+        file.seek(io::SeekFrom::Current(4)).unwrap(); // meta は version と flags を持つ
+        let iprp_end = find_mp4_box(&mut file, meta_end, b"iprp").unwrap().expect("iprp box");
+        let ipco_end = find_mp4_box(&mut file, iprp_end, b"ipco").unwrap().expect("ipco box");
+        find_mp4_box(&mut file, ipco_end, b"ispe").unwrap().expect("ispe box");
+        let mut ispe = [0u8; 12]; // version と flags、幅、高さ
+        file.read_exact(&mut ispe).unwrap();
+        (
+            u32::from_be_bytes([ispe[4], ispe[5], ispe[6], ispe[7]]),
+            u32::from_be_bytes([ispe[8], ispe[9], ispe[10], ispe[11]]),
+        )
+    }
+
+    #[cfg(feature = "avif")]
+    #[test]
+    fn save_thumbnail_writes_valid_avif() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("thumbnail.avif");
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(48, 20, |x, y| {
+            image::Rgb([(x * 5) as u8, (y * 12) as u8, 128])
+        }));
+
+        save_thumbnail(&img, &path, ThumbnailFormat::Avif, 60).unwrap();
+
+        // image クレートの AVIF デコーダーは dav1d (avif-native) が必要なので、コンテナを読んで確かめる
+        let data = fs::read(&path).unwrap();
+        assert_eq!(image::guess_format(&data).unwrap(), ImageFormat::Avif);
+        assert_eq!(&data[4..12], b"ftypavif");
+        assert_eq!(avif_dimensions(&path), (48, 20));
+    }
+
+    /// DateTimeOriginal と、値の位置がファイルの外を指す壊れた ImageDescription を持つ EXIF (TIFF 形式) を作る
+    fn exif_with_malformed_field() -> Vec<u8> {
+        use exif::experimental::Writer;
+        use exif::{Field, In, Tag, Value};
+
+        let fields = [
+            Field { tag: Tag::Make, ifd_num: In::PRIMARY, value: Value::Ascii(vec![b"ScanCo".to_vec()]) },
+            Field {
+                tag: Tag::ImageDescription,
+                ifd_num: In::PRIMARY,
+                value: Value::Ascii(vec![b"broken description".to_vec()]),
+            },
+            Field {
+                tag: Tag::DateTimeOriginal,
+                ifd_num: In::PRIMARY,
+                value: Value::Ascii(vec![b"2023:05:06 07:08:09".to_vec()]),
+            },
+            Field { tag: Tag::OffsetTimeOriginal, ifd_num: In::PRIMARY, value: Value::Ascii(vec![b"+09:00".to_vec()]) },
+        ];
+        let mut writer = Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        let mut tiff = io::Cursor::new(Vec::new());
+        writer.write(&mut tiff, true).unwrap();
+        let mut tiff = tiff.into_inner();
+
+        // IFD0 の ImageDescription のエントリの値の位置を壊す
+        let u16_at = |data: &[u8], at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+        let ifd0 = u32::from_le_bytes(tiff[4..8].try_into().unwrap()) as usize;
+        let entry = (0..u16_at(&tiff, ifd0) as usize)
+            .map(|i| ifd0 + 2 + i * 12)
+            .find(|&entry| u16_at(&tiff, entry) == Tag::ImageDescription.number())
+            .unwrap();
+        tiff[entry + 8..entry + 12].copy_from_slice(&0xFFFF_FF00u32.to_le_bytes());
+        tiff
+    }
+
+    fn expected_datetime_original() -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339("2023-05-06T07:08:09+09:00").unwrap()
+    }
+
+    #[test]
+    fn extract_exif_metadata_reads_tiff_with_malformed_field() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scan.tif");
+        fs::write(&path, exif_with_malformed_field()).unwrap();
+
+        // 厳密に読むと壊れたフィールドのせいで全体が失敗する
+        let strict = exif::Reader::new().read_from_container(&mut BufReader::new(File::open(&path).unwrap()));
+        assert!(strict.is_err());
+
+        let metadata = extract_exif_metadata(&path, None);
+        assert_eq!(metadata.datetime_original, Some(expected_datetime_original()));
+        assert_eq!(metadata.datetime_source, Some(DateSource::ExifOriginal));
+        assert_eq!(metadata.camera_make.as_deref(), Some("\"ScanCo\""));
+    }
+
+    #[test]
+    fn extract_exif_metadata_reads_png_exif_chunk() {
+        use image::ImageEncoder;
+
+        let img = image::RgbImage::from_pixel(4, 4, image::Rgb([10, 20, 30]));
+        let mut png = Vec::new();
+        let mut encoder = image::codecs::png::PngEncoder::new(&mut png);
+        encoder.set_exif_metadata(exif_with_malformed_field()).unwrap();
+        encoder.write_image(img.as_raw(), 4, 4, image::ExtendedColorType::Rgb8).unwrap();
+        assert!(png.windows(4).any(|chunk| chunk == b"eXIf"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scan.png");
+        fs::write(&path, png).unwrap();
+
+        let metadata = extract_exif_metadata(&path, None);
+        assert_eq!(metadata.datetime_original, Some(expected_datetime_original()));
+        assert_eq!(metadata.datetime_source, Some(DateSource::ExifOriginal));
+    }
+
+    fn naive(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn resolve_local_datetime_moves_spring_forward_gap_past_the_switch() {
+        // 2024-03-10 02:00 EST に 03:00 EDT へ進むので、02:30 は存在しない
+        let resolved = resolve_local_datetime(&chrono_tz::America::New_York, &naive("2024-03-10 02:30:00")).unwrap();
+        assert_eq!(resolved.naive_local(), naive("2024-03-10 03:30:00"));
+        assert_eq!(resolved.offset().fix().local_minus_utc(), -4 * 3600);
+        assert_eq!(resolved.naive_utc(), naive("2024-03-10 07:30:00"));
+    }
+
+    #[test]
+    fn resolve_local_datetime_picks_earlier_time_in_fall_back_overlap() {
+        // 2024-11-03 02:00 EDT に 01:00 EST へ戻るので、01:30 は2回ある
+        let resolved = resolve_local_datetime(&chrono_tz::America::New_York, &naive("2024-11-03 01:30:00")).unwrap();
+        assert_eq!(resolved.naive_local(), naive("2024-11-03 01:30:00"));
+        assert_eq!(resolved.offset().fix().local_minus_utc(), -4 * 3600);
+        assert_eq!(resolved.naive_utc(), naive("2024-11-03 05:30:00"));
+    }
+
+    #[test]
+    fn resolve_local_datetime_keeps_unambiguous_time() {
+        let resolved = resolve_local_datetime(&chrono_tz::America::New_York, &naive("2024-07-01 12:00:00")).unwrap();
+        assert_eq!(resolved.naive_utc(), naive("2024-07-01 16:00:00"));
+    }
+}
@@ -1,18 +1,255 @@
-use std::fs;
-use std::io;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::ops::ControlFlow;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
 
 /// スキャン結果として返すファイル情報
 #[derive(Debug)]
 pub struct FileInfo {
     pub path: PathBuf,
+    /// Live Photo の組 (同じディレクトリに同名の静止画と動画がある場合)
+    pub live_photo: Option<LivePhoto>,
     // 必要に応じて他の情報（ファイルサイズ、更新日時など）を追加
 }
 
+/// Apple の Live Photo (同名の HEIC/JPEG 静止画と MOV 動画の組)
+#[derive(Debug, Clone, PartialEq)]
+pub struct LivePhoto {
+    pub still: PathBuf,
+    pub video: PathBuf,
+}
+
+impl LivePhoto {
+    /// 組の両方で共通の識別子 (拡張子を除いた元のパス)
+    pub fn group_key(&self) -> String {
+        self.still.with_extension("").to_string_lossy().into_owned()
+    }
+}
+
+/// Live Photo の静止画と動画の拡張子 (小文字)
+const LIVE_PHOTO_STILL_EXTENSIONS: &[&str] = &["heic", "heif", "jpg", "jpeg"];
+const LIVE_PHOTO_VIDEO_EXTENSIONS: &[&str] = &["mov"];
+
+/// `path` と同じディレクトリに同名の相方 (静止画なら動画、動画なら静止画) があれば組を返す
+fn find_live_photo(path: &Path) -> Option<LivePhoto> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    let counterpart_extensions = if LIVE_PHOTO_STILL_EXTENSIONS.contains(&ext.as_str()) {
+        LIVE_PHOTO_VIDEO_EXTENSIONS
+    } else if LIVE_PHOTO_VIDEO_EXTENSIONS.contains(&ext.as_str()) {
+        LIVE_PHOTO_STILL_EXTENSIONS
+    } else {
+        return None;
+    };
+
+    // 大文字 (iPhone の IMG_0001.HEIC など) と小文字の両方を試す
+    let counterpart = counterpart_extensions
+        .iter()
+        .flat_map(|ext| [ext.to_ascii_uppercase(), ext.to_string()])
+        .map(|ext| path.with_extension(ext))
+        .find(|candidate| candidate.is_file())?;
+
+    if LIVE_PHOTO_STILL_EXTENSIONS.contains(&ext.as_str()) {
+        Some(LivePhoto { still: path.to_path_buf(), video: counterpart })
+    } else {
+        Some(LivePhoto { still: counterpart, video: path.to_path_buf() })
+    }
+}
+
+/// 見つけたファイルを処理に渡す順序
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortBy {
+    /// パス順 (ディレクトリごとに名前順に辿るので、スキャンしながら渡せる)
+    #[default]
+    Path,
+    /// ファイル名順 (ディレクトリをまたいで並べ替える。同名はパス順)
+    Name,
+    /// 更新日時の古い順 (同じ日時はパス順)
+    Mtime,
+}
+
+impl FromStr for SortBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "path" => Ok(Self::Path),
+            "name" => Ok(Self::Name),
+            "mtime" => Ok(Self::Mtime),
+            other => Err(format!(
+                "unknown sort order {:?} (expected \"name\", \"mtime\" or \"path\")",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SortBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Path => "path",
+            Self::Name => "name",
+            Self::Mtime => "mtime",
+        })
+    }
+}
+
+/// スキャン時の絞り込み条件
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    /// この日時より前に更新されたファイルを除外する
+    pub since: Option<SystemTime>,
+    /// このバイト数未満のファイルを壊れたファイルとして除外する (デフォルト1 = 0バイトのみ除外)
+    pub min_size: u64,
+    /// シンボリックリンク先のディレクトリも再帰的にスキャンする (無効時はリンク先ディレクトリに入らない)
+    pub follow_symlinks: bool,
+    /// スキャンルートからの再帰の深さの上限 (0 はルート直下のファイルのみ、None は無制限)
+    pub max_depth: Option<usize>,
+    /// `.` で始まる隠しファイル・ディレクトリもスキャンする (`._` のリソースフォークは常に除外)
+    pub include_hidden: bool,
+    /// スキャンから除外するディレクトリ (インポート先カタログの保存先など、配下も含めて除外)
+    pub exclude_dirs: Vec<PathBuf>,
+    /// ファイルを渡す順序 (`Path` 以外は全体のスキャンが終わってから並べ替えて渡す)
+    pub sort_by: SortBy,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            since: None,
+            min_size: 1,
+            follow_symlinks: false,
+            max_depth: None,
+            include_hidden: false,
+            exclude_dirs: Vec::new(),
+            sort_by: SortBy::default(),
+        }
+    }
+}
+
+/// スキャン結果 (対象ファイルと除外件数)
+#[derive(Debug, Default)]
+pub struct ScanResult {
+    pub files: Vec<FileInfo>,
+    /// `since` より古いため除外したファイル数
+    pub filtered_by_since: usize,
+    /// サイズ不足、ヘッダー読み込み失敗、UTF-8 でないパスのため除外したファイル
+    pub skipped: Vec<PathBuf>,
+}
+
+/// ストリーミングスキャンの集計 (対象ファイル自体はコールバックに渡す)
+#[derive(Debug, Default)]
+pub struct ScanSummary {
+    /// コールバックに渡したファイル数
+    pub found: usize,
+    /// `since` より古いため除外したファイル数
+    pub filtered_by_since: usize,
+    /// サイズ不足、ヘッダー読み込み失敗、UTF-8 でないパスのため除外したファイル
+    pub skipped: Vec<PathBuf>,
+}
+
 /// 指定されたディレクトリを再帰的にスキャンし、ファイルリストを取得する
-pub fn scan_directory(dir_path: &Path) -> io::Result<Vec<FileInfo>> {
+pub fn scan_directory(dir_path: &Path, options: &ScanOptions) -> io::Result<ScanResult> {
     let mut files = Vec::new();
-    println!("Scanning directory: {:?}", dir_path); // デバッグ用
+    let summary = scan_directory_each(dir_path, options, |file_info| {
+        files.push(file_info);
+        ControlFlow::Continue(())
+    })?;
+    Ok(ScanResult {
+        files,
+        filtered_by_since: summary.filtered_by_since,
+        skipped: summary.skipped,
+    })
+}
+
+/// 指定されたディレクトリを再帰的にスキャンし、見つけたファイルを順次 `on_file` に渡す
+///
+/// 全体のスキャン完了を待たずに処理を始められるため、大量のファイルがあるツリー向け。
+/// 途中でエラーになった場合、それまでに見つけたファイルは既に `on_file` に渡されている。
+/// `on_file` が `ControlFlow::Break` を返すと、残りのスキャンを打ち切る。
+/// `sort_by` が `Path` 以外の場合は全体をスキャンして並べ替えてから渡す
+/// (途中でエラーになった場合は何も渡さない)。
+pub fn scan_directory_each<F>(
+    dir_path: &Path,
+    options: &ScanOptions,
+    mut on_file: F,
+) -> io::Result<ScanSummary>
+where
+    F: FnMut(FileInfo) -> ControlFlow<()>,
+{
+    let mut summary = ScanSummary::default();
+    let mut visited = HashSet::new();
+    // 存在しない除外ディレクトリは配下のファイルもないので無視する
+    let excluded: Vec<PathBuf> = options
+        .exclude_dirs
+        .iter()
+        .filter_map(|dir| fs::canonicalize(dir).ok())
+        .collect();
+    if options.sort_by == SortBy::Path {
+        // 打ち切られた場合もそこまでの集計を返す
+        let _ = scan_directory_into(
+            dir_path,
+            0,
+            options,
+            &excluded,
+            &mut summary,
+            &mut visited,
+            &mut on_file,
+        )?;
+        return Ok(summary);
+    }
+
+    let mut files = Vec::new();
+    let _ = scan_directory_into(
+        dir_path,
+        0,
+        options,
+        &excluded,
+        &mut summary,
+        &mut visited,
+        &mut |file_info| {
+            files.push(file_info);
+            ControlFlow::Continue(())
+        },
+    )?;
+    match options.sort_by {
+        SortBy::Path => {}
+        SortBy::Name => files.sort_by(|a, b| {
+            a.path
+                .file_name()
+                .cmp(&b.path.file_name())
+                .then_with(|| a.path.cmp(&b.path))
+        }),
+        // 更新日時を読めないファイルは先頭に並ぶ
+        SortBy::Mtime => files.sort_by_cached_key(|file_info| {
+            let modified = fs::metadata(&file_info.path).and_then(|meta| meta.modified()).ok();
+            (modified, file_info.path.clone())
+        }),
+    }
+    for file_info in files {
+        if on_file(file_info).is_break() {
+            break;
+        }
+    }
+    Ok(summary)
+}
+
+/// `depth` はスキャンルートからの深さ (ルートが 0)
+/// `excluded` は除外ディレクトリの正規化パス
+/// `visited` はスキャン済みディレクトリの正規化パス (シンボリックリンクのループ対策)
+fn scan_directory_into(
+    dir_path: &Path,
+    depth: usize,
+    options: &ScanOptions,
+    excluded: &[PathBuf],
+    summary: &mut ScanSummary,
+    visited: &mut HashSet<PathBuf>,
+    on_file: &mut dyn FnMut(FileInfo) -> ControlFlow<()>,
+) -> io::Result<ControlFlow<()>> {
+    verbose!("Scanning directory: {:?}", dir_path); // デバッグ用
 
     if !dir_path.is_dir() {
         return Err(io::Error::new(
@@ -21,21 +258,187 @@ pub fn scan_directory(dir_path: &Path) -> io::Result<Vec<FileInfo>> {
         ));
     }
 
-    for entry in fs::read_dir(dir_path)? {
-        let entry = entry?;
+    let canonical = fs::canonicalize(dir_path)?;
+    if excluded.iter().any(|dir| canonical.starts_with(dir)) {
+        warn!(
+            "Warning: skipping {:?} (inside the destination catalog)",
+            dir_path
+        );
+        return Ok(ControlFlow::Continue(()));
+    }
+    if !visited.insert(canonical) {
+        warn!(
+            "Warning: skipping {:?} (already scanned, possible symlink loop)",
+            dir_path
+        );
+        return Ok(ControlFlow::Continue(()));
+    }
+
+    // 名前順に辿る (実行ごとに順序が変わらず、全体としてパス順になる)
+    let mut entries = fs::read_dir(dir_path)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
         let path = entry.path();
 
+        // 隠しファイル (.DS_Store など) と AppleDouble のリソースフォーク (._*) を除外
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        if file_name.starts_with("._") || (file_name.starts_with('.') && !options.include_hidden) {
+            verbose!("Skipping hidden entry: {:?}", path);
+            continue;
+        }
+
+        // file_type() はリンクを辿らないので、リンク自体かどうかを判定できる
+        if entry.file_type()?.is_symlink() && !options.follow_symlinks && path.is_dir() {
+            verbose!("Skipping symlinked directory: {:?}", path);
+            continue;
+        }
+
         if path.is_dir() {
+            if options.max_depth.is_some_and(|max_depth| depth >= max_depth) {
+                verbose!("Skipping directory beyond --max-depth: {:?}", path);
+                continue;
+            }
             // サブディレクトリを再帰的にスキャン
-            let mut sub_files = scan_directory(&path)?;
-            files.append(&mut sub_files);
+            let flow = scan_directory_into(
+                &path,
+                depth + 1,
+                options,
+                excluded,
+                summary,
+                visited,
+                on_file,
+            )?;
+            if flow.is_break() {
+                return Ok(flow);
+            }
         } else if path.is_file() {
+            let file_meta = fs::metadata(&path)?;
+
+            // 更新日時による絞り込み (--since)
+            if let Some(since) = options.since {
+                if file_meta.modified()? < since {
+                    summary.filtered_by_since += 1;
+                    continue;
+                }
+            }
+
+            // UTF-8 でないパス (古いアーカイブの Shift_JIS ファイル名など) はDBに正しく記録できないため除外
+            if path.to_str().is_none() {
+                warn!(
+                    "Warning: skipping {:?} (path is not valid UTF-8, rename it to import)",
+                    path
+                );
+                summary.skipped.push(path);
+                continue;
+            }
+
+            // 0バイトや途中で切れたファイル (カードリーダーの不調など) を早期に除外
+            if file_meta.len() < options.min_size {
+                warn!(
+                    "Warning: skipping {:?} ({} bytes, below minimum {} bytes)",
+                    path,
+                    file_meta.len(),
+                    options.min_size
+                );
+                summary.skipped.push(path);
+                continue;
+            }
+            if let Err(reason) = check_header(&path) {
+                warn!("Warning: skipping {:?} ({})", path, reason);
+                summary.skipped.push(path);
+                continue;
+            }
+
             // ファイル情報をリストに追加
             // ここでファイルの種類（画像、動画など）を判定することも可能
-            println!("Found file: {:?}", path); // デバッグ用
-            files.push(FileInfo { path });
+            verbose!("Found file: {:?}", path); // デバッグ用
+            summary.found += 1;
+            let live_photo = find_live_photo(&path);
+            if let Some(pair) = &live_photo {
+                verbose!("Found Live Photo pair: {:?} + {:?}", pair.still, pair.video);
+            }
+            if on_file(FileInfo { path, live_photo }).is_break() {
+                return Ok(ControlFlow::Break(()));
+            }
         }
     }
 
-    Ok(files)
+    Ok(ControlFlow::Continue(()))
+}
+
+/// 先頭バイトを読み、読み込み不能やゼロ埋めのファイルを検出する
+fn check_header(path: &Path) -> Result<(), String> {
+    const HEADER_LEN: usize = 16;
+
+    let mut header = [0u8; HEADER_LEN];
+    let read = File::open(path)
+        .and_then(|mut file| file.read(&mut header))
+        .map_err(|e| format!("header could not be read: {}", e))?;
+
+    if read > 0 && header[..read].iter().all(|&b| b == 0) {
+        return Err("header is zero-filled, file looks truncated".to_string());
+    }
+    Ok(())
+}
+
+/// `root` 配下の空になったディレクトリを深い方から削除し、削除した数を返す
+///
+/// `root` 自体とその外側、シンボリックリンク、`excluded` 配下 (インポート先カタログ) は削除しない。
+/// 隠しファイル (.DS_Store など) が残っているディレクトリは空ではないので残す。
+pub fn prune_empty_dirs(root: &Path, excluded: &[PathBuf]) -> io::Result<usize> {
+    let excluded: Vec<PathBuf> = excluded
+        .iter()
+        .filter_map(|dir| fs::canonicalize(dir).ok())
+        .collect();
+    let mut pruned = 0;
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        // file_type() はリンクを辿らないので、リンク先のディレクトリは対象外になる
+        if entry.file_type()?.is_dir() {
+            prune_dir(&entry.path(), &excluded, &mut pruned);
+        }
+    }
+    Ok(pruned)
+}
+
+/// `dir` の子ディレクトリを先に処理し、空になっていれば `dir` も削除する (削除できたら true)
+fn prune_dir(dir: &Path, excluded: &[PathBuf], pruned: &mut usize) -> bool {
+    if fs::canonicalize(dir).is_ok_and(|canonical| excluded.iter().any(|ex| canonical.starts_with(ex))) {
+        return false;
+    }
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Warning: could not read {:?} while pruning: {}", dir, e);
+            return false;
+        }
+    };
+
+    let mut empty = true;
+    for entry in entries {
+        let Ok(entry) = entry else {
+            empty = false;
+            continue;
+        };
+        let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+        if !(is_dir && prune_dir(&entry.path(), excluded, pruned)) {
+            empty = false;
+        }
+    }
+    if !empty {
+        return false;
+    }
+
+    match fs::remove_dir(dir) {
+        Ok(()) => {
+            verbose!("Removed empty directory: {:?}", dir);
+            *pruned += 1;
+            true
+        }
+        Err(e) => {
+            warn!("Warning: could not remove empty directory {:?}: {}", dir, e);
+            false
+        }
+    }
 }